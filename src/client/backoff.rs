@@ -0,0 +1,69 @@
+//! Exponential backoff used when reconnecting to the server, and by the server when accepting
+//! connections fails
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Delay before the first reconnection attempt
+pub const MIN_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound on the delay between reconnection attempts
+pub const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Exponential backoff with jitter.
+///
+/// The base delay doubles after every failed attempt, up to `max`. The actual delay is picked
+/// randomly between half and all of the base delay, so a group of clients that lost the server at
+/// the same time don't all come back at the same moment.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    /// Get the delay to wait before the next attempt and grow the base delay
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.current;
+        self.current = (self.current * 2).min(self.max);
+
+        let half = base / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    /// Go back to the initial delay once a connection succeeded
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let mut bases = vec![];
+        for _ in 0..6 {
+            bases.push(backoff.current);
+            let delay = backoff.next_delay();
+            let base = *bases.last().unwrap();
+            assert!(delay >= base / 2 && delay <= base);
+        }
+        assert_eq!(
+            bases,
+            [1, 2, 4, 8, 10, 10].map(Duration::from_secs).to_vec()
+        );
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,113 @@
+//! Local changes that mustn't be sent to the server
+
+use crate::messaging::arguments::FileMetadata;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How long filesystem events are ignored after changing a path on behalf of the server. The
+/// watcher reports events after a second of debouncing.
+pub const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Paths whose filesystem events come from the server's own changes.
+///
+/// Files being downloaded are blacklisted until they're complete. Paths moved or deleted on
+/// behalf of the server are blacklisted, along with everything below them, until the watcher had
+/// the time to report the change.
+#[derive(Default)]
+pub struct Blacklist {
+    /// Files being downloaded, along with their metadata
+    downloads: HashMap<PathBuf, FileMetadata>,
+    /// Paths changed on behalf of the server, along with the time they're ignored until
+    echoes: HashMap<PathBuf, Instant>,
+}
+
+impl Blacklist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blacklist the download of `file_md` to `path`
+    pub fn insert(&mut self, path: PathBuf, file_md: FileMetadata) {
+        self.downloads.insert(path, file_md);
+    }
+
+    /// Metadata of the file being downloaded to `path`
+    pub fn get(&self, path: &Path) -> Option<&FileMetadata> {
+        self.downloads.get(path)
+    }
+
+    /// Remove the download to `path` from the blacklist, e.g. once it's complete
+    pub fn remove(&mut self, path: &Path) -> Option<FileMetadata> {
+        self.downloads.remove(path)
+    }
+
+    /// Forget about the downloads in progress. Paths changed on behalf of the server are still
+    /// ignored until their timeout.
+    pub fn clear(&mut self) {
+        self.downloads.clear();
+    }
+
+    /// Ignore the events of `path` and everything below it for the next
+    /// [`ECHO_TIMEOUT`](constant.ECHO_TIMEOUT.html)
+    pub fn ignore(&mut self, path: &Path) {
+        let now = Instant::now();
+        self.echoes.retain(|_, until| *until > now);
+        self.echoes.insert(path.to_owned(), now + ECHO_TIMEOUT);
+    }
+
+    /// Whether `path` is being downloaded
+    pub fn contains(&self, path: &Path) -> bool {
+        self.downloads.contains_key(path)
+    }
+
+    /// Whether `path` was moved or deleted on behalf of the server, so its moves and removals are
+    /// echoes of that change. Files created there afterwards are new changes.
+    pub fn is_echo(&self, path: &Path) -> bool {
+        let now = Instant::now();
+        path.ancestors()
+            .any(|p| self.echoes.get(p).is_some_and(|until| *until > now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blacklist() {
+        let mut blacklist = Blacklist::new();
+        blacklist.ignore(Path::new("dir"));
+        assert!(blacklist.is_echo(Path::new("dir")));
+        assert!(blacklist.is_echo(Path::new("dir/file")));
+        assert!(!blacklist.is_echo(Path::new("dir2")));
+        assert!(!blacklist.is_echo(Path::new("other/dir")));
+        assert!(!blacklist.contains(Path::new("dir")));
+
+        // Downloads only cover the file itself
+        let file_md = FileMetadata::new(
+            crate::messaging::arguments::FileId {
+                path: "other/file".into(),
+                hash: [0u8; 32],
+            },
+            std::fs::metadata(".").unwrap(),
+            &[],
+        )
+        .unwrap();
+        blacklist.insert("other/file".into(), file_md);
+        assert!(blacklist.contains(Path::new("other/file")));
+        assert!(!blacklist.is_echo(Path::new("other/file")));
+        assert!(!blacklist.contains(Path::new("other")));
+        blacklist.clear();
+        assert!(!blacklist.contains(Path::new("other/file")));
+        assert!(blacklist.is_echo(Path::new("dir/file")));
+
+        // Paths stop being ignored after the timeout
+        blacklist.echoes.insert("old".into(), Instant::now());
+        assert!(!blacklist.is_echo(Path::new("old")));
+        blacklist.ignore(Path::new("new"));
+        assert!(!blacklist.echoes.contains_key(Path::new("old")));
+    }
+}
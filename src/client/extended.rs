@@ -0,0 +1,479 @@
+//! Extended metadata of local entries: extended attributes, POSIX ACLs and ownership.
+//!
+//! The kernel keeps POSIX ACLs in the `system.posix_acl_access` and `system.posix_acl_default`
+//! extended attributes, naming users and groups by id. Ids differ between machines, so they're
+//! mapped to names and back, the same as the owner and group.
+
+use crate::messaging::arguments::{
+    AclEntry, AclTag, Capabilities, ExtendedMetadata, Ownership, Xattr,
+};
+use std::{
+    ffi::{CStr, CString, OsStr, OsString},
+    fs, io,
+    mem::MaybeUninit,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+    ptr,
+};
+
+const ACL_ACCESS: &str = "system.posix_acl_access";
+const ACL_DEFAULT: &str = "system.posix_acl_default";
+/// Version of the kernel's ACL format
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+/// Id of the entries that don't name a user or group
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// Whether an extended attribute is carried with [`Capabilities::XATTRS`]. Other namespaces hold
+/// things like file capabilities, which shouldn't travel between machines.
+fn is_synchronized(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"user.") || name == "security.selinux"
+}
+
+/// Collect the parts of the extended metadata in `capabilities` from the entry at `path`
+pub fn read(path: &Path, capabilities: Capabilities) -> io::Result<ExtendedMetadata> {
+    let mut extended = ExtendedMetadata::default();
+    if capabilities.contains(Capabilities::OWNERSHIP) {
+        let md = fs::symlink_metadata(path)?;
+        extended.ownership = Some(Ownership {
+            user: user_name(md.uid()),
+            group: group_name(md.gid()),
+        });
+    }
+
+    let xattrs = capabilities.contains(Capabilities::XATTRS);
+    let acls = capabilities.contains(Capabilities::ACLS);
+    if !xattrs && !acls {
+        return Ok(extended);
+    }
+    for name in list_xattrs(path)? {
+        let wanted = if name == ACL_ACCESS || name == ACL_DEFAULT {
+            acls
+        } else {
+            xattrs && is_synchronized(&name)
+        };
+        if !wanted {
+            continue;
+        }
+        // The attribute might be gone by now
+        let value = match get_xattr(path, &name)? {
+            Some(x) => x,
+            None => continue,
+        };
+        if name == ACL_ACCESS {
+            extended.acl = decode_acl(&value);
+        } else if name == ACL_DEFAULT {
+            extended.default_acl = decode_acl(&value);
+        } else {
+            extended.xattrs.push(Xattr { name, value });
+        }
+    }
+    extended.xattrs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(extended)
+}
+
+/// Restore the parts of `extended` in `capabilities` on the entry at `path`.
+///
+/// Parts the process isn't allowed to restore, or that the filesystem doesn't support, are left
+/// as they are. Ownership usually needs root for example.
+pub fn apply(
+    path: &Path,
+    extended: &ExtendedMetadata,
+    capabilities: Capabilities,
+) -> io::Result<()> {
+    if let Some(ownership) = &extended.ownership {
+        if capabilities.contains(Capabilities::OWNERSHIP) {
+            tolerate(path, "ownership", chown(path, ownership))?;
+        }
+    }
+
+    if capabilities.contains(Capabilities::XATTRS) {
+        // SELinux labels can only be changed, not removed
+        for name in list_xattrs(path)? {
+            let removed = !extended.xattrs.iter().any(|x| x.name == name);
+            if removed && name.as_bytes().starts_with(b"user.") {
+                tolerate(path, &name, remove_xattr(path, &name))?;
+            }
+        }
+        for xattr in &extended.xattrs {
+            if get_xattr(path, &xattr.name)?.as_ref() != Some(&xattr.value) {
+                let res = set_xattr(path, &xattr.name, &xattr.value);
+                tolerate(path, &xattr.name, res)?;
+            }
+        }
+    }
+
+    if capabilities.contains(Capabilities::ACLS) {
+        for (name, acl) in [
+            (ACL_ACCESS, &extended.acl),
+            (ACL_DEFAULT, &extended.default_acl),
+        ] {
+            let name = OsStr::new(name);
+            let res = if acl.is_empty() {
+                match get_xattr(path, name)? {
+                    Some(_) => remove_xattr(path, name),
+                    None => continue,
+                }
+            } else {
+                match encode_acl(acl) {
+                    Some(value) => set_xattr(path, name, &value),
+                    None => {
+                        debug!(
+                            "Not restoring the ACL of {:?}, a user or group is unknown",
+                            path
+                        );
+                        continue;
+                    }
+                }
+            };
+            tolerate(path, name, res)?;
+        }
+    }
+    Ok(())
+}
+
+/// Pass on errors other than missing permissions or support
+fn tolerate(path: &Path, what: impl AsRef<OsStr>, res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(e)
+            if matches!(
+                e.raw_os_error(),
+                Some(libc::EPERM | libc::EACCES | libc::ENOTSUP | libc::ENODATA)
+            ) =>
+        {
+            debug!("Can't restore {:?} of {:?}: {}", what.as_ref(), path, e);
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn chown(path: &Path, ownership: &Ownership) -> io::Result<()> {
+    // Unknown names leave that part unchanged
+    let uid = user_id(&ownership.user).unwrap_or(u32::MAX);
+    let gid = group_id(&ownership.group).unwrap_or(u32::MAX);
+    let path = c_string(path.as_os_str().as_bytes())?;
+    if unsafe { libc::lchown(path.as_ptr(), uid, gid) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Names of the extended attributes of `path`. Filesystems without them have none.
+fn list_xattrs(path: &Path) -> io::Result<Vec<OsString>> {
+    let path = c_string(path.as_os_str().as_bytes())?;
+    loop {
+        let len = unsafe { libc::llistxattr(path.as_ptr(), ptr::null_mut(), 0) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOTSUP) => Ok(vec![]),
+                _ => Err(e),
+            };
+        }
+        let mut buf = vec![0u8; len as usize];
+        let len = unsafe { libc::llistxattr(path.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                // An attribute was added in the meantime
+                Some(libc::ERANGE) => continue,
+                _ => return Err(e),
+            }
+        }
+        buf.truncate(len as usize);
+        return Ok(buf
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| OsStr::from_bytes(name).to_owned())
+            .collect());
+    }
+}
+
+/// Value of the extended attribute `name` of `path`, `None` when it doesn't exist
+fn get_xattr(path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+    let path = c_string(path.as_os_str().as_bytes())?;
+    let name = c_string(name.as_bytes())?;
+    loop {
+        let len = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), ptr::null_mut(), 0) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENODATA | libc::ENOTSUP) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let mut buf = vec![0u8; len as usize];
+        let len = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                // The value grew in the meantime
+                Some(libc::ERANGE) => continue,
+                Some(libc::ENODATA) => return Ok(None),
+                _ => return Err(e),
+            }
+        }
+        buf.truncate(len as usize);
+        return Ok(Some(buf));
+    }
+}
+
+fn set_xattr(path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+    let path = c_string(path.as_os_str().as_bytes())?;
+    let name = c_string(name.as_bytes())?;
+    let res = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn remove_xattr(path: &Path, name: &OsStr) -> io::Result<()> {
+    let path = c_string(path.as_os_str().as_bytes())?;
+    let name = c_string(name.as_bytes())?;
+    if unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Decode the kernel's form of an ACL: `<version:u32>` followed by `<tag:u16> <perm:u16> <id:u32>`
+/// entries, all little endian. Entries that can't be understood are dropped.
+fn decode_acl(value: &[u8]) -> Vec<AclEntry> {
+    match value.split_first_chunk::<4>() {
+        Some((version, entries)) if u32::from_le_bytes(*version) == ACL_XATTR_VERSION => entries
+            .chunks_exact(8)
+            .filter_map(|entry| {
+                let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+                let tag = match u16::from_le_bytes([entry[0], entry[1]]) {
+                    ACL_USER_OBJ => AclTag::UserObj,
+                    ACL_USER => AclTag::User(user_name(id)),
+                    ACL_GROUP_OBJ => AclTag::GroupObj,
+                    ACL_GROUP => AclTag::Group(group_name(id)),
+                    ACL_MASK => AclTag::Mask,
+                    ACL_OTHER => AclTag::Other,
+                    _ => return None,
+                };
+                Some(AclEntry {
+                    tag,
+                    perm: entry[2] & 0o7,
+                })
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Encode an ACL in the kernel's form, `None` when one of its users or groups doesn't exist here
+fn encode_acl(acl: &[AclEntry]) -> Option<Vec<u8>> {
+    let mut entries = acl
+        .iter()
+        .map(|entry| {
+            let (tag, id) = match &entry.tag {
+                AclTag::UserObj => (ACL_USER_OBJ, ACL_UNDEFINED_ID),
+                AclTag::User(name) => (ACL_USER, user_id(name)?),
+                AclTag::GroupObj => (ACL_GROUP_OBJ, ACL_UNDEFINED_ID),
+                AclTag::Group(name) => (ACL_GROUP, group_id(name)?),
+                AclTag::Mask => (ACL_MASK, ACL_UNDEFINED_ID),
+                AclTag::Other => (ACL_OTHER, ACL_UNDEFINED_ID),
+            };
+            Some((tag, id, entry.perm))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    // The kernel wants the entries ordered by tag, and named ones by id
+    entries.sort();
+
+    let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    for (tag, id, perm) in entries {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&u16::from(perm).to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    Some(value)
+}
+
+/// Call one of the reentrant user or group database lookups, growing the buffer as needed, and
+/// `read` what's needed from the entry found
+fn lookup<T, R>(
+    call: impl Fn(*mut T, *mut libc::c_char, usize, *mut *mut T) -> libc::c_int,
+    read: impl FnOnce(&T) -> R,
+) -> Option<R> {
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut entry = MaybeUninit::<T>::uninit();
+        let mut found = ptr::null_mut();
+        match call(entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut found) {
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            0 if !found.is_null() => return Some(read(unsafe { entry.assume_init_ref() })),
+            _ => return None,
+        }
+    }
+}
+
+fn name_of(name: *const libc::c_char) -> Option<String> {
+    let name = unsafe { CStr::from_ptr(name) };
+    name.to_str().ok().map(str::to_owned)
+}
+
+/// Name of the user `uid`, or the id itself when it has none
+fn user_name(uid: u32) -> String {
+    lookup(
+        |pwd, buf, len, found| unsafe { libc::getpwuid_r(uid, pwd, buf, len, found) },
+        |pwd: &libc::passwd| name_of(pwd.pw_name),
+    )
+    .flatten()
+    .unwrap_or_else(|| uid.to_string())
+}
+
+/// Name of the group `gid`, or the id itself when it has none
+fn group_name(gid: u32) -> String {
+    lookup(
+        |grp, buf, len, found| unsafe { libc::getgrgid_r(gid, grp, buf, len, found) },
+        |grp: &libc::group| name_of(grp.gr_name),
+    )
+    .flatten()
+    .unwrap_or_else(|| gid.to_string())
+}
+
+/// Id of the user called `name`. Names that are numbers are taken as the id when there's no
+/// such user.
+fn user_id(name: &str) -> Option<u32> {
+    let c_name = c_string(name.as_bytes()).ok()?;
+    lookup(
+        |pwd, buf, len, found| unsafe { libc::getpwnam_r(c_name.as_ptr(), pwd, buf, len, found) },
+        |pwd: &libc::passwd| pwd.pw_uid,
+    )
+    .or_else(|| name.parse().ok())
+}
+
+/// Id of the group called `name`, see [`user_id`]
+fn group_id(name: &str) -> Option<u32> {
+    let c_name = c_string(name.as_bytes()).ok()?;
+    lookup(
+        |grp, buf, len, found| unsafe { libc::getgrnam_r(c_name.as_ptr(), grp, buf, len, found) },
+        |grp: &libc::group| grp.gr_gid,
+    )
+    .or_else(|| name.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_acl_names() {
+        assert_eq!(user_name(0), "root");
+        assert_eq!(user_id("root"), Some(0));
+        assert_eq!(group_id("4242424"), Some(4242424));
+        assert_eq!(user_id("no such user"), None);
+
+        let acl = vec![
+            AclEntry {
+                tag: AclTag::Other,
+                perm: 4,
+            },
+            AclEntry {
+                tag: AclTag::UserObj,
+                perm: 6,
+            },
+            AclEntry {
+                tag: AclTag::User("root".to_string()),
+                perm: 7,
+            },
+            AclEntry {
+                tag: AclTag::Mask,
+                perm: 7,
+            },
+            AclEntry {
+                tag: AclTag::GroupObj,
+                perm: 4,
+            },
+        ];
+        let value = encode_acl(&acl).unwrap();
+        assert_eq!(value.len(), 4 + acl.len() * 8);
+        // Decoded in the kernel's order
+        let mut sorted = acl.clone();
+        sorted.sort_by_key(|entry| match entry.tag {
+            AclTag::UserObj => 0,
+            AclTag::User(_) => 1,
+            AclTag::GroupObj => 2,
+            AclTag::Group(_) => 3,
+            AclTag::Mask => 4,
+            AclTag::Other => 5,
+        });
+        assert_eq!(decode_acl(&value), sorted);
+
+        let unknown = vec![AclEntry {
+            tag: AclTag::Group("no such group".to_string()),
+            perm: 7,
+        }];
+        assert_eq!(encode_acl(&unknown), None);
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let path = std::env::temp_dir().join(format!("phoenix-xattrs-{}", std::process::id()));
+        fs::write(&path, b"data").unwrap();
+        if set_xattr(&path, OsStr::new("user.probe"), b"").is_err() {
+            // The filesystem doesn't support user attributes
+            fs::remove_file(&path).unwrap();
+            return;
+        }
+        set_xattr(&path, OsStr::new("trusted.other"), b"x").ok();
+
+        // The file might have an SELinux label as well
+        let capabilities = Capabilities(Capabilities::XATTRS.0 | Capabilities::ACLS.0);
+        let user_xattrs = |extended: ExtendedMetadata| {
+            let mut xattrs = extended.xattrs;
+            xattrs.retain(|x| x.name.as_bytes().starts_with(b"user."));
+            xattrs
+        };
+        let mut extended = read(&path, capabilities).unwrap();
+        assert_eq!(
+            user_xattrs(extended.clone()),
+            vec![Xattr {
+                name: "user.probe".into(),
+                value: vec![],
+            }]
+        );
+
+        // Attributes that are gone elsewhere are removed
+        extended.xattrs = vec![Xattr {
+            name: "user.tag".into(),
+            value: b"blue".to_vec(),
+        }];
+        apply(&path, &extended, capabilities).unwrap();
+        assert_eq!(
+            user_xattrs(read(&path, capabilities).unwrap()),
+            extended.xattrs
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
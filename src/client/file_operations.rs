@@ -1,118 +1,518 @@
 use crate::{
-    client::utils::get_file_info,
+    client::utils::get_entry_info,
+    config::SymlinkPolicy,
     messaging::{
-        arguments::{self, Argument, ChunkId, FileId, FilePath, QualifiedChunkId},
-        Directive, MessageBuilder,
+        arguments::{
+            self, Argument, Capabilities, ChunkId, ChunkList, ErrorCode, FileId, FileMetadata,
+            FilePath, MetadataList, ProtocolError, QualifiedChunkId, Rename,
+        },
+        error::MessageError,
+        mux::{MuxSender, CONTROL_STREAM},
+        Envelope, Message, MessageBuilder, MAX_BATCH_LEN,
     },
-    net::{error::NetError, NetClient, NoiseConnection},
+    net::error::NetError,
 };
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
+    fmt::Display,
     fs::File,
-    io::{Read, Seek, SeekFrom},
+    future::Future,
+    io::{self, Read, Seek, SeekFrom},
     path::Path,
+    pin::Pin,
+    task::{Context, Poll},
 };
+use tokio::sync::oneshot;
 
 pub const CHUNK_SIZE: usize = 1 << 20; // 8 byte chunk size. TODO: automatically determine this.
-                                      // Probably using file size ranges
+                                       // Probably using file size ranges
+
+/// Reasons a request sent to the server didn't succeed
+#[derive(Debug)]
+pub enum RequestError {
+    /// The server answered with an error
+    Refused(ProtocolError),
+    /// The connection dropped before the server answered
+    Disconnected,
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Refused(e) => write!(f, "Refused by the server: {}", e),
+            RequestError::Disconnected => write!(f, "Disconnected before the server answered"),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+/// Resolves once the server has answered a request.
+///
+/// Dropping it doesn't cancel the request, the response is just ignored.
+pub struct Ack(oneshot::Receiver<Result<(), ProtocolError>>);
+
+impl Future for Ack {
+    type Output = Result<(), RequestError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|res| match res {
+            Ok(res) => res.map_err(RequestError::Refused),
+            Err(_) => Err(RequestError::Disconnected),
+        })
+    }
+}
 
 /// This struct is the main entry point for any operations that come from the client.
 ///
 /// Any message that is transmitted through the network should be generated by this struct at a
-/// high level.
+/// high level. Messages from the server are received separately, through the other half of the
+/// connection.
+///
+/// Requests only queue their message and return an [`Ack`](struct.Ack.html) right away. The
+/// `Response` or `Error` from the server has to be handed to [`resolve`](#method.resolve) for the
+/// `Ack` to complete, so the messages from the server must keep being handled while waiting on it.
 pub struct Client {
     builder: MessageBuilder,
-    net_client: NetClient,
+    net_client: MuxSender,
+    /// Optional features agreed on with the server
+    capabilities: Capabilities,
+    /// Id of the next stream opened by the client
+    next_stream: u16,
+    /// Requests waiting for the server's response, by message id
+    pending: HashMap<u16, oneshot::Sender<Result<(), ProtocolError>>>,
 }
 
 impl Client {
-    pub fn new(builder: MessageBuilder, net_client: NetClient) -> Self {
+    pub fn new(builder: MessageBuilder, net_client: MuxSender, capabilities: Capabilities) -> Self {
         Client {
             builder,
             net_client,
+            capabilities,
+            next_stream: 1,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Decode a message from the server with the agreed on capabilities
+    pub fn decode(&self, message: &[u8]) -> Result<Envelope, MessageError> {
+        self.builder.decode_message(message)
+    }
+
+    /// Whether the server supports `capability`
+    pub fn supports(&self, capability: Capabilities) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Optional features agreed on with the server
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Encode a message for `stream`. Messages the server can't take, like chunks past 4 GiB
+    /// without `LARGE_OFFSETS`, are refused as invalid input.
+    fn encode(&mut self, stream: u16, message: Message) -> Result<Vec<u8>, NetError> {
+        self.builder
+            .encode_stream_message(stream, message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e).into())
+    }
+
+    /// Send a request on the control stream
+    fn request(&mut self, message: Message) -> Result<Ack, NetError> {
+        let id = self.builder.next_id();
+        let msg = self.encode(CONTROL_STREAM, message)?;
+        self.net_client.send(CONTROL_STREAM, msg)?;
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.insert(id, tx);
+        Ok(Ack(rx))
+    }
+
+    /// Complete the request answered by a `Response` or an `Error` from the server.
+    ///
+    /// Errors about messages that aren't requests, like chunks, are reported right away.
+    pub fn resolve(&mut self, id: u16, res: Result<(), ProtocolError>) {
+        match (self.pending.remove(&id), res) {
+            // Nobody might be waiting on the request anymore
+            (Some(tx), res) => {
+                let _ = tx.send(res);
+            }
+            (None, Ok(())) => warn!("Got a response to unknown request {}", id),
+            (None, Err(e)) => error!("Server couldn't handle message {}: {}", id, e),
         }
     }
 
-    /// Send file metadata to the server
-    pub async fn send_file_info(&mut self, base: &Path, path: &Path) -> Result<(), Box<dyn Error>> {
-        let mut file_info = get_file_info(path)?;
+    /// Open a new stream for a file transfer. Streams opened by the client have odd ids.
+    pub fn open_stream(&mut self) -> u16 {
+        let stream = self.next_stream;
+        // Odd ids never wrap around to the control stream
+        self.next_stream = self.next_stream.wrapping_add(2);
+        stream
+    }
+
+    /// Metadata of the entry at `path` as it's sent to the server, with its path relative to
+    /// `base`. Links left out under `symlinks` are an error.
+    pub fn file_info(
+        &self,
+        base: &Path,
+        path: &Path,
+        symlinks: SymlinkPolicy,
+    ) -> Result<FileMetadata, Box<dyn Error>> {
+        let mut file_info = get_entry_info(base, path, symlinks, self.capabilities)?
+            .ok_or_else(|| format!("{:?} is left out by the symlink policy", path))?;
+        if !self.capabilities.carries(&file_info.kind) {
+            return Err(format!("The server can't synchronize {:?}", path).into());
+        }
+        if !self.capabilities.fits(&file_info) {
+            return Err(format!("{:?} is too large for the server", path).into());
+        }
         file_info.file_id.path = path.strip_prefix(base).unwrap().to_owned();
-        let msg = self
-            .builder
-            .encode_message(Directive::SendFile, Some(file_info));
-        self.net_client.send(&msg).await?;
-        Ok(())
+        Ok(file_info)
+    }
+
+    /// Send file metadata to the server, in a single `SendFileBatch` when there's more than one
+    /// file. Batches need the `BATCH` capability.
+    ///
+    /// The returned `Ack` completes once the server stored every chunk of the files, which it
+    /// requests separately.
+    pub fn send_files(&mut self, mut files: Vec<FileMetadata>) -> Result<Ack, NetError> {
+        match files.len() {
+            1 => self.request(Message::SendFile(files.pop().unwrap())),
+            _ => self.request(Message::SendFileBatch(MetadataList(files))),
+        }
     }
 
-    /// Send a specific chunk from a given file
+    /// Send a specific chunk from a given file, answering the server's request `id` on `stream`.
+    ///
+    /// When the chunk can't be read, the request is answered with an error instead.
     pub async fn send_chunk(
         &mut self,
-        chunk_id: &ChunkId,
+        id: u16,
+        stream: u16,
+        chunk: &QualifiedChunkId,
         file_path: &Path,
-    ) -> Result<(), Box<dyn Error>> {
-        let file_info = get_file_info(file_path)?;
-        let mut file = File::open(&file_path)?;
-        let mut hasher = blake3::Hasher::new();
-
-        let chunk_index = file_info
-            .chunks
-            .iter()
-            .position(|i| *i == *chunk_id)
-            .expect("Attempted to get a chunk from a file that's changed");
-
-        file.seek(SeekFrom::Start((chunk_index * CHUNK_SIZE) as u64))?;
-
-        let mut buf = vec![0; CHUNK_SIZE];
-        let len = file.read(&mut buf)?;
-
-        hasher.update(&buf[..len]);
-        let hash = hasher.finalize().as_bytes().to_vec();
-
-        if chunk_id.to_bin() == hash {
-            let chunk = arguments::Chunk {
-                id: arguments::ChunkId(hash),
-                data: buf[..len].to_vec(),
-            };
-            let msg = self
+    ) -> Result<(), NetError> {
+        let msg = self.chunk_answer(id, stream, chunk, file_path);
+        self.net_client.answer(stream, msg)
+    }
+
+    /// Answer the server's `RequestChunks` `id` on `stream` with every chunk in `chunks`, in order.
+    /// The chunks' paths are relative to `base`.
+    pub async fn send_chunks(
+        &mut self,
+        id: u16,
+        stream: u16,
+        chunks: &[QualifiedChunkId],
+        base: &Path,
+    ) -> Result<(), NetError> {
+        for (i, chunk) in chunks.iter().enumerate() {
+            let msg = self.chunk_answer(id, stream, chunk, &base.join(&chunk.path.path));
+            if i + 1 == chunks.len() {
+                self.net_client.answer(stream, msg)?;
+            } else {
+                self.net_client.answer_part(stream, msg)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The requested `chunk` of the file at `file_path`, or the error answering the request `id`
+    /// when it can't be read
+    fn chunk_answer(
+        &mut self,
+        id: u16,
+        stream: u16,
+        chunk: &QualifiedChunkId,
+        file_path: &Path,
+    ) -> Vec<u8> {
+        let msg = read_chunk(&chunk.id, chunk.offset, file_path).and_then(|chunk| {
+            Ok(self
                 .builder
-                .encode_message(Directive::SendChunk, Some(chunk));
-            self.net_client.send(&msg).await?;
-        } else {
-            panic!("Chunks don't match up. File must have changed. This error will be handled in the future")
+                .encode_stream_message(stream, Message::SendChunk(chunk))?)
+        });
+        match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Failed to send chunk of {:?}: {}", file_path, e);
+                self.builder.encode_error(id, stream, &e)
+            }
         }
+    }
 
+    /// Request the chunks in `batches` on a stream opened with [`open_stream`](#method.open_stream).
+    /// Nothing is requested when any of the chunks can't be addressed.
+    pub fn request_chunks(&mut self, stream: u16, batches: ChunkBatches) -> Result<(), NetError> {
+        let messages = batches
+            .into_messages(self.supports(Capabilities::BATCH))
+            .into_iter()
+            .map(|message| self.encode(stream, message))
+            .collect::<Result<Vec<_>, _>>()?;
+        for msg in messages {
+            self.net_client.send(stream, msg)?;
+        }
         Ok(())
     }
 
-    pub async fn request_chunk(&mut self, chunk: QualifiedChunkId) -> Result<(), NetError> {
-        let msg = self
-            .builder
-            .encode_message::<arguments::QualifiedChunkId>(Directive::RequestChunk, Some(chunk));
-        self.net_client.send(&msg).await
+    /// Account for a request the server sent on one of its streams. Fails when the server exceeded
+    /// its credit.
+    pub fn accept_request(&self, stream: u16) -> Result<(), NetError> {
+        self.net_client.accept_request(stream)
+    }
+
+    /// Add credit handed back by the server
+    pub fn add_credit(&self, stream: u16, credit: u32) -> Result<(), NetError> {
+        self.net_client.add_credit(stream, credit)
+    }
+
+    pub fn request_file_list(&mut self) -> Result<Ack, NetError> {
+        self.request(Message::ListFiles)
+    }
+
+    pub async fn ping(&mut self) -> Result<(), NetError> {
+        let msg = self.encode(CONTROL_STREAM, Message::Ping)?;
+        self.net_client.send(CONTROL_STREAM, msg)
+    }
+
+    pub async fn pong(&mut self) -> Result<(), NetError> {
+        let msg = self.encode(CONTROL_STREAM, Message::Pong)?;
+        self.net_client.answer(CONTROL_STREAM, msg)
+    }
+
+    pub fn request_file(&mut self, file: FileId) -> Result<Ack, NetError> {
+        self.request(Message::RequestFile(file))
+    }
+
+    pub fn delete_file(&mut self, file_path: FilePath) -> Result<Ack, NetError> {
+        self.request(Message::DeleteFile(file_path))
+    }
+
+    /// Move a file or directory on the server. Needs the `RENAME` capability.
+    pub fn rename_file(&mut self, rename: Rename) -> Result<Ack, NetError> {
+        self.request(Message::RenameFile(rename))
+    }
+}
+
+/// Chunk requests grouped into batches for `RequestChunks`.
+///
+/// A batch holds at most [`MAX_BATCH_LEN`](../../messaging/constant.MAX_BATCH_LEN.html) chunks
+/// and, unless it's a single chunk, no more than `CHUNK_SIZE` bytes. Answering a batch then takes
+/// about as much room as answering a single `RequestChunk`, so the stream's credit still bounds
+/// the chunk data queued.
+#[derive(Default)]
+pub struct ChunkBatches {
+    batches: Vec<Vec<QualifiedChunkId>>,
+    /// Bytes requested by the last batch
+    size: u64,
+}
+
+impl ChunkBatches {
+    /// Request every chunk of `file`, at every offset it's found at
+    pub fn add_file(&mut self, file: &FileMetadata) {
+        for (i, chunk) in file.chunks.iter().enumerate() {
+            self.push(file, i, chunk);
+        }
+    }
+
+    /// Request the `missing` chunks of `file`, once each
+    pub fn add_missing(&mut self, file: &FileMetadata, missing: &[ChunkId]) {
+        let mut missing: HashSet<&ChunkId> = missing.iter().collect();
+        for (i, chunk) in file.chunks.iter().enumerate() {
+            if missing.remove(chunk) {
+                self.push(file, i, chunk);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    fn push(&mut self, file: &FileMetadata, index: usize, chunk: &ChunkId) {
+        let offset = (index * CHUNK_SIZE) as u64;
+        // Peers without `LARGE_OFFSETS` don't send the size, so every chunk counts as a full one
+        let len = match file.size {
+            0 if !file.chunks.is_empty() => CHUNK_SIZE as u64,
+            size => size.saturating_sub(offset).min(CHUNK_SIZE as u64),
+        };
+        let full = match self.batches.last() {
+            Some(batch) => batch.len() == MAX_BATCH_LEN || self.size + len > CHUNK_SIZE as u64,
+            None => true,
+        };
+        if full {
+            self.batches.push(vec![]);
+            self.size = 0;
+        }
+        self.size += len;
+        self.batches.last_mut().unwrap().push(QualifiedChunkId {
+            path: file.file_id.clone(),
+            offset,
+            id: chunk.clone(),
+        });
     }
 
-    pub async fn request_file_list(&mut self) -> Result<(), NetError> {
-        let msg = self
-            .builder
-            .encode_message::<arguments::Dummy>(Directive::ListFiles, None);
-        self.net_client.send(&msg).await
+    /// Messages requesting the chunks, one per chunk unless `batch` is set
+    pub fn into_messages(self, batch: bool) -> Vec<Message> {
+        if !batch {
+            return self
+                .batches
+                .into_iter()
+                .flatten()
+                .map(Message::RequestChunk)
+                .collect();
+        }
+        self.batches
+            .into_iter()
+            .map(|mut chunks| match chunks.len() {
+                1 => Message::RequestChunk(chunks.pop().unwrap()),
+                _ => Message::RequestChunks(ChunkList(chunks)),
+            })
+            .collect()
     }
+}
 
-    pub async fn request_file(&mut self, file: FileId) -> Result<(), NetError> {
-        let msg = self
-            .builder
-            .encode_message(Directive::RequestFile, Some(file));
-        self.net_client.send(&msg).await
+/// Read the chunk `chunk_id` starting at `offset` in the file at `file_path`
+///
+/// Only the requested chunk is hashed, a mismatch means the file changed since it was announced.
+fn read_chunk(
+    chunk_id: &ChunkId,
+    offset: u64,
+    file_path: &Path,
+) -> Result<arguments::Chunk, ProtocolError> {
+    let storage_failure = |e: String| ProtocolError::new(ErrorCode::STORAGE_FAILURE, e);
+    let changed = || {
+        ProtocolError::new(
+            ErrorCode::NOT_FOUND,
+            "The file changed since the chunk was requested",
+        )
+    };
+
+    if !offset.is_multiple_of(CHUNK_SIZE as u64) {
+        return Err(changed());
     }
 
-    pub async fn delete_file(&mut self, file_path: FilePath) -> Result<(), NetError> {
-        let msg = self
-            .builder
-            .encode_message(Directive::DeleteFile, Some(file_path));
-        self.net_client.send(&msg).await
+    let mut file = File::open(file_path).map_err(|e| storage_failure(e.to_string()))?;
+    let mut data = Vec::with_capacity(CHUNK_SIZE);
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.take(CHUNK_SIZE as u64).read_to_end(&mut data))
+        .map_err(|e| storage_failure(e.to_string()))?;
+
+    let hash = blake3::hash(&data).as_bytes().to_vec();
+    if chunk_id.to_bin() != hash {
+        return Err(changed());
+    }
+
+    Ok(arguments::Chunk {
+        id: arguments::ChunkId(hash),
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::arguments::EntryKind;
+
+    #[tokio::test]
+    async fn test_ack() {
+        let (tx, rx) = oneshot::channel();
+        tx.send(Ok(())).unwrap();
+        assert!(Ack(rx).await.is_ok());
+
+        let (tx, rx) = oneshot::channel();
+        tx.send(Err(ProtocolError::new(ErrorCode::NOT_FOUND, "gone")))
+            .unwrap();
+        assert!(matches!(
+            Ack(rx).await,
+            Err(RequestError::Refused(ProtocolError {
+                code: ErrorCode::NOT_FOUND,
+                ..
+            }))
+        ));
+
+        // The connection dropped along with the pending requests
+        let (tx, rx) = oneshot::channel::<Result<(), ProtocolError>>();
+        drop(tx);
+        assert!(matches!(Ack(rx).await, Err(RequestError::Disconnected)));
     }
 
-    pub async fn recv(&mut self) -> Result<Vec<u8>, NetError> {
-        self.net_client.recv().await
+    fn metadata(path: &str, size: u64, chunks: &[u8]) -> FileMetadata {
+        FileMetadata {
+            file_id: FileId {
+                path: path.into(),
+                hash: [0u8; 32],
+            },
+            file_name: path.into(),
+            permissions: 0o100644,
+            modified: 0,
+            created: 0,
+            size,
+            chunks: chunks.iter().map(|x| ChunkId([*x; 32].to_vec())).collect(),
+            kind: EntryKind::File,
+            extended: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_chunk_batches() {
+        // Small files share batches, as long as they fit in a chunk
+        let mut batches = ChunkBatches::default();
+        for i in 0..3 {
+            batches.add_file(&metadata(&i.to_string(), CHUNK_SIZE as u64 / 2, &[i]));
+        }
+        let messages = batches.into_messages(true);
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[0], Message::RequestChunks(ChunkList(x)) if x.len() == 2));
+        assert!(matches!(&messages[1], Message::RequestChunk(_)));
+
+        // Full chunks go one by one, every time they're found in the file
+        let mut batches = ChunkBatches::default();
+        batches.add_file(&metadata("big", 2 * CHUNK_SIZE as u64 + 1, &[1, 1, 2]));
+        let offsets: Vec<u64> = batches
+            .into_messages(true)
+            .into_iter()
+            .map(|msg| match msg {
+                Message::RequestChunk(chunk) => chunk.offset,
+                msg => panic!("Unexpected {:?}", msg),
+            })
+            .collect();
+        assert_eq!(offsets, [0, CHUNK_SIZE as u64, 2 * CHUNK_SIZE as u64]);
+
+        // Missing chunks are requested once, at their first offset
+        let mut batches = ChunkBatches::default();
+        let file = metadata("big", 2 * CHUNK_SIZE as u64 + 1, &[1, 1, 2]);
+        batches.add_missing(
+            &file,
+            &[ChunkId([2; 32].to_vec()), ChunkId([1; 32].to_vec())],
+        );
+        let messages = batches.into_messages(false);
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[0], Message::RequestChunk(x) if x.offset == 0));
+
+        // Batches don't get longer than `MAX_BATCH_LEN`
+        let mut batches = ChunkBatches::default();
+        for i in 0..=MAX_BATCH_LEN {
+            batches.add_file(&metadata(&i.to_string(), 1, &[1]));
+        }
+        assert_eq!(batches.into_messages(true).len(), 2);
+    }
+
+    #[test]
+    fn test_read_chunk() {
+        let path = std::env::temp_dir().join(format!("phoenix-chunk-{}", std::process::id()));
+        let mut data = vec![1; CHUNK_SIZE];
+        data.extend_from_slice(b"tail");
+        std::fs::write(&path, &data).unwrap();
+        let tail = ChunkId(blake3::hash(b"tail").as_bytes().to_vec());
+
+        let chunk = read_chunk(&tail, CHUNK_SIZE as u64, &path).unwrap();
+        assert_eq!(chunk.data, b"tail");
+        // Offsets between chunks are refused
+        assert!(read_chunk(&tail, 4, &path).is_err());
+
+        std::fs::write(&path, b"changed").unwrap();
+        let changed = read_chunk(&tail, CHUNK_SIZE as u64, &path).unwrap_err();
+        assert_eq!(changed.code, ErrorCode::NOT_FOUND);
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -1,55 +1,135 @@
 use crate::{
-    config::{ClientConfig, Config},
+    config::{self, ClientConfig, Config, Endpoint, SymlinkPolicy},
     messaging::{
         self,
-        arguments::{FileId, FileList, FileMetadata, FilePath, QualifiedChunk, QualifiedChunkId},
-        Message, MessageBuilder,
+        arguments::{Capabilities, EntryKind, ErrorCode, FileId, FileMetadata, FilePath, Rename},
+        mux::{MuxSender, CONTROL_STREAM},
+        Envelope, Message,
+    },
+    net::{
+        error::NetError, BoxedTransport, Duplex, Heartbeat, Incoming, NetClient, NoiseConnection,
+        RateLimiter,
     },
-    net::{NetClient, NoiseConnection},
 };
+use backoff::Backoff;
 use base64ct::{Base64, Encoding};
-use file_operations::Client;
+use file_operations::{Ack, Client, RequestError};
 use notify::{watcher, DebouncedEvent, Watcher};
+use queue::{Change, ChangeQueue};
 use std::{
-    collections::{HashMap, HashSet},
-    fs::{self, File},
+    collections::HashSet,
+    error::Error,
+    fs::{self, File, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll},
     thread,
     time::Duration,
 };
 use tokio::{
-    net::TcpStream,
-    select,
-    sync::mpsc::{self, Receiver, Sender},
+    io::{AsyncRead, ReadBuf},
+    net::{TcpStream, UnixStream},
+    process, select,
+    signal::unix::{signal, SignalKind},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        watch,
+    },
+    task::JoinSet,
 };
 
+pub mod backoff;
+mod blacklist;
+mod extended;
 mod file_operations;
+mod queue;
 mod utils;
 
-pub use file_operations::CHUNK_SIZE;
+pub use blacklist::Blacklist;
+pub use file_operations::{ChunkBatches, CHUNK_SIZE};
 
-pub type Blacklist = HashMap<PathBuf, FileMetadata>;
+/// Number of messages from the server buffered while the client is busy
+const INCOMING_CAPACITY: usize = 16;
 
-pub async fn start_client(config_file: &Path, path: &Path) {
-    let config = ClientConfig::read_config(config_file).unwrap();
+/// Queued changes sent with a single request, along with their sequence numbers
+type SentChanges = Vec<(u64, Change)>;
+
+/// Queued changes that were sent to the server and wait for its response
+#[derive(Default)]
+struct InFlight {
+    seqs: HashSet<u64>,
+    /// Changes sent with each request, along with the server's response
+    acks: JoinSet<(SentChanges, Result<(), RequestError>)>,
+}
+
+impl InFlight {
+    /// Wait for the server's response to the request that sent `changes`
+    fn add(&mut self, changes: SentChanges, ack: Ack) {
+        for (seq, change) in &changes {
+            info!("Sent {:?}", change);
+            self.seqs.insert(*seq);
+        }
+        self.acks.spawn(async move { (changes, ack.await) });
+    }
+}
+
+/// Small files waiting to be sent together with a `SendFileBatch`
+#[derive(Default)]
+struct UploadBatch {
+    changes: SentChanges,
+    files: Vec<FileMetadata>,
+}
 
-    let net_client = NetClient::new(
-        TcpStream::connect(config.server_address).await.unwrap(),
-        &Base64::decode_vec(&config.privkey).unwrap(),
-        &[Base64::decode_vec(&config.server_pubkey).unwrap()],
-    )
-    .await
-    .unwrap();
+impl UploadBatch {
+    /// Add the upload of `file`, sending the batch once it's full
+    fn add(
+        &mut self,
+        client: &mut Client,
+        in_flight: &mut InFlight,
+        seq: u64,
+        change: Change,
+        file: FileMetadata,
+    ) -> Result<(), NetError> {
+        self.changes.push((seq, change));
+        self.files.push(file);
+        if self.files.len() == messaging::MAX_BATCH_LEN {
+            self.send(client, in_flight)?;
+        }
+        Ok(())
+    }
+
+    /// Send the files added so far, if any
+    fn send(&mut self, client: &mut Client, in_flight: &mut InFlight) -> Result<(), NetError> {
+        if self.files.is_empty() {
+            return Ok(());
+        }
+        let ack = client.send_files(std::mem::take(&mut self.files))?;
+        in_flight.add(std::mem::take(&mut self.changes), ack);
+        Ok(())
+    }
+}
 
-    let builder = messaging::MessageBuilder::new(1);
-    let mut client = Client::new(builder, net_client);
+pub async fn start_client(config_file: &Path, path: &Path) {
+    let config = ClientConfig::read_config(config_file).unwrap();
 
     let watch_path = PathBuf::from(path);
     if !fs::metadata(&watch_path).unwrap().is_dir() {
         error!("Can only watch directories not files!");
         std::process::exit(1);
     }
+    // Everything below works on the canonical path, so it's resolved once and for all
+    let watch_path = watch_path.canonicalize().unwrap();
+
+    let queue_path = config.state_path.join(utils::state_dir_name(&watch_path));
+    let queue = ChangeQueue::open(&queue_path).expect("Couldn't open the change queue");
+    if queue.len() > 0 {
+        info!("{} local changes waiting to be sent", queue.len());
+    }
 
+    // The watcher is setup before connecting so it keeps running across reconnects
     let (tx, rx) = std::sync::mpsc::channel();
     let mut watcher = watcher(tx, Duration::from_secs(1)).unwrap();
     let (tx, mut fs_event): (Sender<DebouncedEvent>, Receiver<DebouncedEvent>) = mpsc::channel(100);
@@ -70,158 +150,747 @@ pub async fn start_client(config_file: &Path, path: &Path) {
         .watch(&watch_path, notify::RecursiveMode::Recursive)
         .unwrap();
 
-    // Get startup file list to compare against local file tree
-    client.request_file_list().await.unwrap();
+    let (upload_tx, upload_limit) = watch::channel(config::limit_bytes(config.upload_limit));
+    let (download_tx, download_limit) = watch::channel(config::limit_bytes(config.download_limit));
+    tokio::spawn(reload_limits(
+        config_file.to_owned(),
+        upload_tx,
+        download_tx,
+    ));
+    let limits = (upload_limit, download_limit);
+
+    let privkey = Base64::decode_vec(&config.privkey).expect("Couldn't decode private key");
+    let server_pubkey =
+        Base64::decode_vec(&config.server_pubkey).expect("Couldn't decode server public key");
+
+    let mut backoff = Backoff::new(backoff::MIN_DELAY, backoff::MAX_DELAY);
+    let mut blacklist = Blacklist::new();
+    loop {
+        let session = open_session(&config, &privkey, &server_pubkey, &limits).await;
+        let (mut client, mut incoming) = match session {
+            Ok(x) => x,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "Failed to connect to the server ({}), retrying in {:?}",
+                    e, delay
+                );
+                // Keep queueing local changes while waiting
+                let sleep = tokio::time::sleep(delay);
+                tokio::pin!(sleep);
+                loop {
+                    select! {
+                        _ = &mut sleep => break,
+                        Some(event) = fs_event.recv() => {
+                            queue_fs_event(&queue, &watch_path, event, &blacklist, config.symlinks);
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+        info!("Connected to the server");
+        backoff.reset();
+
+        let e = run_session(
+            &config,
+            &mut client,
+            &mut incoming,
+            &watch_path,
+            &mut fs_event,
+            &mut blacklist,
+            &queue,
+        )
+        .await;
+        warn!("Lost connection to the server: {}", e);
+
+        // Partial downloads won't complete on the new connection. The reconciliation after
+        // reconnecting requests them again.
+        blacklist.clear();
+    }
+}
+
+/// Re-read the bandwidth limits from the config file on every SIGHUP.
+///
+/// The current connection picks up the new limits right away. Every other setting needs a
+/// restart.
+async fn reload_limits(
+    config_file: PathBuf,
+    upload_limit: watch::Sender<Option<u64>>,
+    download_limit: watch::Sender<Option<u64>>,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Can't listen for SIGHUP, limits won't be reloaded: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match ClientConfig::read_config(&config_file) {
+            Ok(config) => {
+                upload_limit.send_replace(config::limit_bytes(config.upload_limit));
+                download_limit.send_replace(config::limit_bytes(config.download_limit));
+                info!(
+                    "Reloaded bandwidth limits: upload {:?} KiB/s, download {:?} KiB/s",
+                    config.upload_limit, config.download_limit
+                );
+            }
+            Err(e) => error!("Failed to reload {}: {}", config_file.display(), e),
+        }
+    }
+}
+
+/// Connect to the server and complete the Noise handshake.
+///
+/// Messages from the server are read in the background and handed out through the returned
+/// [`Incoming`], so sending never keeps them from being received. `limits` holds the upload and
+/// download limits in bytes/s.
+async fn open_session(
+    config: &ClientConfig,
+    privkey: &[u8],
+    server_pubkey: &[u8],
+    limits: &(watch::Receiver<Option<u64>>, watch::Receiver<Option<u64>>),
+) -> Result<(Client, Incoming), Box<dyn Error>> {
+    let mut net_client =
+        NetClient::new(connect(config).await?, privkey, &[server_pubkey.to_vec()]).await?;
+    net_client.set_limiters(
+        RateLimiter::new(limits.0.clone()),
+        RateLimiter::new(limits.1.clone()),
+    );
+    let (sender, receiver) = net_client.split();
+    let sender = MuxSender::spawn(sender);
+    let mut incoming = receiver.spawn(INCOMING_CAPACITY);
+
+    // Both sides announce their version before anything else
+    let mut builder = messaging::MessageBuilder::new(messaging::PROTOCOL_VERSION);
+    // Only root can give files away, so other users leave ownership to the clients that can
+    if unsafe { libc::geteuid() } != 0 {
+        builder.set_capabilities(Capabilities(
+            messaging::CAPABILITIES.0 & !Capabilities::OWNERSHIP.0,
+        ));
+    }
+    sender.send(CONTROL_STREAM, builder.announce_version())?;
+    let announcement =
+        tokio::time::timeout(Duration::from_secs(config.idle_timeout), incoming.recv())
+            .await
+            .map_err(|_| NetError::Timeout)??;
+    let capabilities = builder.negotiate(&announcement)?;
+    debug!("Agreed on capabilities {:?}", capabilities);
+    sender.set_compression(capabilities.contains(Capabilities::COMPRESSION))?;
+
+    Ok((Client::new(builder, sender, capabilities), incoming))
+}
+
+/// Handle server and filesystem events until the connection to the server drops
+async fn run_session(
+    config: &ClientConfig,
+    client: &mut Client,
+    incoming: &mut Incoming,
+    watch_path: &Path,
+    fs_event: &mut Receiver<DebouncedEvent>,
+    blacklist: &mut Blacklist,
+    queue: &ChangeQueue,
+) -> NetError {
+    // Changes made while offline go out before the file list is requested, otherwise the
+    // reconciliation would bring back files that were deleted in the meantime
+    let symlinks = config.symlinks;
+    let mut in_flight = InFlight::default();
+    if let Err(e) = drain_queue(client, watch_path, queue, &mut in_flight, symlinks) {
+        return e;
+    }
+
+    // Get startup file list to compare against local file tree. The list itself is all that's
+    // needed, so the response is ignored.
+    if let Err(e) = client.request_file_list() {
+        return e;
+    }
 
-    let mut blacklist: Blacklist = HashMap::new();
+    let mut heartbeat = Heartbeat::new(
+        Duration::from_secs(config.heartbeat_interval),
+        Duration::from_secs(config.idle_timeout),
+    );
     loop {
         select! {
             // Server messages
-            push = (&mut client).recv() => {
-                match MessageBuilder::decode_message(&push.unwrap()) {
-                    Ok(msg) => handle_server_event(&mut client, &watch_path, *msg, &mut blacklist).await,
+            push = incoming.recv() => {
+                let push = match push {
+                    Ok(x) => x,
+                    Err(e) => return e,
+                };
+                heartbeat.seen();
+                match client.decode(&push) {
+                    Ok(msg) => {
+                        // Streams opened by the server have even ids
+                        if msg.stream % 2 == 0 {
+                            if let Err(e) = client.accept_request(msg.stream) {
+                                return e;
+                            }
+                        }
+                        handle_server_event(client, watch_path, msg, blacklist, queue, symlinks).await
+                    }
                     Err(e) => error!("msg decode error: {:?}", e),
                 }
+                // Comparing against the server's file list queues the files it's missing
+                if queue.len() > in_flight.seqs.len() {
+                    if let Err(e) = drain_queue(client, watch_path, queue, &mut in_flight, symlinks) {
+                        return e;
+                    }
+                }
             }
             // Filesystem messages
             event = fs_event.recv() => {
-                if event.is_some() {
-                    handle_fs_event(
-                        &mut client,
-                        &watch_path.canonicalize().unwrap(),
-                        event.unwrap(),
-                        &mut blacklist).await;
+                if let Some(event) = event {
+                    queue_fs_event(queue, watch_path, event, blacklist, symlinks);
+                    // Events that are already waiting are sent along with this one
+                    while let Ok(event) = fs_event.try_recv() {
+                        queue_fs_event(queue, watch_path, event, blacklist, symlinks);
+                    }
+                    if let Err(e) = drain_queue(client, watch_path, queue, &mut in_flight, symlinks) {
+                        return e;
+                    }
                 } else {
                     debug!("Failing fs_event checking");
                 }
             }
+            // Responses to the changes sent from the queue
+            Some(done) = in_flight.acks.join_next() => {
+                let (changes, res) = done.expect("Waiting on a response failed");
+                for (seq, change) in changes {
+                    in_flight.seqs.remove(&seq);
+                    match (&res, &change) {
+                        (Ok(()), _) => info!("Server committed {:?}", change),
+                        // Sent again on the next connection
+                        (Err(RequestError::Disconnected), _) => continue,
+                        // The server never had the old path, so the files are uploaded instead
+                        (Err(RequestError::Refused(e)), Change::Rename { to, .. })
+                            if e.code == ErrorCode::NOT_FOUND =>
+                        {
+                            debug!("Uploading {:?} instead of moving it: {}", to, e);
+                            queue_uploads(queue, watch_path, to, symlinks);
+                        }
+                        // Moves the server can't make yet are replaced like on servers that
+                        // can't move files at all
+                        (Err(RequestError::Refused(e)), Change::Rename { from, to })
+                            if e.code == ErrorCode::UPLOAD_PENDING =>
+                        {
+                            debug!("Replacing {:?} with {:?}: {}", from, to, e);
+                            if let Err(e) = queue.push(&Change::Delete(from.clone())) {
+                                error!("Failed to queue the deletion of {:?}: {}", from, e);
+                            }
+                            queue_uploads(queue, watch_path, to, symlinks);
+                        }
+                        (Err(e), _) => warn!("Dropping {:?}: {}", change, e),
+                    }
+                    if let Err(e) = queue.remove(seq, &change) {
+                        error!("Failed to update the change queue: {}", e);
+                    }
+                }
+                if queue.len() > in_flight.seqs.len() {
+                    if let Err(e) = drain_queue(client, watch_path, queue, &mut in_flight, symlinks) {
+                        return e;
+                    }
+                }
+            }
+            beat = heartbeat.tick() => {
+                if let Err(e) = beat {
+                    return e;
+                }
+                if let Err(e) = client.ping().await {
+                    return e;
+                }
+            }
+        }
+    }
+}
+
+/// Open the byte stream to the server.
+///
+/// This either connects to `server_address` (over TCP, or a Unix domain socket when prefixed with
+/// `unix:`) or, when `server_command` is set, spawns the command and talks to the server over its
+/// stdin/stdout.
+async fn connect(config: &ClientConfig) -> io::Result<BoxedTransport> {
+    let command = match &config.server_command {
+        Some(command) => command,
+        None => {
+            return match Endpoint::from(config.server_address.clone()) {
+                Endpoint::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr).await?)),
+                Endpoint::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+            }
+        }
+    };
+
+    info!("Spawning server command: {}", command);
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let missing = |pipe| io::Error::other(format!("Server command has no {}", pipe));
+    let stdin = child.stdin.take().ok_or_else(|| missing("stdin"))?;
+    let stdout = child.stdout.take().ok_or_else(|| missing("stdout"))?;
+
+    Ok(Box::new(Duplex::new(
+        CommandOutput { stdout, child },
+        stdin,
+    )))
+}
+
+/// Stdout of the server command, which owns the command's process.
+///
+/// The connection is dropped once the session ends, which kills the command in case it didn't
+/// exit when its stdin was closed.
+struct CommandOutput {
+    stdout: process::ChildStdout,
+    child: process::Child,
+}
+
+impl AsyncRead for CommandOutput {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_read(cx, buf)
+    }
+}
+
+impl Drop for CommandOutput {
+    fn drop(&mut self) {
+        match self.child.try_wait() {
+            Ok(Some(status)) => info!("Server command exited: {}", status),
+            Ok(None) => info!("Killing server command"),
+            Err(e) => error!("Failed to check on server command: {}", e),
         }
     }
 }
 
+/// Log the failure of a request that nothing else waits on
+fn report_failure(ack: Ack, request: String) {
+    tokio::spawn(async move {
+        if let Err(RequestError::Refused(e)) = ack.await {
+            warn!("{} failed: {}", request, e);
+        }
+    });
+}
+
 async fn handle_server_event(
     client: &mut Client,
     watch_path: &Path,
-    event: Message,
+    event: Envelope,
     blacklist: &mut Blacklist,
+    queue: &ChangeQueue,
+    symlinks: SymlinkPolicy,
 ) {
-    let verb = event.verb.clone();
-    match verb {
-        messaging::Directive::SendFiles => {
-            let files = utils::generate_file_list(watch_path).unwrap();
-            let mut local_files: HashSet<FileId> = HashSet::new();
-            for file in files.0 {
-                local_files.insert(file);
-            }
-
-            let mut server_files: HashSet<FileId> = HashSet::new();
-
-            if let Some(argument) = event.argument {
-                let files = argument.as_any().downcast_ref::<FileList>().unwrap();
-
-                for file in &files.0 {
-                    server_files.insert(file.clone());
-                }
-            }
+    match event.message {
+        Message::SendFiles(files) => {
+            let local_files: HashSet<FileId> =
+                match utils::generate_file_list(watch_path, watch_path, symlinks) {
+                    Ok(list) => list.0.into_iter().collect(),
+                    Err(e) => {
+                        error!("Failed to list local files: {}", e);
+                        return;
+                    }
+                };
+            let server_files: HashSet<FileId> = files.0.into_iter().collect();
 
             for file in local_files.difference(&server_files) {
                 debug!("File not found on server: {:?}", file.path);
-                client
-                    .send_file_info(watch_path, &watch_path.join(&file.path))
-                    .await
-                    .unwrap();
+                // Uploads go through the queue so they're retried until the server commits them
+                let change = Change::Update(file.path.clone());
+                if let Err(e) = queue.push(&change) {
+                    error!("Failed to queue {:?}: {}", change, e);
+                }
             }
             for file in server_files.difference(&local_files) {
                 debug!("File not found locally: {:?}", file.path);
-                let _ = client.request_file(file.clone()).await;
+                if let Ok(ack) = client.request_file(file.clone()) {
+                    report_failure(ack, format!("Requesting {:?}", file.path));
+                }
             }
         }
-        messaging::Directive::RequestFile => todo!(),
-        messaging::Directive::RequestChunk => {
-            if let Some(argument) = event.argument {
-                let chunk: &QualifiedChunkId = argument
-                    .as_any()
-                    .downcast_ref::<QualifiedChunkId>()
-                    .unwrap();
-                let path = watch_path.join(chunk.path.path.clone());
-                client
-                    .send_chunk(&chunk.id, &path)
-                    .await
-                    .expect("Failed to queue chunk");
-            }
-        }
-        messaging::Directive::SendFile => {
-            if let Some(argument) = event.argument {
-                let file_md = argument.as_any().downcast_ref::<FileMetadata>().unwrap();
-                let path = file_md.file_id.path.clone();
-                // The blacklist needs to be updated to make sure we dont send file information for
-                // a in progress transfer
-                debug!("adding to blacklist");
-                blacklist.insert(path, file_md.clone());
-                let mut _file = File::create(watch_path.join(&file_md.file_id.path)).unwrap();
-                info!("Started file download: {:?}", &file_md.file_id.path);
-                for (i, chunk) in file_md.chunks.iter().enumerate() {
-                    let q_chunk = QualifiedChunkId {
-                        path: file_md.file_id.clone(),
-                        offset: (i * CHUNK_SIZE) as u32,
-                        id: chunk.clone(),
-                    };
-                    client.request_chunk(q_chunk).await.unwrap();
-                }
-            }
-        }
-        messaging::Directive::SendQualifiedChunk => {
-            if let Some(argument) = event.argument {
-                if let Err(e) = utils::write_chunk(
+        Message::Response(_) => client.resolve(event.id, Ok(())),
+        Message::Error(error) => client.resolve(event.id, Err(error)),
+        Message::Credit(credit) => {
+            let _ = client.add_credit(event.stream, credit.0);
+        }
+        Message::Ping => {
+            if let Err(e) = client.pong().await {
+                error!("Failed to answer ping: {}", e);
+            }
+        }
+        Message::Pong => {}
+        Message::RequestChunk(chunk) => {
+            let path = watch_path.join(&chunk.path.path);
+            if let Err(e) = client
+                .send_chunk(event.id, event.stream, &chunk, &path)
+                .await
+            {
+                error!("Failed to send chunk: {}", e);
+            }
+        }
+        Message::RequestChunks(chunks) => {
+            if let Err(e) = client
+                .send_chunks(event.id, event.stream, &chunks.0, watch_path)
+                .await
+            {
+                error!("Failed to send chunks: {}", e);
+            }
+        }
+        Message::SendFile(file_md) => {
+            let mut chunks = ChunkBatches::default();
+            receive_entry(
+                client,
+                watch_path,
+                blacklist,
+                file_md,
+                symlinks,
+                &mut chunks,
+            );
+            request_download(client, chunks);
+        }
+        Message::SendFileBatch(files) => {
+            // The files share a stream, and their chunks are requested together
+            let mut chunks = ChunkBatches::default();
+            for file_md in files.0 {
+                receive_entry(
+                    client,
+                    watch_path,
                     blacklist,
-                    &watch_path.canonicalize().unwrap(),
-                    argument.as_any().downcast_ref::<QualifiedChunk>().unwrap(),
-                ) {
-                    error!("{}", e);
-                }
+                    file_md,
+                    symlinks,
+                    &mut chunks,
+                );
+            }
+            request_download(client, chunks);
+        }
+        Message::SendQualifiedChunk(chunk) => {
+            if let Err(e) = utils::write_chunk(
+                blacklist,
+                watch_path,
+                &chunk,
+                symlinks,
+                client.capabilities(),
+            ) {
+                error!("{}", e);
             }
         }
-        messaging::Directive::DeleteFile => {
-            if let Some(argument) = event.argument {
-                let fpath = argument.as_any().downcast_ref::<FilePath>().unwrap();
-                debug!("Got file deletion of {:?}", fpath);
-                let _ = tokio::fs::remove_file(watch_path.join(&fpath.0)).await;
+        Message::DeleteFile(fpath) => {
+            debug!("Got file deletion of {:?}", fpath);
+            // Unless links are followed, nothing is deleted through one
+            if let Err(e) = utils::check_parents(watch_path, &fpath.0, symlinks) {
+                error!("Refusing deletion of {:?}: {}", fpath.0, e);
+                return;
             }
+            // Everything removed below a directory gets an event of its own, which mustn't be
+            // sent back
+            blacklist.ignore(&fpath.0);
+            let path = watch_path.join(&fpath.0);
+            // Links to directories are removed like files, never followed
+            let _ = match tokio::fs::symlink_metadata(&path).await {
+                Ok(md) if md.is_dir() => tokio::fs::remove_dir_all(&path).await,
+                _ => tokio::fs::remove_file(&path).await,
+            };
+        }
+        Message::RenameFile(rename) => {
+            debug!("Got move of {:?} to {:?}", rename.from, rename.to);
+            if let Err(e) = utils::check_parents(watch_path, &rename.from, symlinks)
+                .and_then(|_| utils::check_parents(watch_path, &rename.to, symlinks))
+            {
+                error!(
+                    "Refusing move of {:?} to {:?}: {}",
+                    rename.from, rename.to, e
+                );
+                return;
+            }
+            let to = watch_path.join(&rename.to);
+            if let Some(parent) = to.parent() {
+                let _ = tokio::fs::create_dir_all(parent).await;
+            }
+            // The client that made the move already did it. The watcher's events for it are
+            // echoes that mustn't be sent back.
+            blacklist.ignore(&rename.from);
+            blacklist.ignore(&rename.to);
+            if let Err(e) = tokio::fs::rename(watch_path.join(&rename.from), &to).await {
+                debug!("Couldn't move {:?}: {}", rename.from, e);
+            }
+        }
+        // Only sent by clients, or already handled while connecting
+        Message::AnnounceVersion(_)
+        | Message::ListFiles
+        | Message::RequestFile(_)
+        | Message::SendChunk(_) => {
+            debug!("Ignoring {:?} from the server", event.message.directive())
         }
-        _ => {}
     };
 }
 
-async fn handle_fs_event(
-    client: &mut Client,
+/// Create the entry described by `file_md`, adding the chunks it needs to `chunks`
+fn receive_entry(
+    client: &Client,
+    watch_path: &Path,
+    blacklist: &mut Blacklist,
+    file_md: FileMetadata,
+    symlinks: SymlinkPolicy,
+    chunks: &mut ChunkBatches,
+) {
+    // Unless links are followed, nothing received is written through one
+    if let Err(e) = utils::prepare_write(watch_path, &file_md.file_id.path, symlinks) {
+        error!("Refusing {:?}: {}", file_md.file_id.path, e);
+        return;
+    }
+    match &file_md.kind {
+        EntryKind::File => download_file(client, watch_path, blacklist, file_md, chunks),
+        EntryKind::Directory => {
+            debug!("Got directory {:?}", file_md.file_id.path);
+            if let Err(e) = utils::create_dir(watch_path, &file_md, client.capabilities()) {
+                error!("Failed to create {:?}: {}", file_md.file_id.path, e);
+            }
+        }
+        EntryKind::Symlink(target) => {
+            debug!("Got link {:?} to {:?}", file_md.file_id.path, target);
+            if symlinks == SymlinkPolicy::Skip {
+                return;
+            }
+            if let Err(e) = utils::create_symlink(watch_path, &file_md, target) {
+                error!("Failed to create {:?}: {}", file_md.file_id.path, e);
+            }
+        }
+    }
+}
+
+/// Request the chunks of the files being downloaded on a stream of their own, so they can't hold
+/// up the other downloads
+fn request_download(client: &mut Client, chunks: ChunkBatches) {
+    if chunks.is_empty() {
+        return;
+    }
+    let stream = client.open_stream();
+    if let Err(e) = client.request_chunks(stream, chunks) {
+        error!("Failed to request chunks: {}", e);
+    }
+}
+
+/// Allocate the file described by `file_md` and add all of its chunks to `chunks`
+fn download_file(
+    client: &Client,
     watch_path: &Path,
-    event: DebouncedEvent,
     blacklist: &mut Blacklist,
+    file_md: FileMetadata,
+    chunks: &mut ChunkBatches,
 ) {
-    match event {
-        DebouncedEvent::Rename(_, p)
-        | DebouncedEvent::Create(p)
-        | DebouncedEvent::Write(p)
-        | DebouncedEvent::Chmod(p) => {
+    // Chunks past 4 GiB can't be requested from servers without `LARGE_OFFSETS`
+    if !client.capabilities().fits(&file_md) {
+        error!("{:?} is too large to be downloaded", file_md.file_id.path);
+        return;
+    }
+    let full_path = watch_path.join(&file_md.file_id.path);
+    // Content that's already here, e.g. after a chmod, only needs its metadata updated
+    if FileId::new(full_path.clone()).is_ok_and(|id| id.hash == file_md.file_id.hash) {
+        debug!("Updating metadata of {:?}", file_md.file_id.path);
+        if let Err(e) = utils::apply_metadata(watch_path, &file_md, client.capabilities()) {
+            error!("Failed to update {:?}: {}", file_md.file_id.path, e);
+        }
+        return;
+    }
+
+    let path = file_md.file_id.path.clone();
+    // The blacklist needs to be updated to make sure we dont send file information for
+    // a in progress transfer
+    debug!("adding to blacklist");
+    blacklist.insert(path, file_md.clone());
+    // The directory's own entry might still be on its way
+    if let Some(parent) = full_path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    // A previous download might have left the file read only. Its permissions are applied again
+    // once this one completes.
+    if let Ok(md) = fs::metadata(&full_path) {
+        let mode = md.permissions().mode();
+        if mode & 0o200 == 0 {
+            let _ = fs::set_permissions(&full_path, Permissions::from_mode(mode | 0o200));
+        }
+    }
+    // Allocated up front so the chunks can be written in any order, and so files that
+    // shrank don't keep their old tail
+    let allocated = File::create(&full_path).and_then(|file| file.set_len(file_md.size));
+    if let Err(e) = allocated {
+        error!("Failed to allocate {:?}: {}", file_md.file_id.path, e);
+        blacklist.remove(&file_md.file_id.path);
+        return;
+    }
+    // Empty files have no chunks to wait for
+    if file_md.chunks.is_empty() {
+        blacklist.remove(&file_md.file_id.path);
+        if let Err(e) = utils::apply_metadata(watch_path, &file_md, client.capabilities()) {
+            error!("Failed to update {:?}: {}", file_md.file_id.path, e);
+        }
+        return;
+    }
+    info!("Started file download: {:?}", &file_md.file_id.path);
+    chunks.add_file(&file_md);
+}
+
+/// Add the change behind a filesystem event to the queue
+fn queue_fs_event(
+    queue: &ChangeQueue,
+    watch_path: &Path,
+    event: DebouncedEvent,
+    blacklist: &Blacklist,
+    symlinks: SymlinkPolicy,
+) {
+    let change = match event {
+        DebouncedEvent::Rename(from, to) => {
+            let from = from.strip_prefix(watch_path).unwrap().to_owned();
+            let to = to.strip_prefix(watch_path).unwrap().to_owned();
+            // Moves made on behalf of the server are already known to it
+            if blacklist.is_echo(&from) || blacklist.is_echo(&to) {
+                return;
+            }
+            Change::Rename { from, to }
+        }
+        DebouncedEvent::Create(p) | DebouncedEvent::Write(p) | DebouncedEvent::Chmod(p) => {
+            let path = p.strip_prefix(watch_path).unwrap().to_owned();
             // Check the blacklist to make sure the event isn't from a partial file transfer
-            if !blacklist.contains_key(p.strip_prefix(watch_path).unwrap()) {
-                match client.send_file_info(watch_path, &p).await {
-                    Ok(_) => {
-                        info!("Successfully sent the file");
-                    }
-                    Err(e) => error!("{:?}", e),
-                };
+            if blacklist.contains(&path) {
+                return;
+            }
+            match utils::sync_as(watch_path, &p, symlinks) {
+                Ok(utils::SyncAs::Skipped) => return,
+                // Nothing below a followed link to a directory gets events of its own
+                Ok(utils::SyncAs::Content) if p.is_symlink() && p.is_dir() => {
+                    queue_uploads(queue, watch_path, &path, symlinks)
+                }
+                _ => {}
             }
+            Change::Update(path)
         }
         DebouncedEvent::Remove(p) => {
-            match client
-                .delete_file(FilePath::new(p.strip_prefix(watch_path).unwrap()))
-                .await
-            {
-                Ok(_) => info!("Successfully deleted the file"),
-                Err(e) => error!("{:?}", e),
+            let path = p.strip_prefix(watch_path).unwrap().to_owned();
+            if blacklist.is_echo(&path) {
+                return;
+            }
+            Change::Delete(path)
+        }
+        _ => return,
+    };
+    if let Err(e) = queue.push(&change) {
+        error!("Failed to queue {:?}: {}", change, e);
+    }
+}
+
+/// Queue uploads of the file at `path`, or of every file below it when it's a directory
+fn queue_uploads(queue: &ChangeQueue, watch_path: &Path, path: &Path, symlinks: SymlinkPolicy) {
+    let full_path = watch_path.join(path);
+    let paths = if full_path.is_dir() {
+        match utils::generate_file_list(watch_path, &full_path, symlinks) {
+            Ok(files) => files.0.into_iter().map(|file| file.path).collect(),
+            Err(e) => {
+                error!("Failed to list {:?}: {}", full_path, e);
+                return;
             }
         }
-        _ => {}
+    } else {
+        vec![path.to_owned()]
+    };
+    for path in paths {
+        let change = Change::Update(path);
+        if let Err(e) = queue.push(&change) {
+            error!("Failed to queue {:?}: {}", change, e);
+        }
+    }
+}
+
+/// Send the queued changes that aren't in flight yet to the server, oldest first.
+///
+/// When the server supports it, uploads of files no larger than a chunk are sent together with
+/// the ones queued next to them. A change is only removed from the queue once the server has
+/// answered it, see [`run_session`]. Network errors leave it in place for the next connection
+/// and are returned so the session can be torn down. Changes that can't be sent for local reasons
+/// (e.g. the file was removed again) are dropped.
+fn drain_queue(
+    client: &mut Client,
+    watch_path: &Path,
+    queue: &ChangeQueue,
+    in_flight: &mut InFlight,
+    symlinks: SymlinkPolicy,
+) -> Result<(), NetError> {
+    let mut batch = UploadBatch::default();
+    for entry in queue.iter() {
+        let (seq, change) = match entry {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to read the change queue: {}", e);
+                break;
+            }
+        };
+        if in_flight.seqs.contains(&seq) {
+            continue;
+        }
+
+        // Everything but small files is sent after the batch, so the changes stay in order
+        let res = match &change {
+            Change::Update(path) => {
+                match client.file_info(watch_path, &watch_path.join(path), symlinks) {
+                    Ok(file)
+                        if client.supports(Capabilities::BATCH)
+                            && file.size <= CHUNK_SIZE as u64 =>
+                    {
+                        batch.add(client, in_flight, seq, change, file)?;
+                        continue;
+                    }
+                    Ok(file) => {
+                        batch.send(client, in_flight)?;
+                        client.send_files(vec![file]).map_err(|e| e.into())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Change::Delete(path) => {
+                batch.send(client, in_flight)?;
+                client
+                    .delete_file(FilePath::new(path))
+                    .map_err(|e| e.into())
+            }
+            Change::Rename { from, to } if client.supports(Capabilities::RENAME) => {
+                batch.send(client, in_flight)?;
+                client
+                    .rename_file(Rename {
+                        from: from.clone(),
+                        to: to.clone(),
+                    })
+                    .map_err(|e| e.into())
+            }
+            // Servers that can't move files get the old path deleted and the new one uploaded
+            Change::Rename { from, to } => {
+                let delete = Change::Delete(from.clone());
+                if let Err(e) = queue.push(&delete) {
+                    error!("Failed to queue {:?}: {}", delete, e);
+                    break;
+                }
+                queue_uploads(queue, watch_path, to, symlinks);
+                if let Err(e) = queue.remove(seq, &change) {
+                    error!("Failed to update the change queue: {}", e);
+                    break;
+                }
+                continue;
+            }
+        };
+        match res {
+            Ok(ack) => in_flight.add(vec![(seq, change)], ack),
+            Err(e) => match e.downcast::<NetError>() {
+                Ok(e) => return Err(*e),
+                Err(e) => {
+                    warn!("Dropping {:?}: {}", change, e);
+                    if let Err(e) = queue.remove(seq, &change) {
+                        error!("Failed to update the change queue: {}", e);
+                        break;
+                    }
+                }
+            },
+        }
     }
+    batch.send(client, in_flight)
 }
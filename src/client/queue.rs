@@ -0,0 +1,243 @@
+//! Durable queue of local changes waiting to be sent to the server
+
+use crate::messaging::arguments::os_bytes;
+use serde::{Deserialize, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionResult, TransactionError, TransactionalTree},
+    Transactional, Tree,
+};
+use std::{
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Static name of the changes table
+static CHANGES: &str = "changes";
+/// Static name of the queued_paths table
+static QUEUED_PATHS: &str = "queued_paths";
+
+/// A change to the watched directory. Paths are relative to the watched directory, and are
+/// stored as raw bytes since they don't have to be valid UTF-8.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// A file was created or modified
+    Update(#[serde(with = "os_bytes")] PathBuf),
+    /// A file was removed
+    Delete(#[serde(with = "os_bytes")] PathBuf),
+    /// A file or directory was moved
+    Rename {
+        #[serde(with = "os_bytes")]
+        from: PathBuf,
+        #[serde(with = "os_bytes")]
+        to: PathBuf,
+    },
+}
+
+impl Change {
+    /// Key the change is queued under, queueing a change replaces the one with the same key
+    fn key(&self) -> Vec<u8> {
+        match self {
+            Change::Update(path) | Change::Delete(path) => path_key(path).to_vec(),
+            // Paths can't contain NUL bytes, so renames never replace the changes of a path
+            Change::Rename { from, to } => [path_key(from), b"\0", path_key(to)].concat(),
+        }
+    }
+}
+
+/// Ordered queue of changes that haven't been sent to the server yet.
+///
+/// The queue is stored on disk so changes made while the server is unreachable survive until the
+/// next connection, even across restarts. Only the latest change of each path is kept, and it
+/// moves to the back of the queue. Renames are kept in order along with the changes around them.
+pub struct ChangeQueue {
+    /// Needed to generate monotonic sequence numbers
+    db: sled::Db,
+    /// Queued changes keyed by their big endian sequence number, so iteration follows the
+    /// order the changes were made in
+    changes: Tree,
+    /// Sequence number of the queued change for each path
+    queued_paths: Tree,
+}
+
+impl ChangeQueue {
+    /// Open (or create) the queue stored at `path`
+    pub fn open(path: &Path) -> sled::Result<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn new_temporary() -> sled::Result<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: sled::Db) -> sled::Result<Self> {
+        Ok(ChangeQueue {
+            changes: db.open_tree(CHANGES)?,
+            queued_paths: db.open_tree(QUEUED_PATHS)?,
+            db,
+        })
+    }
+
+    /// Add a change to the back of the queue, replacing any queued change for the same path
+    pub fn push(&self, change: &Change) -> sled::Result<()> {
+        let seq = self.db.generate_id()?.to_be_bytes();
+        let key = change.key();
+        let value = bincode::serialize(change).expect("Couldn't serialize queued change");
+
+        (&self.changes, &self.queued_paths).transaction(
+            |(changes, queued_paths): &(TransactionalTree, TransactionalTree)|
+             -> ConflictableTransactionResult<(), sled::Error> {
+                if let Some(old_seq) = queued_paths.insert(key.as_slice(), &seq)? {
+                    changes.remove(old_seq)?;
+                }
+                changes.insert(&seq, value.as_slice())?;
+                Ok(())
+            },
+        )
+        .map_err(storage_error)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Iterate over the queued changes along with their sequence numbers, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = sled::Result<(u64, Change)>> {
+        self.changes.iter().map(|entry| {
+            let (seq, value) = entry?;
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&seq);
+            Ok((
+                u64::from_be_bytes(buf),
+                bincode::deserialize(&value).expect("Couldn't deserialize queued change"),
+            ))
+        })
+    }
+
+    /// Remove a change once it has been handled
+    pub fn remove(&self, seq: u64, change: &Change) -> sled::Result<()> {
+        let seq = seq.to_be_bytes();
+        let key = change.key();
+
+        (&self.changes, &self.queued_paths).transaction(
+            |(changes, queued_paths): &(TransactionalTree, TransactionalTree)|
+             -> ConflictableTransactionResult<(), sled::Error> {
+                changes.remove(&seq)?;
+                // The path might have been queued again in the meantime
+                if queued_paths.get(&key)?.as_deref() == Some(&seq[..]) {
+                    queued_paths.remove(key.as_slice())?;
+                }
+                Ok(())
+            },
+        )
+        .map_err(storage_error)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+}
+
+/// The transactions never abort, so every error is a storage error
+fn storage_error(e: TransactionError<sled::Error>) -> sled::Error {
+    match e {
+        TransactionError::Abort(e) | TransactionError::Storage(e) => e,
+    }
+}
+
+fn path_key(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn first(queue: &ChangeQueue) -> Option<(u64, Change)> {
+        queue.iter().next().transpose().unwrap()
+    }
+
+    #[test]
+    fn test_queue_order() {
+        let queue = ChangeQueue::new_temporary().unwrap();
+        queue.push(&Change::Update(PathBuf::from("a"))).unwrap();
+        queue.push(&Change::Update(PathBuf::from("b"))).unwrap();
+        queue.push(&Change::Delete(PathBuf::from("c"))).unwrap();
+
+        let mut drained = vec![];
+        while let Some((seq, change)) = first(&queue) {
+            queue.remove(seq, &change).unwrap();
+            drained.push(change);
+        }
+        assert_eq!(
+            drained,
+            vec![
+                Change::Update(PathBuf::from("a")),
+                Change::Update(PathBuf::from("b")),
+                Change::Delete(PathBuf::from("c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_queue_rename() {
+        let queue = ChangeQueue::new_temporary().unwrap();
+        let rename = Change::Rename {
+            from: PathBuf::from("a"),
+            to: PathBuf::from("b"),
+        };
+        queue.push(&Change::Update(PathBuf::from("a"))).unwrap();
+        queue.push(&rename).unwrap();
+        queue.push(&Change::Update(PathBuf::from("b"))).unwrap();
+        queue.push(&Change::Update(PathBuf::from("a"))).unwrap();
+
+        // Renames don't replace the changes of either path, and aren't replaced by them
+        let changes: Vec<_> = queue.iter().map(|x| x.unwrap().1).collect();
+        assert_eq!(
+            changes,
+            vec![
+                rename,
+                Change::Update(PathBuf::from("b")),
+                Change::Update(PathBuf::from("a")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_queue_non_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let queue = ChangeQueue::new_temporary().unwrap();
+        // Latin-1 "café"
+        let change = Change::Update(PathBuf::from(OsStr::from_bytes(b"caf\xe9")));
+        queue.push(&change).unwrap();
+        assert_eq!(first(&queue).map(|x| x.1), Some(change));
+    }
+
+    #[test]
+    fn test_queue_collapse() {
+        let queue = ChangeQueue::new_temporary().unwrap();
+        queue.push(&Change::Update(PathBuf::from("a"))).unwrap();
+        queue.push(&Change::Update(PathBuf::from("b"))).unwrap();
+        queue.push(&Change::Update(PathBuf::from("a"))).unwrap();
+        queue.push(&Change::Delete(PathBuf::from("a"))).unwrap();
+        assert_eq!(queue.len(), 2);
+
+        let (seq, change) = first(&queue).unwrap();
+        assert_eq!(change, Change::Update(PathBuf::from("b")));
+
+        // Queueing the path again before the old change is removed keeps the new change
+        queue.push(&Change::Update(PathBuf::from("b"))).unwrap();
+        queue.remove(seq, &change).unwrap();
+        assert_eq!(queue.len(), 2);
+
+        let (seq, change) = first(&queue).unwrap();
+        assert_eq!(change, Change::Delete(PathBuf::from("a")));
+        queue.remove(seq, &change).unwrap();
+        assert_eq!(
+            first(&queue).map(|x| x.1),
+            Some(Change::Update(PathBuf::from("b")))
+        );
+    }
+}
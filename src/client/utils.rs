@@ -1,11 +1,81 @@
-use super::{file_operations::CHUNK_SIZE, Blacklist};
-use crate::messaging::{arguments::{FileId, FileList, FileMetadata, QualifiedChunk}, error::MessageError};
+use super::{extended, file_operations::CHUNK_SIZE, Blacklist};
+use crate::{
+    config::SymlinkPolicy,
+    messaging::{
+        arguments::{
+            is_safe_path, Capabilities, EntryKind, FileId, FileList, FileMetadata, QualifiedChunk,
+        },
+        error::MessageError,
+    },
+};
 use std::{
-    fs::{self, File},
+    collections::HashSet,
+    fs::{self, File, Permissions},
     io::{self, Read, Seek, SeekFrom, Write},
-    path::Path,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, PermissionsExt},
+    },
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
 };
 
+/// How an entry of the watched directory is synchronized under the symlink policy
+pub enum SyncAs {
+    /// Files and directories, along with the links that are followed
+    Content,
+    /// Links that are preserved, with their target
+    Link(PathBuf),
+    /// Links that are left out
+    Skipped,
+}
+
+/// Decide how the entry at `path` below the watched directory `base` is synchronized
+pub fn sync_as(base: &Path, path: &Path, symlinks: SymlinkPolicy) -> io::Result<SyncAs> {
+    if !fs::symlink_metadata(path)?.is_symlink() {
+        return Ok(SyncAs::Content);
+    }
+    Ok(match symlinks {
+        SymlinkPolicy::Skip => SyncAs::Skipped,
+        // Dangling links have nothing to follow
+        SymlinkPolicy::Follow if path.exists() => SyncAs::Content,
+        SymlinkPolicy::Follow => SyncAs::Skipped,
+        SymlinkPolicy::Preserve => {
+            let target = fs::read_link(path)?;
+            if link_stays_inside(path.strip_prefix(base).unwrap(), &target) {
+                SyncAs::Link(target)
+            } else {
+                debug!(
+                    "Leaving out {:?}, it points outside the watched directory",
+                    path
+                );
+                SyncAs::Skipped
+            }
+        }
+    })
+}
+
+/// Whether `target` of the link at `link` (relative to the watched directory) stays inside it.
+///
+/// This only looks at the paths, so absolute targets count as outside, as they differ between
+/// clients anyway.
+pub fn link_stays_inside(link: &Path, target: &Path) -> bool {
+    let parent = link.parent().unwrap_or(Path::new(""));
+    let mut depth = 0usize;
+    for component in parent.components().chain(target.components()) {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(x) => depth = x,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
 /// Calculate chunk boundries and file hash
 fn chunk_file(path: &Path) -> Result<Vec<[u8; 32]>, io::Error> {
     let mut file = File::open(path)?;
@@ -17,7 +87,7 @@ fn chunk_file(path: &Path) -> Result<Vec<[u8; 32]>, io::Error> {
 
     file.seek(SeekFrom::Start(0))?;
 
-    for _ in 0..(size as f32 / CHUNK_SIZE as f32).ceil() as usize {
+    for _ in 0..size.div_ceil(CHUNK_SIZE as u64) {
         let mut buf = vec![0; CHUNK_SIZE];
         let len = file.read(&mut buf)?;
         hasher.update(&buf[..len]);
@@ -28,16 +98,118 @@ fn chunk_file(path: &Path) -> Result<Vec<[u8; 32]>, io::Error> {
     Ok(chunks)
 }
 
-/// Write a `QualifiedChunk` to it's specified file
+/// Create the directory described by `dir` below `base_path`, along with its parents
+pub fn create_dir(
+    base_path: &Path,
+    dir: &FileMetadata,
+    capabilities: Capabilities,
+) -> io::Result<()> {
+    fs::create_dir_all(base_path.join(&dir.file_id.path))?;
+    apply_metadata(base_path, dir, capabilities)
+}
+
+/// Apply the metadata of `entry` to its copy below `base_path`: the extended metadata in
+/// `capabilities`, the modification time and the permissions
+pub fn apply_metadata(
+    base_path: &Path,
+    entry: &FileMetadata,
+    capabilities: Capabilities,
+) -> io::Result<()> {
+    let path = resolve(&base_path.join(&entry.file_id.path))?;
+    // Changing the owner clears the setuid and setgid bits, so it comes before the permissions
+    extended::apply(&path, &entry.extended, capabilities)?;
+    let modified = UNIX_EPOCH + Duration::from_millis(entry.modified as u64);
+    // Before the permissions, which might not allow opening the file anymore
+    File::open(&path)?.set_modified(modified)?;
+    fs::set_permissions(&path, Permissions::from_mode(entry.permissions & 0o7777))
+}
+
+/// Create the symbolic link described by `link` below `base_path`, replacing a file or link
+/// that's in the way.
+///
+/// Links pointing outside of `base_path` are refused.
+pub fn create_symlink(base_path: &Path, link: &FileMetadata, target: &Path) -> io::Result<()> {
+    if !link_stays_inside(&link.file_id.path, target) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{:?} points outside the watched directory",
+                link.file_id.path
+            ),
+        ));
+    }
+    let path = base_path.join(&link.file_id.path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::symlink_metadata(&path) {
+        Ok(md) if !md.is_dir() => fs::remove_file(&path)?,
+        _ => {}
+    }
+    symlink(target, &path)
+}
+
+/// Make sure the entry at `path` below `base_path` can't be reached outside of it.
+///
+/// Fails when `path` isn't made of plain names. Unless links are followed, it also fails when one
+/// of the parent directories is a symbolic link. A link at `path` itself is the entry, not a way
+/// out.
+pub fn check_parents(base_path: &Path, path: &Path, symlinks: SymlinkPolicy) -> io::Result<()> {
+    if !is_safe_path(path) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{:?} leaves the watched directory", path),
+        ));
+    }
+    if symlinks == SymlinkPolicy::Follow {
+        return Ok(());
+    }
+    let mut full_path = base_path.to_owned();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        full_path.push(component);
+        if fs::symlink_metadata(&full_path).is_ok_and(|md| md.is_symlink()) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{:?} goes through a symbolic link", path),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Make sure writing the entry at `path` below `base_path` can't end up outside of it, see
+/// [`check_parents`].
+///
+/// Unless links are followed, a link at `path` itself is removed so it gets replaced instead of
+/// written through.
+pub fn prepare_write(base_path: &Path, path: &Path, symlinks: SymlinkPolicy) -> io::Result<()> {
+    check_parents(base_path, path, symlinks)?;
+    if symlinks == SymlinkPolicy::Follow {
+        return Ok(());
+    }
+    let full_path = base_path.join(path);
+    if fs::symlink_metadata(&full_path).is_ok_and(|md| md.is_symlink()) {
+        fs::remove_file(&full_path)?;
+    }
+    Ok(())
+}
+
+/// Write a `QualifiedChunk` to it's specified file, see [`prepare_write`].
+///
+/// Once the file is complete, its metadata is applied with the extended metadata in
+/// `capabilities`.
 pub fn write_chunk(
     blacklist: &mut Blacklist,
     base_path: &Path,
     chunk: &QualifiedChunk,
+    symlinks: SymlinkPolicy,
+    capabilities: Capabilities,
 ) -> Result<(), std::io::Error> {
+    prepare_write(base_path, &chunk.id.path.path, symlinks)?;
     let mut file = File::options()
         .write(true)
         .open(base_path.join(&chunk.id.path.path))?;
-    file.seek(SeekFrom::Start(chunk.id.offset as u64))?;
+    file.seek(SeekFrom::Start(chunk.id.offset))?;
     file.write_all(&chunk.data)?;
     if let Some(x) = blacklist.get(&chunk.id.path.path) {
         let hash = x.file_id.hash;
@@ -48,40 +220,257 @@ pub fn write_chunk(
             == hash
         {
             debug!("File download completed for {:?}", chunk.id.path.path);
-            blacklist.remove(&chunk.id.path.path);
+            if let Some(file_md) = blacklist.remove(&chunk.id.path.path) {
+                apply_metadata(base_path, &file_md, capabilities)?;
+            }
         }
     }
     Ok(())
 }
 
-/// Get the file metadata from a file at a given path.
-pub fn get_file_info(path: &Path) -> Result<FileMetadata, MessageError> {
+/// Get the file metadata from a file at a given path, with the extended metadata in
+/// `capabilities`.
+pub fn get_file_info(
+    path: &Path,
+    capabilities: Capabilities,
+) -> Result<FileMetadata, MessageError> {
     let md = fs::metadata(path)?;
     let file_id = FileId::new(path.to_owned())?;
-    let chunks = chunk_file(path)?;
-    Ok(FileMetadata::new(file_id, md, &chunks).unwrap())
+    let chunks = if md.is_dir() {
+        vec![]
+    } else {
+        chunk_file(path)?
+    };
+    let mut info = FileMetadata::new(file_id, md, &chunks).unwrap();
+    info.extended = extended::read(&resolve(path)?, capabilities)?;
+    Ok(info)
+}
+
+/// Resolve `path` when it's a link that's followed, so its target's metadata is used
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    if fs::symlink_metadata(path)?.is_symlink() {
+        fs::canonicalize(path)
+    } else {
+        Ok(path.to_owned())
+    }
+}
+
+/// Get the metadata of the entry at `path` below the watched directory `base`.
+///
+/// Returns `None` for links that are left out under `symlinks`. Links that are preserved carry no
+/// extended metadata.
+pub fn get_entry_info(
+    base: &Path,
+    path: &Path,
+    symlinks: SymlinkPolicy,
+    capabilities: Capabilities,
+) -> Result<Option<FileMetadata>, MessageError> {
+    match sync_as(base, path, symlinks)? {
+        SyncAs::Content => get_file_info(path, capabilities).map(Some),
+        SyncAs::Link(target) => {
+            let file_id = FileId::symlink(path.to_owned(), &target);
+            let mut info = FileMetadata::new(file_id, fs::symlink_metadata(path)?, &[])?;
+            info.kind = EntryKind::Symlink(target);
+            Ok(Some(info))
+        }
+        SyncAs::Skipped => Ok(None),
+    }
 }
 
-/// Generate a file listing of the watched directory.
+/// Name of the directory holding the client state of a watched directory.
 ///
-/// This will be used to preform an initial synchronization when the clients connect.
-pub fn generate_file_list(path: &Path) -> Result<FileList, MessageError> {
-    Ok(FileList(recursive_file_list(path, path)?))
+/// Every watched directory gets its own state, named after the hash of its canonical path.
+pub fn state_dir_name(watch_path: &Path) -> String {
+    let hash = blake3::hash(watch_path.as_os_str().as_bytes());
+    base16ct::lower::encode_string(hash.as_bytes())
 }
 
-fn recursive_file_list(base: &Path, path: &Path) -> Result<Vec<FileId>, MessageError> {
-    let mut files: Vec<FileId> = vec![];
+/// Generate a file listing of `path`, a directory below the watched directory `base`.
+///
+/// This will be used to preform an initial synchronization when the clients connect. Paths are
+/// relative to `base`.
+pub fn generate_file_list(
+    base: &Path,
+    path: &Path,
+    symlinks: SymlinkPolicy,
+) -> Result<FileList, MessageError> {
+    let mut files = vec![];
+    let mut ancestors = HashSet::from([path.canonicalize()?]);
+    recursive_file_list(base, path, symlinks, &mut ancestors, &mut files)?;
+    Ok(FileList(files))
+}
 
+fn recursive_file_list(
+    base: &Path,
+    path: &Path,
+    symlinks: SymlinkPolicy,
+    ancestors: &mut HashSet<PathBuf>,
+    files: &mut Vec<FileId>,
+) -> Result<(), MessageError> {
     for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            files.append(&mut recursive_file_list(base, &path)?);
-        } else {
-            let mut file_info = FileId::new(path)?;
-            file_info.path = file_info.path.strip_prefix(base).unwrap().to_owned();
-            files.push(file_info);
+        let path = entry?.path();
+        let relative = path.strip_prefix(base).unwrap().to_owned();
+        match sync_as(base, &path, symlinks)? {
+            SyncAs::Content => {
+                // Directories are listed too, so empty ones are synchronized
+                let mut file_info = FileId::new(path.clone())?;
+                file_info.path = relative;
+                files.push(file_info);
+                if !path.is_dir() {
+                    continue;
+                }
+                // Followed links can point back up the tree
+                let canonical = path.canonicalize()?;
+                if ancestors.insert(canonical.clone()) {
+                    recursive_file_list(base, &path, symlinks, ancestors, files)?;
+                    ancestors.remove(&canonical);
+                } else {
+                    debug!("Not following {:?} again, it loops back", path);
+                }
+            }
+            SyncAs::Link(target) => files.push(FileId::symlink(relative, &target)),
+            SyncAs::Skipped => {}
         }
     }
-    Ok(files)
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::arguments::{ChunkId, QualifiedChunkId};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_link_stays_inside() {
+        assert!(link_stays_inside(Path::new("link"), Path::new("file")));
+        assert!(link_stays_inside(
+            Path::new("a/b/link"),
+            Path::new("../../c/./d")
+        ));
+        assert!(link_stays_inside(Path::new("link"), Path::new(".")));
+        assert!(!link_stays_inside(Path::new("a/link"), Path::new("../..")));
+        assert!(!link_stays_inside(
+            Path::new("a/link"),
+            Path::new("b/../../../c")
+        ));
+        assert!(!link_stays_inside(
+            Path::new("link"),
+            Path::new("/etc/passwd")
+        ));
+    }
+
+    #[test]
+    fn test_prepare_write() {
+        let tmp = std::env::temp_dir().join(format!("phoenix-write-{}", std::process::id()));
+        let base = tmp.join("watch");
+        let _ = fs::remove_dir_all(&tmp);
+        fs::create_dir_all(&base).unwrap();
+        symlink(&tmp, base.join("up")).unwrap();
+        symlink("file", base.join("link")).unwrap();
+
+        let denied = |path: &str, symlinks| {
+            prepare_write(&base, Path::new(path), symlinks)
+                .unwrap_err()
+                .kind()
+                == io::ErrorKind::PermissionDenied
+        };
+        for symlinks in [SymlinkPolicy::Preserve, SymlinkPolicy::Follow] {
+            assert!(denied("../file", symlinks));
+            assert!(denied("/etc/passwd", symlinks));
+        }
+        assert!(denied("up/file", SymlinkPolicy::Preserve));
+        prepare_write(&base, Path::new("up/file"), SymlinkPolicy::Follow).unwrap();
+        // Checking the parents leaves the entry itself alone
+        let checked = check_parents(&base, Path::new("up/file"), SymlinkPolicy::Preserve);
+        assert_eq!(checked.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        check_parents(&base, Path::new("link"), SymlinkPolicy::Preserve).unwrap();
+        assert!(fs::symlink_metadata(base.join("link")).is_ok());
+        // Links are replaced rather than written through
+        prepare_write(&base, Path::new("link"), SymlinkPolicy::Preserve).unwrap();
+        assert!(fs::symlink_metadata(base.join("link")).is_err());
+
+        // Chunks are checked the same way
+        let chunk = QualifiedChunk {
+            id: QualifiedChunkId {
+                path: FileId {
+                    path: PathBuf::from("../file"),
+                    hash: [0u8; 32],
+                },
+                offset: 0,
+                id: ChunkId(vec![]),
+            },
+            data: vec![1],
+        };
+        fs::write(tmp.join("file"), b"").unwrap();
+        let res = write_chunk(
+            &mut Blacklist::new(),
+            &base,
+            &chunk,
+            SymlinkPolicy::Follow,
+            Capabilities::default(),
+        );
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs::read(tmp.join("file")).unwrap(), b"");
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn test_apply_metadata() {
+        let base = std::env::temp_dir().join(format!("phoenix-metadata-{}", std::process::id()));
+        fs::create_dir_all(&base).unwrap();
+        fs::write(base.join("script"), b"#!/bin/sh").unwrap();
+
+        let mut file_md = get_file_info(&base.join("script"), Capabilities::default()).unwrap();
+        file_md.file_id.path = PathBuf::from("script");
+        file_md.permissions = 0o100555;
+        file_md.modified = 1_600_000_000_123;
+        apply_metadata(&base, &file_md, Capabilities::default()).unwrap();
+
+        let applied = get_file_info(&base.join("script"), Capabilities::default()).unwrap();
+        assert_eq!(applied.permissions, 0o100555);
+        assert_eq!(applied.modified, 1_600_000_000_123);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_file_list_symlinks() {
+        let tmp = std::env::temp_dir().join(format!("phoenix-links-{}", std::process::id()));
+        let base = tmp.join("watch");
+        let _ = fs::remove_dir_all(&tmp);
+        fs::create_dir_all(base.join("dir")).unwrap();
+        fs::create_dir_all(tmp.join("outside")).unwrap();
+        fs::write(base.join("dir/file"), b"data").unwrap();
+        fs::write(tmp.join("outside/file"), b"data").unwrap();
+        symlink("..", base.join("dir/up")).unwrap();
+        symlink(tmp.join("outside"), base.join("out")).unwrap();
+
+        let list = |symlinks| {
+            let mut paths: Vec<PathBuf> = generate_file_list(&base, &base, symlinks)
+                .unwrap()
+                .0
+                .into_iter()
+                .map(|file| file.path)
+                .collect();
+            paths.sort();
+            paths
+        };
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        // Links pointing outside are left out
+        assert_eq!(
+            list(SymlinkPolicy::Preserve),
+            paths(&["dir", "dir/file", "dir/up"])
+        );
+        assert_eq!(list(SymlinkPolicy::Skip), paths(&["dir", "dir/file"]));
+        // The loop back up the tree is only entered once
+        let followed = list(SymlinkPolicy::Follow);
+        assert!(followed.contains(&PathBuf::from("dir/up")));
+        assert!(!followed.contains(&PathBuf::from("dir/up/dir")));
+        assert!(followed.contains(&PathBuf::from("out/file")));
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
 }
@@ -1,8 +1,10 @@
 //! This module provides the configuration file structure for both the client and the server.
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::{
-    env, fs,
+    env,
+    fmt::Display,
+    fs,
     path::{Path, PathBuf},
 };
 
@@ -21,13 +23,81 @@ pub trait Config: Serialize {
     }
 }
 
+/// An address the server listens on for connections.
+///
+/// Endpoints are written as strings in the config file. Unix domain sockets are prefixed with
+/// `unix:` (`unix:/run/phoenix.sock`), anything else is treated as a TCP address
+/// (`0.0.0.0:8080`, `[::]:8080`, `localhost:8080`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum Endpoint {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl From<String> for Endpoint {
+    fn from(s: String) -> Self {
+        match s.strip_prefix("unix:") {
+            Some(path) => Endpoint::Unix(PathBuf::from(path)),
+            None => Endpoint::Tcp(s),
+        }
+    }
+}
+
+impl From<Endpoint> for String {
+    fn from(endpoint: Endpoint) -> Self {
+        endpoint.to_string()
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Accept either a single endpoint or a list of endpoints.
+///
+/// This keeps configs written when `bind_address` only took a single address working.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<Endpoint>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Endpoint),
+        Many(Vec<Endpoint>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(endpoint) => vec![endpoint],
+        OneOrMany::Many(endpoints) => endpoints,
+    })
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ServerConfig {
-    pub bind_address: String,
+    /// Endpoints to accept connections on. Every endpoint shares the same storage.
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind_address: Vec<Endpoint>,
     pub privkey: String,
     #[serde(default = "get_server_storage_path")]
     pub storage_path: PathBuf,
     pub clients: Vec<String>,
+    /// Seconds between heartbeats sent to idle clients
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    /// Seconds without hearing from a client before its session is torn down
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Bandwidth limit of each client connection in KiB/s, applied to each direction. Reloaded
+    /// on SIGHUP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_limit: Option<u64>,
 }
 
 impl Config for ServerConfig {
@@ -39,10 +109,13 @@ impl Config for ServerConfig {
         } else {
             warn!("Config file doesn't exist. Using defaults.");
             let config = ServerConfig {
-                bind_address: "127.0.0.1:8080".to_string(),
+                bind_address: vec![Endpoint::Tcp("127.0.0.1:8080".to_string())],
                 privkey: String::new(),
                 storage_path: get_server_storage_path(),
                 clients: vec![],
+                heartbeat_interval: default_heartbeat_interval(),
+                idle_timeout: default_idle_timeout(),
+                client_limit: None,
             };
             Ok(config)
         }
@@ -59,7 +132,29 @@ impl Config for ServerConfig {
     }
 }
 
+/// Convert a bandwidth limit from the config (KiB/s) to bytes per second
+pub fn limit_bytes(limit: Option<u64>) -> Option<u64> {
+    limit.map(|kib| kib.saturating_mul(1024))
+}
+
+fn default_heartbeat_interval() -> u64 {
+    15
+}
+
+fn default_idle_timeout() -> u64 {
+    45
+}
+
 fn get_server_storage_path() -> PathBuf {
+    get_data_home().join("phoenix")
+}
+
+fn get_client_state_path() -> PathBuf {
+    get_data_home().join("phoenix-client")
+}
+
+/// Base directory for persistent data (`XDG_DATA_HOME` or `~/.local/share`)
+fn get_data_home() -> PathBuf {
     let mut base_path = PathBuf::new();
     if let Ok(var) = env::var("XDG_DATA_HOME") {
         base_path = PathBuf::from(var);
@@ -72,14 +167,55 @@ fn get_server_storage_path() -> PathBuf {
         }
     }
     debug!("Base path: {:?}", base_path);
-    base_path.join("phoenix")
+    base_path
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ClientConfig {
     pub privkey: String,
+    /// Address of the server. Same format as the server's
+    /// [`Endpoint`](enum.Endpoint.html)s.
     pub server_address: String,
     pub server_pubkey: String,
+    /// Command to spawn instead of connecting to `server_address`.
+    ///
+    /// The protocol is spoken over the command's stdin/stdout, e.g.
+    /// `ssh host phoenix run --server --stdio`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_command: Option<String>,
+    /// Directory holding the client's local state, such as the queue of changes that still have
+    /// to be sent to the server
+    #[serde(default = "get_client_state_path")]
+    pub state_path: PathBuf,
+    /// Seconds between heartbeats sent to the server
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    /// Seconds without hearing from the server before reconnecting
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Upload bandwidth limit in KiB/s. Reloaded on SIGHUP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_limit: Option<u64>,
+    /// Download bandwidth limit in KiB/s. Reloaded on SIGHUP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_limit: Option<u64>,
+    /// How symbolic links in the watched directory are synchronized
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+}
+
+/// How the client treats symbolic links in the watched directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Synchronize links as links, so other clients recreate them. Links pointing outside the
+    /// watched directory are left out.
+    #[default]
+    Preserve,
+    /// Synchronize what links point to as if it was in the watched directory
+    Follow,
+    /// Leave links out
+    Skip,
 }
 
 impl Config for ClientConfig {
@@ -94,6 +230,13 @@ impl Config for ClientConfig {
                 privkey: String::new(),
                 server_address: "127.0.0.1:8080".to_string(),
                 server_pubkey: String::new(),
+                server_command: None,
+                state_path: get_client_state_path(),
+                heartbeat_interval: default_heartbeat_interval(),
+                idle_timeout: default_idle_timeout(),
+                upload_limit: None,
+                download_limit: None,
+                symlinks: SymlinkPolicy::default(),
             };
             Ok(config)
         }
@@ -36,6 +36,9 @@ enum Command {
     Run {
         #[clap(long, action)]
         server: bool,
+        /// Serve a single connection over stdin/stdout (for tunneling through ssh)
+        #[clap(long, action, requires = "server")]
+        stdio: bool,
         #[clap(value_parser)]
         file_path: Option<PathBuf>,
     },
@@ -68,9 +71,13 @@ async fn main() {
     let config_file = find_config(cli.config);
 
     match cli.command {
-        Command::Run { server, file_path } => {
+        Command::Run {
+            server,
+            stdio,
+            file_path,
+        } => {
             if server {
-                server::start_server(&config_file).await;
+                server::start_server(&config_file, stdio).await;
             } else if let Some(arg) = file_path {
                 start_client(&config_file, &arg).await;
             }
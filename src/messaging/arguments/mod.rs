@@ -6,44 +6,228 @@ use base64ct::{Base64, Encoding};
 use core::fmt::Debug;
 use serde::{Deserialize, Serialize};
 use std::{
-    any::Any,
+    ffi::{OsStr, OsString},
     fmt::{Display, Write},
-    fs::{File, Metadata},
+    fs::{self, File, Metadata},
     hash::Hash,
     io,
-    os::unix::prelude::PermissionsExt,
-    path::{PathBuf, Path},
+    os::unix::{ffi::OsStrExt, prelude::PermissionsExt},
+    path::{Component, Path, PathBuf},
     time, vec,
 };
 
 use super::error::MessageError;
+use crate::client::CHUNK_SIZE;
 
 pub trait Argument: Debug + Send {
     fn to_bin(&self) -> Vec<u8>;
     fn from_bin(data: &[u8]) -> Result<Self, MessageError>
     where
         Self: Sized;
-    fn as_any(&self) -> &dyn Any;
 }
 
+/// Serde helpers storing paths and file names as their raw bytes.
+///
+/// File names on Linux are arbitrary bytes, which serde's own implementation refuses. Bincode
+/// encodes bytes the same way as strings, so data stored before still reads back.
+pub mod os_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::{
+        ffi::{OsStr, OsString},
+        os::unix::ffi::{OsStrExt, OsStringExt},
+    };
+
+    pub fn serialize<P, S>(path: &P, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        P: AsRef<OsStr>,
+        S: Serializer,
+    {
+        serializer.serialize_bytes(path.as_ref().as_bytes())
+    }
+
+    pub fn deserialize<'de, P, D>(deserializer: D) -> Result<P, D::Error>
+    where
+        P: From<OsString>,
+        D: Deserializer<'de>,
+    {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        Ok(OsString::from_vec(bytes).into())
+    }
+}
+
+/// Bounds checked reader over the binary form of an argument.
+///
+/// Reading past the end returns `MessageError::Truncated` instead of panicking, so arguments
+/// received from peers can be decoded safely.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    /// Take the next `len` bytes
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MessageError> {
+        if len > self.data.len() {
+            return Err(MessageError::Truncated {
+                needed: len,
+                available: self.data.len(),
+            });
+        }
+        let (field, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(field)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], MessageError> {
+        let mut buf = [0u8; N];
+        buf.copy_from_slice(self.take(N)?);
+        Ok(buf)
+    }
+
+    /// Take a field prefixed with its length as a big endian `u32`
+    fn take_u32_prefixed(&mut self) -> Result<&'a [u8], MessageError> {
+        let len = u32::from_be_bytes(self.array()?);
+        self.take(usize::try_from(len).unwrap_or(usize::MAX))
+    }
+
+    /// Take a field prefixed with its length as a big endian `u64`
+    fn take_u64_prefixed(&mut self) -> Result<&'a [u8], MessageError> {
+        let len = u64::from_be_bytes(self.array()?);
+        self.take(usize::try_from(len).unwrap_or(usize::MAX))
+    }
+
+    /// Everything that's left
+    fn rest(self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// Append a field prefixed with its length as a big endian `u32`
+fn put_u32_prefixed(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    buf.extend_from_slice(field);
+}
+
+/// Check that a fixed size argument has exactly `N` bytes
+fn exact<const N: usize>(data: &[u8]) -> Result<[u8; N], MessageError> {
+    data.try_into().map_err(|_| MessageError::BadLength {
+        expected: N,
+        actual: data.len(),
+    })
+}
+
+/// Set of optional protocol features, one bit per feature
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// Chunk offsets are 64 bits wide and `FileMetadata` carries the file size. Without it,
+    /// offsets only have 32 bits and the size is unknown (`0`).
+    pub const LARGE_OFFSETS: Capabilities = Capabilities(0x01);
+    /// Files and directories can be moved with `RenameFile`
+    pub const RENAME: Capabilities = Capabilities(0x02);
+    /// `FileMetadata` carries the entry kind, so directories are synchronized. Without it, every
+    /// entry is a file.
+    pub const ENTRY_KINDS: Capabilities = Capabilities(0x04);
+    /// Symbolic links are synchronized with their target. Needs `ENTRY_KINDS`.
+    pub const SYMLINKS: Capabilities = Capabilities(0x08);
+    /// `FileMetadata` carries `user.*` extended attributes and SELinux labels
+    pub const XATTRS: Capabilities = Capabilities(0x10);
+    /// `FileMetadata` carries POSIX ACLs
+    pub const ACLS: Capabilities = Capabilities(0x20);
+    /// `FileMetadata` carries the owner and group, by name
+    pub const OWNERSHIP: Capabilities = Capabilities(0x40);
+    /// Every part of the extended metadata
+    pub const EXTENDED: Capabilities =
+        Capabilities(Self::XATTRS.0 | Self::ACLS.0 | Self::OWNERSHIP.0);
+    /// Chunks can be requested with `RequestChunks` and files sent with `SendFileBatch`
+    pub const BATCH: Capabilities = Capabilities(0x80);
+    /// Messages can be compressed with zstd. See the [`net`](../../net/index.html#compression)
+    /// module.
+    pub const COMPRESSION: Capabilities = Capabilities(0x100);
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether any of the features in `other` is supported
+    pub fn intersects(&self, other: Capabilities) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Whether entries of `kind` can be synchronized with a peer supporting these features
+    pub fn carries(&self, kind: &EntryKind) -> bool {
+        match kind {
+            EntryKind::File => true,
+            EntryKind::Directory => self.contains(Capabilities::ENTRY_KINDS),
+            EntryKind::Symlink(_) => {
+                self.contains(Capabilities::ENTRY_KINDS) && self.contains(Capabilities::SYMLINKS)
+            }
+        }
+    }
+
+    /// Whether the chunks of `entry` can be addressed by a peer supporting these features. Without
+    /// `LARGE_OFFSETS`, offsets only have 32 bits, so files past 4 GiB can't be synchronized.
+    pub fn fits(&self, entry: &FileMetadata) -> bool {
+        if self.contains(Capabilities::LARGE_OFFSETS) {
+            return true;
+        }
+        // Peers without `LARGE_OFFSETS` don't send the size, so the chunks have to be counted too
+        let last_offset = entry.chunks.len().saturating_sub(1) as u64 * CHUNK_SIZE as u64;
+        entry.size <= u32::MAX as u64 && last_offset <= u32::MAX as u64
+    }
+
+    /// Features supported by both sets
+    pub fn intersection(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Announced by both sides right after the handshake.
+///
+/// Binary layout: `<version:u8> <min_version:u8> <capabilities:u32>`. Peers that only send the
+/// version byte are taken to accept nothing older than their own version and to support no
+/// optional features.
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Version(pub u8);
+pub struct Version {
+    /// Protocol version spoken by the sender
+    pub version: u8,
+    /// Oldest protocol version the sender accepts from its peer
+    pub min_version: u8,
+    pub capabilities: Capabilities,
+}
 
 impl Argument for Version {
     fn to_bin(&self) -> Vec<u8> {
-        self.0.to_be_bytes().to_vec()
+        let mut buf = vec![self.version, self.min_version];
+        buf.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        buf
     }
 
     fn from_bin(ver: &[u8]) -> Result<Self, MessageError> {
-        Ok(Version(ver[0]))
-    }
-    fn as_any(&self) -> &dyn Any {
-        self
+        if let [version] = *ver {
+            return Ok(Version {
+                version,
+                min_version: version,
+                capabilities: Capabilities::default(),
+            });
+        }
+        let [version, min_version, c0, c1, c2, c3] = exact::<6>(ver)?;
+        Ok(Version {
+            version,
+            min_version,
+            capabilities: Capabilities(u32::from_be_bytes([c0, c1, c2, c3])),
+        })
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 pub struct FileId {
+    /// Raw path, which doesn't have to be valid UTF-8
+    #[serde(with = "os_bytes")]
     pub path: PathBuf,
     pub hash: [u8; 32],
 }
@@ -58,7 +242,15 @@ impl Clone for FileId {
 }
 
 impl FileId {
+    /// Hash the file at `path`, following symbolic links. Directories have no content, so their
+    /// hash is all zeros.
     pub fn new(path: PathBuf) -> Result<Self, MessageError> {
+        if fs::metadata(&path)?.is_dir() {
+            return Ok(FileId {
+                path,
+                hash: [0u8; 32],
+            });
+        }
         let mut file = File::open(&path)?;
         let mut hasher = blake3::Hasher::new();
         io::copy(&mut file, &mut hasher)?;
@@ -68,59 +260,96 @@ impl FileId {
             hash: hash.into(),
         })
     }
+
+    /// Id of a symbolic link at `path`, which is the hash of its target
+    pub fn symlink(path: PathBuf, target: &Path) -> Self {
+        FileId {
+            path,
+            hash: blake3::hash(target.as_os_str().as_bytes()).into(),
+        }
+    }
 }
 
 impl Argument for FileId {
     fn to_bin(&self) -> Vec<u8> {
-        let mut x = match self.path.to_str() {
-            Some(s) => s.as_bytes().to_vec(),
-            None => vec![],
-        };
+        let mut x = self.path.as_os_str().as_bytes().to_vec();
         x.extend_from_slice(&self.hash);
         x
     }
 
     fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
-        if data.len() < 32 {
-            return Err(MessageError::InvalidBin);
-        }
-        let path = match String::from_utf8(data[..data.len() - 32].to_vec()) {
-            Ok(x) => Ok(PathBuf::from(x)),
-            Err(_) => Err(MessageError::InvalidBin),
-        }?;
-
-        let mut xdata = [0u8; 32];
-
-        xdata.copy_from_slice(&data[data.len() - 32..]);
-
-        Ok(FileId { path, hash: xdata })
-    }
-
-    fn as_any(&self) -> &dyn Any {
-        self
+        // The hash is at the end, so the path is whatever comes before it
+        let path_len = data.len().checked_sub(32).ok_or(MessageError::Truncated {
+            needed: 32,
+            available: data.len(),
+        })?;
+        let mut reader = Reader::new(data);
+        let path = PathBuf::from(OsStr::from_bytes(reader.take(path_len)?));
+        let hash = reader.array::<32>()?;
+
+        Ok(FileId { path, hash })
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct FilePath(pub String);
+/// Raw path of a file, which doesn't have to be valid UTF-8
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FilePath(pub PathBuf);
 
 impl Argument for FilePath {
     fn to_bin(&self) -> Vec<u8> {
-        self.0.as_bytes().to_vec()
+        self.0.as_os_str().as_bytes().to_vec()
     }
 
     fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
-        Ok(Self(String::from_utf8(data.to_vec())?))
-    }
-
-    fn as_any(&self) -> &dyn Any {
-        self
+        Ok(Self(PathBuf::from(OsStr::from_bytes(data))))
     }
 }
 
 impl FilePath {
     pub fn new(path: &Path) -> Self {
-        FilePath(path.to_str().unwrap().to_owned())
+        FilePath(path.to_owned())
+    }
+}
+
+/// Whether `path` is made of plain names only, so joining it to a directory can't leave it.
+///
+/// Paths received from a peer have to pass this before they're used. Empty and absolute paths,
+/// `.` and `..` are refused.
+pub fn is_safe_path(path: &Path) -> bool {
+    let mut components = path.components().peekable();
+    components.peek().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Move of the file at `from`, or of every file below it when it's a directory, to `to`.
+///
+/// Binary layout: `<from_len:u32> <from> <to>`, with both paths as raw bytes.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl Argument for Rename {
+    fn to_bin(&self) -> Vec<u8> {
+        let from = self.from.as_os_str().as_bytes();
+        let mut buf = (from.len() as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(from);
+        buf.extend_from_slice(self.to.as_os_str().as_bytes());
+        buf
+    }
+
+    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(data);
+        let from = reader.take_u32_prefixed()?;
+        let to = reader.rest();
+        if from.is_empty() || to.is_empty() {
+            return Err(MessageError::EmptyPath);
+        }
+        Ok(Rename {
+            from: PathBuf::from(OsStr::from_bytes(from)),
+            to: PathBuf::from(OsStr::from_bytes(to)),
+        })
     }
 }
 
@@ -135,10 +364,6 @@ impl Argument for ChunkId {
     fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
         Ok(Self(data.to_vec()))
     }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -151,12 +376,16 @@ pub struct QualifiedChunkId {
     /// The offset of the beginning of the chunk's location in the file.
     ///
     /// For example: If it's the first chunk in the file, it's offset should be `0`.
-    pub offset: u32,
+    pub offset: u64,
     pub id: ChunkId,
 }
 
-impl Argument for QualifiedChunkId {
-    fn to_bin(&self) -> Vec<u8> {
+impl QualifiedChunkId {
+    /// Binary form for a peer supporting `capabilities`. The offset only takes 32 bits without
+    /// [`LARGE_OFFSETS`](struct.Capabilities.html#associatedconstant.LARGE_OFFSETS), so chunks
+    /// past 4 GiB can't be addressed and fail with
+    /// [`OffsetTooLarge`](../error/enum.MessageError.html#variant.OffsetTooLarge).
+    pub fn to_bin_for(&self, capabilities: Capabilities) -> Result<Vec<u8>, MessageError> {
         let mut buf: Vec<u8> = vec![];
         let path_bytes = self.path.to_bin();
         // Add path length
@@ -164,40 +393,283 @@ impl Argument for QualifiedChunkId {
         // Add path
         buf.extend_from_slice(&path_bytes);
         // Add chunk offset
-        buf.extend_from_slice(&self.offset.to_be_bytes());
+        if capabilities.contains(Capabilities::LARGE_OFFSETS) {
+            buf.extend_from_slice(&self.offset.to_be_bytes());
+        } else {
+            let offset = u32::try_from(self.offset)
+                .map_err(|_| MessageError::OffsetTooLarge(self.offset))?;
+            buf.extend_from_slice(&offset.to_be_bytes());
+        }
         // Add the rest of the ChunkId
         buf.extend_from_slice(&self.id.to_bin());
-        buf
+        Ok(buf)
     }
 
-    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
-        let mut buf = [0u8; 4];
-        // Get size of path string
-        buf.copy_from_slice(&data[..4]);
-        let str_size: usize = u32::from_be_bytes(buf) as usize;
+    /// Decode the binary form sent by a peer supporting `capabilities`
+    pub fn from_bin_for(data: &[u8], capabilities: Capabilities) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(data);
         // Get path
-        let path = FileId::from_bin(&data[4..4 + str_size])?;
+        let path = FileId::from_bin(reader.take_u32_prefixed()?)?;
         // Get the chunk offset
-        buf.copy_from_slice(&data[4 + str_size..8 + str_size]);
-        let offset = u32::from_be_bytes(buf);
+        let offset = if capabilities.contains(Capabilities::LARGE_OFFSETS) {
+            u64::from_be_bytes(reader.array()?)
+        } else {
+            u32::from_be_bytes(reader.array()?).into()
+        };
         // Get the ChunkId
-        let id = ChunkId::from_bin(&data[8 + str_size..])?;
+        let id = ChunkId::from_bin(reader.rest())?;
         Ok(QualifiedChunkId { path, offset, id })
     }
+}
 
-    fn as_any(&self) -> &dyn Any {
-        self
+impl Argument for QualifiedChunkId {
+    fn to_bin(&self) -> Vec<u8> {
+        // Every offset fits with `LARGE_OFFSETS`
+        self.to_bin_for(super::CAPABILITIES)
+            .expect("64 bit offsets can't overflow")
+    }
+
+    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
+        Self::from_bin_for(data, super::CAPABILITIES)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileMetadata {
     pub file_id: FileId,
-    pub file_name: String,
+    #[serde(with = "os_bytes")]
+    pub file_name: OsString,
     pub permissions: u32,
     pub modified: u128,
     pub created: u128,
+    /// Size of the file in bytes
+    pub size: u64,
     pub chunks: Vec<ChunkId>,
+    /// Stored after the other fields, so records from before entry kinds are completed by
+    /// appending it
+    pub kind: EntryKind,
+    /// Stored last, for the same reason as `kind`
+    pub extended: ExtendedMetadata,
+}
+
+/// Type of a synchronized entry
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum EntryKind {
+    File,
+    /// Directories have no chunks. They're synchronized so empty ones exist everywhere too.
+    Directory,
+    /// Symbolic link pointing at the contained target, which is kept as written
+    Symlink(#[serde(with = "os_bytes")] PathBuf),
+}
+
+impl EntryKind {
+    /// Binary layout: `<kind:u8>`, followed by `<target_len:u32> <target>` for symbolic links
+    fn to_bin(&self, buf: &mut Vec<u8>) {
+        match self {
+            EntryKind::File => buf.push(0),
+            EntryKind::Directory => buf.push(1),
+            EntryKind::Symlink(target) => {
+                let target = target.as_os_str().as_bytes();
+                buf.push(2);
+                buf.extend_from_slice(&(target.len() as u32).to_be_bytes());
+                buf.extend_from_slice(target);
+            }
+        }
+    }
+
+    fn from_bin(reader: &mut Reader) -> Result<Self, MessageError> {
+        let [kind] = reader.array()?;
+        match kind {
+            0 => Ok(EntryKind::File),
+            1 => Ok(EntryKind::Directory),
+            2 => {
+                let target = reader.take_u32_prefixed()?;
+                if target.is_empty() {
+                    return Err(MessageError::EmptyPath);
+                }
+                Ok(EntryKind::Symlink(PathBuf::from(OsStr::from_bytes(target))))
+            }
+            x => Err(MessageError::UnknownEntryKind(x)),
+        }
+    }
+}
+
+/// Metadata beyond the permission bits. Each part is only carried when both sides support its
+/// capability, and is empty otherwise.
+///
+/// Binary layout: `<has_owner:u8> [<user> <group>] <xattr_count:u32> (<name> <value>)*
+/// <acl_count:u32> <acl entries> <default_acl_count:u32> <acl entries>`, where every string and
+/// value is prefixed with its length as a `u32`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct ExtendedMetadata {
+    /// Owner and group, see [`Capabilities::OWNERSHIP`](struct.Capabilities.html)
+    pub ownership: Option<Ownership>,
+    /// Sorted by name, see [`Capabilities::XATTRS`](struct.Capabilities.html)
+    pub xattrs: Vec<Xattr>,
+    /// Access ACL, empty when the permission bits say everything. See
+    /// [`Capabilities::ACLS`](struct.Capabilities.html).
+    pub acl: Vec<AclEntry>,
+    /// ACL inherited by entries created in a directory
+    pub default_acl: Vec<AclEntry>,
+}
+
+/// Owner and group of an entry.
+///
+/// Ids differ between machines, so these are names. Ids without a name are written as the number.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Ownership {
+    pub user: String,
+    pub group: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Xattr {
+    #[serde(with = "os_bytes")]
+    pub name: OsString,
+    pub value: Vec<u8>,
+}
+
+/// Entry of a POSIX ACL.
+///
+/// Binary layout: `<tag:u8> <perm:u8>`, followed by the name for `User` and `Group` entries.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    /// Read, write and execute bits, as in the permission bits
+    pub perm: u8,
+}
+
+/// Who an [`AclEntry`](struct.AclEntry.html) applies to. Named users and groups are mapped by
+/// name, like [`Ownership`](struct.Ownership.html).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum AclTag {
+    UserObj,
+    User(String),
+    GroupObj,
+    Group(String),
+    Mask,
+    Other,
+}
+
+impl ExtendedMetadata {
+    /// Drop the parts a peer without `capabilities` can't carry
+    pub fn restrict(&mut self, capabilities: Capabilities) {
+        if !capabilities.contains(Capabilities::OWNERSHIP) {
+            self.ownership = None;
+        }
+        if !capabilities.contains(Capabilities::XATTRS) {
+            self.xattrs.clear();
+        }
+        if !capabilities.contains(Capabilities::ACLS) {
+            self.acl.clear();
+            self.default_acl.clear();
+        }
+    }
+
+    /// Take the parts a peer without `capabilities` couldn't send from `stored`, so they aren't
+    /// lost when it updates an entry
+    pub fn keep_unsupported(&mut self, stored: &ExtendedMetadata, capabilities: Capabilities) {
+        if !capabilities.contains(Capabilities::OWNERSHIP) {
+            self.ownership = stored.ownership.clone();
+        }
+        if !capabilities.contains(Capabilities::XATTRS) {
+            self.xattrs = stored.xattrs.clone();
+        }
+        if !capabilities.contains(Capabilities::ACLS) {
+            self.acl = stored.acl.clone();
+            self.default_acl = stored.default_acl.clone();
+        }
+    }
+
+    fn to_bin(&self, buf: &mut Vec<u8>) {
+        match &self.ownership {
+            Some(ownership) => {
+                buf.push(1);
+                put_u32_prefixed(buf, ownership.user.as_bytes());
+                put_u32_prefixed(buf, ownership.group.as_bytes());
+            }
+            None => buf.push(0),
+        }
+        buf.extend_from_slice(&(self.xattrs.len() as u32).to_be_bytes());
+        for xattr in &self.xattrs {
+            put_u32_prefixed(buf, xattr.name.as_bytes());
+            put_u32_prefixed(buf, &xattr.value);
+        }
+        for acl in [&self.acl, &self.default_acl] {
+            buf.extend_from_slice(&(acl.len() as u32).to_be_bytes());
+            for entry in acl {
+                entry.to_bin(buf);
+            }
+        }
+    }
+
+    fn from_bin(reader: &mut Reader) -> Result<Self, MessageError> {
+        let [has_owner] = reader.array()?;
+        let ownership = match has_owner {
+            0 => None,
+            _ => Some(Ownership {
+                user: take_string(reader)?,
+                group: take_string(reader)?,
+            }),
+        };
+        // Counts aren't trusted for allocations, every item takes at least a few bytes anyway
+        let mut xattrs = vec![];
+        for _ in 0..u32::from_be_bytes(reader.array()?) {
+            xattrs.push(Xattr {
+                name: OsStr::from_bytes(reader.take_u32_prefixed()?).to_owned(),
+                value: reader.take_u32_prefixed()?.to_vec(),
+            });
+        }
+        let mut acls = [vec![], vec![]];
+        for acl in &mut acls {
+            for _ in 0..u32::from_be_bytes(reader.array()?) {
+                acl.push(AclEntry::from_bin(reader)?);
+            }
+        }
+        let [acl, default_acl] = acls;
+        Ok(ExtendedMetadata {
+            ownership,
+            xattrs,
+            acl,
+            default_acl,
+        })
+    }
+}
+
+impl AclEntry {
+    fn to_bin(&self, buf: &mut Vec<u8>) {
+        let (tag, name) = match &self.tag {
+            AclTag::UserObj => (0, None),
+            AclTag::User(name) => (1, Some(name)),
+            AclTag::GroupObj => (2, None),
+            AclTag::Group(name) => (3, Some(name)),
+            AclTag::Mask => (4, None),
+            AclTag::Other => (5, None),
+        };
+        buf.extend_from_slice(&[tag, self.perm]);
+        if let Some(name) = name {
+            put_u32_prefixed(buf, name.as_bytes());
+        }
+    }
+
+    fn from_bin(reader: &mut Reader) -> Result<Self, MessageError> {
+        let [tag, perm] = reader.array()?;
+        let tag = match tag {
+            0 => AclTag::UserObj,
+            1 => AclTag::User(take_string(reader)?),
+            2 => AclTag::GroupObj,
+            3 => AclTag::Group(take_string(reader)?),
+            4 => AclTag::Mask,
+            5 => AclTag::Other,
+            x => return Err(MessageError::UnknownAclTag(x)),
+        };
+        Ok(AclEntry { tag, perm })
+    }
+}
+
+/// Take a UTF-8 string prefixed with its length as a `u32`
+fn take_string(reader: &mut Reader) -> Result<String, MessageError> {
+    Ok(String::from_utf8(reader.take_u32_prefixed()?.to_vec())?)
 }
 
 impl PartialEq for FileMetadata {
@@ -205,7 +677,11 @@ impl PartialEq for FileMetadata {
         self.file_id == other.file_id
             && self.file_name == other.file_name
             && self.permissions == other.permissions
+            && self.modified == other.modified
+            && self.size == other.size
             && self.chunks == other.chunks
+            && self.kind == other.kind
+            && self.extended == other.extended
     }
 }
 
@@ -222,13 +698,19 @@ impl Display for FileMetadata {
             r#"Path: {:?}
 File hash: {}
 Permissions: {}
+Kind: {:?}
+Extended: {:?}
 Created: {} Modified: {}
+Size: {}
 Chunks: {}"#,
             self.file_id.path,
             Base64::encode_string(&self.file_id.hash),
             self.permissions,
+            self.kind,
+            self.extended,
             self.created,
             self.modified,
+            self.size,
             chunks,
         )
     }
@@ -241,13 +723,7 @@ impl FileMetadata {
         chunks: &[[u8; 32]],
     ) -> Result<Self, MessageError> {
         Ok(FileMetadata {
-            file_name: file_id
-                .path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned(),
+            file_name: file_id.path.file_name().unwrap_or_default().to_owned(),
             file_id,
             permissions: metadata.permissions().mode(),
             modified: metadata
@@ -260,25 +736,54 @@ impl FileMetadata {
                 .duration_since(time::UNIX_EPOCH)
                 .unwrap()
                 .as_millis(),
+            // The length of a directory is the space used by its listing, and the length of a
+            // link the length of its target
+            size: if metadata.is_file() {
+                metadata.len()
+            } else {
+                0
+            },
             chunks: chunks
                 .iter()
                 .map(|x| ChunkId(x.to_vec()))
                 .collect::<Vec<ChunkId>>(),
+            kind: if metadata.is_dir() {
+                EntryKind::Directory
+            } else {
+                EntryKind::File
+            },
+            extended: ExtendedMetadata::default(),
         })
     }
 }
 
-impl Argument for FileMetadata {
-    fn to_bin(&self) -> Vec<u8> {
+impl FileMetadata {
+    /// Binary form for a peer supporting `capabilities`.
+    ///
+    /// The size is only sent with
+    /// [`LARGE_OFFSETS`](struct.Capabilities.html#associatedconstant.LARGE_OFFSETS), the kind
+    /// with `ENTRY_KINDS` and the extended metadata with any of its capabilities. Entries the peer
+    /// can't [carry](struct.Capabilities.html#method.carries) or doesn't
+    /// [fit](struct.Capabilities.html#method.fits) mustn't be sent at all.
+    pub fn to_bin_for(&self, capabilities: Capabilities) -> Vec<u8> {
         let mut buf: Vec<u8> = vec![];
 
-        let path = self.file_id.path.to_str().unwrap().as_bytes();
+        let path = self.file_id.path.as_os_str().as_bytes();
         buf.extend_from_slice(&(path.len() as u64).to_be_bytes());
         buf.extend_from_slice(path);
 
         buf.extend_from_slice(&self.permissions.to_be_bytes());
         buf.extend_from_slice(&self.modified.to_be_bytes());
         buf.extend_from_slice(&self.created.to_be_bytes());
+        if capabilities.contains(Capabilities::LARGE_OFFSETS) {
+            buf.extend_from_slice(&self.size.to_be_bytes());
+        }
+        if capabilities.contains(Capabilities::ENTRY_KINDS) {
+            self.kind.to_bin(&mut buf);
+        }
+        if capabilities.intersects(Capabilities::EXTENDED) {
+            self.extended.to_bin(&mut buf);
+        }
         buf.extend_from_slice(&self.file_id.hash);
         for chunk in &self.chunks {
             buf.extend_from_slice(&chunk.0);
@@ -286,53 +791,68 @@ impl Argument for FileMetadata {
         buf
     }
 
-    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
-        let mut buf = [0u8; 8];
-        buf.copy_from_slice(&data[0..8]);
-        let end = 8 + u64::from_be_bytes(buf) as usize;
-        let path = PathBuf::from(String::from_utf8(data[8..(end)].to_vec())?);
-
-        let mut buf = [0u8; 4];
-        buf.copy_from_slice(&data[end..end + 4]);
-        let permissions = u32::from_be_bytes(buf);
-
-        let end = end + 4;
-        let mut buf = [0u8; 16];
-        buf.copy_from_slice(&data[end..end + 16]);
-        let modified = u128::from_be_bytes(buf);
-        buf.copy_from_slice(&data[end + 16..end + 32]);
-        let created = u128::from_be_bytes(buf);
-
-        let end = end + 32;
-        let mut hash = [0u8; 32];
-        hash.copy_from_slice(&data[end..end + 32]);
-
-        let end = end + 32;
-        let mut chunks: Vec<ChunkId> = vec![];
-        for cur in (end..data.len()).step_by(32) {
-            if cur + 32 <= data.len() {
-                chunks.push(ChunkId(data[cur..cur + 32].to_vec()));
-            } else {
-                chunks.push(ChunkId(data[cur..].to_vec()));
-            }
+    /// Decode the binary form sent by a peer supporting `capabilities`
+    pub fn from_bin_for(data: &[u8], capabilities: Capabilities) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(data);
+        let path = PathBuf::from(OsStr::from_bytes(reader.take_u64_prefixed()?));
+        let file_name = path.file_name().ok_or(MessageError::EmptyPath)?.to_owned();
+
+        let permissions = u32::from_be_bytes(reader.array()?);
+        let modified = u128::from_be_bytes(reader.array()?);
+        let created = u128::from_be_bytes(reader.array()?);
+        let size = if capabilities.contains(Capabilities::LARGE_OFFSETS) {
+            u64::from_be_bytes(reader.array()?)
+        } else {
+            0
+        };
+        let kind = if capabilities.contains(Capabilities::ENTRY_KINDS) {
+            EntryKind::from_bin(&mut reader)?
+        } else {
+            EntryKind::File
+        };
+        let extended = if capabilities.intersects(Capabilities::EXTENDED) {
+            ExtendedMetadata::from_bin(&mut reader)?
+        } else {
+            ExtendedMetadata::default()
+        };
+        let hash = reader.array::<32>()?;
+
+        let rest = reader.rest();
+        let partial = rest.len() % 32;
+        if partial != 0 {
+            // The last chunk id was cut off
+            return Err(MessageError::Truncated {
+                needed: 32,
+                available: partial,
+            });
         }
+        let chunks = rest.chunks(32).map(|x| ChunkId(x.to_vec())).collect();
 
         Ok(FileMetadata {
-            file_name: path.file_name().unwrap().to_str().unwrap().to_owned(),
+            file_name,
             file_id: FileId { path, hash },
             permissions,
             modified,
             created,
+            size,
             chunks,
+            kind,
+            extended,
         })
     }
+}
+
+impl Argument for FileMetadata {
+    fn to_bin(&self) -> Vec<u8> {
+        self.to_bin_for(super::CAPABILITIES)
+    }
 
-    fn as_any(&self) -> &dyn Any {
-        self
+    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
+        Self::from_bin_for(data, super::CAPABILITIES)
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct FileList(pub Vec<FileId>);
 
 impl Argument for FileList {
@@ -349,31 +869,114 @@ impl Argument for FileList {
     }
 
     fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
-        let mut buf = [0u8; 2];
-        let mut cur = 0;
+        let mut reader = Reader::new(data);
         let mut files: Vec<FileId> = vec![];
 
-        while cur < data.len() {
-            buf.copy_from_slice(&data[cur..cur + 2]);
-            cur += 2;
-            let size = u16::from_be_bytes(buf);
+        while !reader.data.is_empty() {
+            let size = u16::from_be_bytes(reader.array()?);
+            files.push(FileId::from_bin(reader.take(size.into())?)?);
+        }
+        Ok(FileList(files))
+    }
+}
 
-            if data[cur..].len() < size.into() {
-                return Err(MessageError::InvalidBin);
+/// Chunks requested together with `RequestChunks`.
+///
+/// Binary layout: `(<len:u32> <chunk id>)*`. A batch holds at least one and at most
+/// [`MAX_BATCH_LEN`](../constant.MAX_BATCH_LEN.html) chunks.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ChunkList(pub Vec<QualifiedChunkId>);
+
+impl ChunkList {
+    /// Binary form for a peer supporting `capabilities`
+    pub fn to_bin_for(&self, capabilities: Capabilities) -> Result<Vec<u8>, MessageError> {
+        let mut buf: Vec<u8> = vec![];
+        for chunk in &self.0 {
+            put_u32_prefixed(&mut buf, &chunk.to_bin_for(capabilities)?);
+        }
+        Ok(buf)
+    }
+
+    /// Decode the binary form sent by a peer supporting `capabilities`
+    pub fn from_bin_for(data: &[u8], capabilities: Capabilities) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(data);
+        let mut chunks = vec![];
+        while !reader.data.is_empty() {
+            let chunk = reader.take_u32_prefixed()?;
+            if chunks.len() == super::MAX_BATCH_LEN {
+                return Err(MessageError::BatchTooLong {
+                    max: super::MAX_BATCH_LEN,
+                });
             }
+            chunks.push(QualifiedChunkId::from_bin_for(chunk, capabilities)?);
+        }
+        if chunks.is_empty() {
+            return Err(MessageError::EmptyBatch);
+        }
+        Ok(ChunkList(chunks))
+    }
+}
 
-            files.push(FileId::from_bin(&data[cur..(cur + size as usize)])?);
-            cur += size as usize;
+impl Argument for ChunkList {
+    fn to_bin(&self) -> Vec<u8> {
+        // Every offset fits with `LARGE_OFFSETS`
+        self.to_bin_for(super::CAPABILITIES)
+            .expect("64 bit offsets can't overflow")
+    }
+
+    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
+        Self::from_bin_for(data, super::CAPABILITIES)
+    }
+}
+
+/// Files sent together with `SendFileBatch`.
+///
+/// Binary layout: `(<len:u32> <metadata>)*`. A batch holds at least one and at most
+/// [`MAX_BATCH_LEN`](../constant.MAX_BATCH_LEN.html) files.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MetadataList(pub Vec<FileMetadata>);
+
+impl MetadataList {
+    /// Binary form for a peer supporting `capabilities`
+    pub fn to_bin_for(&self, capabilities: Capabilities) -> Vec<u8> {
+        let mut buf: Vec<u8> = vec![];
+        for file in &self.0 {
+            put_u32_prefixed(&mut buf, &file.to_bin_for(capabilities));
         }
-        Ok(FileList(files))
+        buf
+    }
+
+    /// Decode the binary form sent by a peer supporting `capabilities`
+    pub fn from_bin_for(data: &[u8], capabilities: Capabilities) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(data);
+        let mut files = vec![];
+        while !reader.data.is_empty() {
+            let file = reader.take_u32_prefixed()?;
+            if files.len() == super::MAX_BATCH_LEN {
+                return Err(MessageError::BatchTooLong {
+                    max: super::MAX_BATCH_LEN,
+                });
+            }
+            files.push(FileMetadata::from_bin_for(file, capabilities)?);
+        }
+        if files.is_empty() {
+            return Err(MessageError::EmptyBatch);
+        }
+        Ok(MetadataList(files))
     }
+}
 
-    fn as_any(&self) -> &dyn Any {
-        self
+impl Argument for MetadataList {
+    fn to_bin(&self) -> Vec<u8> {
+        self.to_bin_for(super::CAPABILITIES)
+    }
+
+    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
+        Self::from_bin_for(data, super::CAPABILITIES)
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Chunk {
     pub id: ChunkId,
     pub data: Vec<u8>,
@@ -387,52 +990,64 @@ impl Argument for Chunk {
     }
 
     fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
-        let chunk_id = ChunkId::from_bin(&data[..32])?;
-        let chunk_data = data[32..].to_vec();
+        let mut reader = Reader::new(data);
+        let chunk_id = ChunkId::from_bin(reader.take(32)?)?;
+        let chunk_data = reader.rest().to_vec();
         Ok(Chunk {
             id: chunk_id,
             data: chunk_data,
         })
     }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct QualifiedChunk {
     pub id: QualifiedChunkId,
     pub data: Vec<u8>,
 }
 
-impl Argument for QualifiedChunk {
-    fn to_bin(&self) -> Vec<u8> {
-        let id = self.id.to_bin();
+impl QualifiedChunk {
+    /// Binary form for a peer supporting `capabilities`
+    pub fn to_bin_for(&self, capabilities: Capabilities) -> Result<Vec<u8>, MessageError> {
+        let id = self.id.to_bin_for(capabilities)?;
         let mut buf: Vec<u8> = (id.len() as u64).to_be_bytes().to_vec();
         buf.extend_from_slice(&id);
         buf.extend_from_slice(&self.data);
-        buf
+        Ok(buf)
     }
 
-    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
-        let mut buf = [0u8; 8];
-        buf.copy_from_slice(&data[..8]);
-        let len = u64::from_be_bytes(buf) as usize;
-        let chunk_id = QualifiedChunkId::from_bin(&data[8..8 + len])?;
-        let chunk_data = data[8 + len..].to_vec();
+    /// Decode the binary form sent by a peer supporting `capabilities`
+    pub fn from_bin_for(data: &[u8], capabilities: Capabilities) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(data);
+        let chunk_id = QualifiedChunkId::from_bin_for(reader.take_u64_prefixed()?, capabilities)?;
+        let chunk_data = reader.rest().to_vec();
         Ok(QualifiedChunk {
             id: chunk_id,
             data: chunk_data,
         })
     }
+}
 
-    fn as_any(&self) -> &dyn Any {
-        self
+impl Argument for QualifiedChunk {
+    fn to_bin(&self) -> Vec<u8> {
+        // Every offset fits with `LARGE_OFFSETS`
+        self.to_bin_for(super::CAPABILITIES)
+            .expect("64 bit offsets can't overflow")
     }
+
+    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
+        Self::from_bin_for(data, super::CAPABILITIES)
+    }
+}
+/// Outcome of a successful request, sent back in a `Response` carrying the request's id.
+///
+/// Failed requests are answered with an `Error` instead.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ResponseCode(pub u16);
+
+impl ResponseCode {
+    pub const OK: ResponseCode = ResponseCode(0);
 }
-#[derive(Debug, PartialEq, Eq)]
-pub struct ResponseCode(u16);
 
 impl Argument for ResponseCode {
     fn to_bin(&self) -> Vec<u8> {
@@ -440,32 +1055,109 @@ impl Argument for ResponseCode {
     }
 
     fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
-        let mut buf = [0u8; 2];
-        buf.copy_from_slice(data);
-        Ok(ResponseCode(u16::from_be_bytes(buf)))
+        Ok(ResponseCode(u16::from_be_bytes(exact(data)?)))
     }
+}
 
-    fn as_any(&self) -> &dyn Any {
-        self
+/// Kind of a [`ProtocolError`](struct.ProtocolError.html).
+///
+/// Unknown codes (from newer peers) are kept as is.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ErrorCode(pub u16);
+
+impl ErrorCode {
+    /// The file or chunk the message refers to doesn't exist
+    pub const NOT_FOUND: ErrorCode = ErrorCode(1);
+    /// The message or its argument is malformed
+    pub const INVALID_ARGUMENT: ErrorCode = ErrorCode(2);
+    /// Reading or writing the peer's storage failed
+    pub const STORAGE_FAILURE: ErrorCode = ErrorCode(3);
+    /// The peer doesn't handle the message's directive
+    pub const UNSUPPORTED_DIRECTIVE: ErrorCode = ErrorCode(4);
+    /// Files the message refers to are still being uploaded, so they can't be changed yet
+    pub const UPLOAD_PENDING: ErrorCode = ErrorCode(5);
+    /// The file is too large to be addressed without `LARGE_OFFSETS`
+    pub const TOO_LARGE: ErrorCode = ErrorCode(6);
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ErrorCode::NOT_FOUND => write!(f, "Not found"),
+            ErrorCode::INVALID_ARGUMENT => write!(f, "Invalid argument"),
+            ErrorCode::STORAGE_FAILURE => write!(f, "Storage failure"),
+            ErrorCode::UNSUPPORTED_DIRECTIVE => write!(f, "Unsupported directive"),
+            ErrorCode::UPLOAD_PENDING => write!(f, "Upload pending"),
+            ErrorCode::TOO_LARGE => write!(f, "Too large"),
+            ErrorCode(code) => write!(f, "Error {}", code),
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct Dummy {}
+/// Argument of the `Error` directive, sent back when a message couldn't be handled.
+///
+/// Binary layout: `<code:u16> <message:utf-8>`. The message is meant for humans.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ProtocolError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<MessageError> for ProtocolError {
+    /// The error answering a message that can't be encoded or decoded
+    fn from(e: MessageError) -> Self {
+        let code = match e {
+            MessageError::UnknownDirective(_) => ErrorCode::UNSUPPORTED_DIRECTIVE,
+            MessageError::OffsetTooLarge(_) => ErrorCode::TOO_LARGE,
+            _ => ErrorCode::INVALID_ARGUMENT,
+        };
+        ProtocolError::new(code, e.to_string())
+    }
+}
 
-impl Argument for Dummy {
+impl Argument for ProtocolError {
     fn to_bin(&self) -> Vec<u8> {
-        todo!()
+        let mut buf = self.code.0.to_be_bytes().to_vec();
+        buf.extend_from_slice(self.message.as_bytes());
+        buf
     }
 
-    fn from_bin(data: &[u8]) -> Result<Self, MessageError>
-    where
-        Self: Sized,
-    {
-        todo!()
+    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(data);
+        Ok(ProtocolError {
+            code: ErrorCode(u16::from_be_bytes(reader.array()?)),
+            message: String::from_utf8_lossy(reader.rest()).into_owned(),
+        })
+    }
+}
+
+/// Number of additional requests the peer may have outstanding on the message's stream
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Credit(pub u32);
+
+impl Argument for Credit {
+    fn to_bin(&self) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
     }
 
-    fn as_any(&self) -> &dyn Any {
-        todo!()
+    fn from_bin(data: &[u8]) -> Result<Self, MessageError> {
+        Ok(Credit(u32::from_be_bytes(exact(data)?)))
     }
 }
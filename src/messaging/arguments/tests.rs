@@ -5,8 +5,51 @@ use pretty_assertions::assert_eq;
 
 #[test]
 fn test_argument_version() {
-    assert_eq!(Version(1).to_bin(), vec![1u8]);
-    assert_eq!(Version::from_bin(&[1u8]).unwrap(), Version(1));
+    let version = Version {
+        version: 3,
+        min_version: 2,
+        capabilities: Capabilities(0x0105),
+    };
+    assert_eq!(version.to_bin(), vec![3u8, 2, 0, 0, 1, 5]);
+    assert_eq!(Version::from_bin(&[3u8, 2, 0, 0, 1, 5]).unwrap(), version);
+    // Announcements of peers that predate capabilities
+    assert_eq!(
+        Version::from_bin(&[1u8]).unwrap(),
+        Version {
+            version: 1,
+            min_version: 1,
+            capabilities: Capabilities(0),
+        }
+    );
+    assert!(matches!(
+        Version::from_bin(&[1u8, 2]),
+        Err(MessageError::BadLength {
+            expected: 6,
+            actual: 2
+        })
+    ));
+}
+
+#[test]
+fn test_argument_protocol_error() {
+    let error = ProtocolError::new(ErrorCode::NOT_FOUND, "gone");
+    assert_eq!(error.to_bin(), vec![0u8, 1, b'g', b'o', b'n', b'e']);
+    assert_eq!(ProtocolError::from_bin(&error.to_bin()).unwrap(), error);
+    assert_eq!(
+        ProtocolError::from_bin(&[0u8, 42]).unwrap(),
+        ProtocolError::new(ErrorCode(42), "")
+    );
+    assert!(ProtocolError::from_bin(&[1u8]).is_err());
+}
+
+#[test]
+fn test_argument_credit() {
+    assert_eq!(Credit(0x01020304).to_bin(), vec![1u8, 2, 3, 4]);
+    assert_eq!(Credit::from_bin(&[0u8, 0, 1, 0]).unwrap(), Credit(256));
+    assert!(matches!(
+        Credit::from_bin(&[0u8, 1]),
+        Err(MessageError::BadLength { .. })
+    ));
 }
 
 #[test]
@@ -15,7 +58,7 @@ fn test_argument_fileid() {
     let mut a = vec![112, 97, 116, 104, 47, 116, 111, 47, 102, 105, 108, 101];
     h.update(b"Hello world");
     let mut b = [0u8; 32];
-    b.copy_from_slice(&h.finalize().as_bytes().to_vec());
+    b.copy_from_slice(h.finalize().as_bytes().as_ref());
     a.extend_from_slice(&b);
     assert_eq!(
         FileId {
@@ -34,6 +77,76 @@ fn test_argument_fileid() {
     );
 }
 
+#[test]
+fn test_non_utf8_paths() {
+    // Latin-1 "café", which isn't valid UTF-8
+    let name = OsStr::from_bytes(b"caf\xe9");
+    let path = Path::new("dir").join(name);
+
+    let file_id = FileId {
+        path: path.clone(),
+        hash: [4u8; 32],
+    };
+    assert_eq!(FileId::from_bin(&file_id.to_bin()).unwrap(), file_id);
+    assert_eq!(
+        bincode::deserialize::<FileId>(&bincode::serialize(&file_id).unwrap()).unwrap(),
+        file_id
+    );
+
+    let file_path = FilePath(path.clone());
+    assert_eq!(file_path.to_bin(), b"dir/caf\xe9");
+    assert_eq!(FilePath::from_bin(&file_path.to_bin()).unwrap(), file_path);
+
+    let metadata = FileMetadata {
+        file_id,
+        file_name: name.to_owned(),
+        permissions: 0o644,
+        modified: 1,
+        created: 2,
+        size: 100,
+        chunks: vec![ChunkId([1u8; 32].to_vec())],
+        kind: EntryKind::File,
+        extended: ExtendedMetadata::default(),
+    };
+    assert_eq!(
+        FileMetadata::from_bin(&metadata.to_bin()).unwrap(),
+        metadata
+    );
+    assert_eq!(
+        bincode::deserialize::<FileMetadata>(&bincode::serialize(&metadata).unwrap()).unwrap(),
+        metadata
+    );
+}
+
+#[test]
+fn test_argument_rename() {
+    let rename = Rename {
+        from: PathBuf::from("dir"),
+        to: PathBuf::from("a/b"),
+    };
+    assert_eq!(rename.to_bin(), b"\0\0\0\x03dira/b");
+    assert_eq!(Rename::from_bin(&rename.to_bin()).unwrap(), rename);
+
+    // Both paths are needed
+    assert!(matches!(
+        Rename::from_bin(b"\0\0\0\x03dir"),
+        Err(MessageError::EmptyPath)
+    ));
+    assert!(matches!(
+        Rename::from_bin(b"\0\0\0\x04dir"),
+        Err(MessageError::Truncated { .. })
+    ));
+}
+
+#[test]
+fn test_safe_paths() {
+    assert!(is_safe_path(Path::new("dir/file")));
+    assert!(is_safe_path(Path::new("dir/")));
+    for path in ["", ".", "./file", "..", "dir/../../file", "/etc/passwd"] {
+        assert!(!is_safe_path(Path::new(path)), "{:?}", path);
+    }
+}
+
 #[test]
 fn test_argument_chunkid() {
     assert_eq!(
@@ -59,7 +172,174 @@ fn test_qualfied_chunk() {
         },
         data: vec![9, 8, 7, 6, 5, 4, 3, 2, 1, 0],
     };
-    assert_eq!(chunk, QualifiedChunk::from_bin(&chunk.to_bin()).unwrap())
+    assert_eq!(chunk, QualifiedChunk::from_bin(&chunk.to_bin()).unwrap());
+
+    // A length prefix pointing past the end of the message
+    let mut raw = chunk.to_bin();
+    raw[0] = 0xff;
+    assert!(matches!(
+        QualifiedChunk::from_bin(&raw),
+        Err(MessageError::Truncated { .. })
+    ));
+}
+
+#[test]
+fn test_file_metadata() {
+    let metadata = FileMetadata {
+        file_id: FileId {
+            path: PathBuf::from("dir/file"),
+            hash: [3u8; 32],
+        },
+        file_name: "file".into(),
+        permissions: 0o644,
+        modified: 1,
+        created: 2,
+        size: (1 << 20) + 100,
+        chunks: vec![ChunkId([1u8; 32].to_vec()), ChunkId([2u8; 32].to_vec())],
+        kind: EntryKind::File,
+        extended: ExtendedMetadata::default(),
+    };
+    let raw = metadata.to_bin();
+    assert_eq!(FileMetadata::from_bin(&raw).unwrap(), metadata);
+    // Touching a file changes its metadata
+    let touched = FileMetadata {
+        modified: 3,
+        ..metadata.clone()
+    };
+    assert_ne!(touched, metadata);
+
+    // Cut off in the middle of the last chunk id
+    assert!(matches!(
+        FileMetadata::from_bin(&raw[..raw.len() - 1]),
+        Err(MessageError::Truncated {
+            needed: 32,
+            available: 31
+        })
+    ));
+    // Cut off in the middle of the path
+    assert!(matches!(
+        FileMetadata::from_bin(&raw[..10]),
+        Err(MessageError::Truncated { .. })
+    ));
+
+    // Paths without a file name
+    let mut raw = 0u64.to_be_bytes().to_vec();
+    raw.extend_from_slice(&[0u8; 77]);
+    assert!(matches!(
+        FileMetadata::from_bin(&raw),
+        Err(MessageError::EmptyPath)
+    ));
+
+    let dir = FileMetadata {
+        file_id: FileId {
+            path: PathBuf::from("dir"),
+            hash: [0u8; 32],
+        },
+        file_name: "dir".into(),
+        permissions: 0o40755,
+        modified: 1,
+        created: 2,
+        size: 0,
+        chunks: vec![],
+        kind: EntryKind::Directory,
+        extended: ExtendedMetadata::default(),
+    };
+    let mut raw = dir.to_bin();
+    assert_eq!(FileMetadata::from_bin(&raw).unwrap(), dir);
+    // The kind comes right before the extended metadata, which takes 13 bytes when empty
+    let kind = raw.len() - 32 - 13 - 1;
+    raw[kind] = 7;
+    assert!(matches!(
+        FileMetadata::from_bin(&raw),
+        Err(MessageError::UnknownEntryKind(7))
+    ));
+
+    let target = PathBuf::from(OsStr::from_bytes(b"../t\xffrget"));
+    let link = FileMetadata {
+        file_id: FileId::symlink(PathBuf::from("dir/link"), &target),
+        file_name: "link".into(),
+        permissions: 0o120777,
+        modified: 1,
+        created: 2,
+        size: 0,
+        chunks: vec![],
+        kind: EntryKind::Symlink(target),
+        extended: ExtendedMetadata::default(),
+    };
+    let raw = link.to_bin();
+    assert_eq!(FileMetadata::from_bin(&raw).unwrap(), link);
+    // The target is cut off
+    assert!(matches!(
+        FileMetadata::from_bin(&raw[..raw.len() - 34]),
+        Err(MessageError::Truncated { .. })
+    ));
+}
+
+#[test]
+fn test_extended_metadata() {
+    let extended = ExtendedMetadata {
+        ownership: Some(Ownership {
+            user: "alice".to_string(),
+            group: "1234".to_string(),
+        }),
+        xattrs: vec![
+            Xattr {
+                name: "security.selinux".into(),
+                value: b"user_u:object_r:user_home_t:s0\0".to_vec(),
+            },
+            Xattr {
+                name: "user.tag".into(),
+                value: vec![],
+            },
+        ],
+        acl: vec![
+            AclEntry {
+                tag: AclTag::UserObj,
+                perm: 6,
+            },
+            AclEntry {
+                tag: AclTag::User("bob".to_string()),
+                perm: 4,
+            },
+            AclEntry {
+                tag: AclTag::Mask,
+                perm: 4,
+            },
+        ],
+        default_acl: vec![AclEntry {
+            tag: AclTag::Group("staff".to_string()),
+            perm: 7,
+        }],
+    };
+    let mut file = FileMetadata {
+        file_id: FileId {
+            path: PathBuf::from("file"),
+            hash: [1u8; 32],
+        },
+        file_name: "file".into(),
+        permissions: 0o100640,
+        modified: 1,
+        created: 2,
+        size: 0,
+        chunks: vec![],
+        kind: EntryKind::File,
+        extended: extended.clone(),
+    };
+    let raw = file.to_bin();
+    assert_eq!(FileMetadata::from_bin(&raw).unwrap(), file);
+    assert!(matches!(
+        FileMetadata::from_bin(&raw[..raw.len() - 40]),
+        Err(MessageError::Truncated { .. })
+    ));
+
+    // Only the supported parts are sent, the rest is kept from what's stored
+    file.extended.restrict(Capabilities::XATTRS);
+    assert_eq!(file.extended.ownership, None);
+    assert_eq!(file.extended.xattrs, extended.xattrs);
+    assert!(file.extended.acl.is_empty() && file.extended.default_acl.is_empty());
+    file.extended
+        .keep_unsupported(&extended, Capabilities::XATTRS);
+    assert_eq!(file.extended, extended);
 }
 
 #[test]
@@ -78,19 +358,217 @@ fn test_qualified_chunkid() {
         vec![
             0u8, 0u8, 0u8, 40u8, 100u8, 105u8, 114u8, 47u8, 102u8, 105u8, 108u8, 101u8, 0u8, 0u8,
             0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8,
-            0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 2u8, 2u8, 2u8, 2u8,
+            0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8,
+            2u8, 2u8, 2u8, 2u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8,
             1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8,
-            1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8,
+            1u8, 1u8,
         ]
     );
 
     let raw_chunk_id = vec![
         0u8, 0u8, 0u8, 40u8, 100u8, 105u8, 114u8, 47u8, 102u8, 105u8, 108u8, 101u8, 0u8, 0u8, 0u8,
         0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8,
-        0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 2u8, 2u8, 2u8, 2u8, 1u8, 1u8, 1u8,
-        1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8,
-        1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8,
+        0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 2u8, 2u8, 2u8,
+        2u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8,
+        1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8, 1u8,
     ];
 
     assert_eq!(QualifiedChunkId::from_bin(&raw_chunk_id).unwrap(), chunk_id);
+
+    // Chunks of files larger than 4 GiB
+    let chunk_id = QualifiedChunkId {
+        offset: 5 << 30,
+        ..chunk_id
+    };
+    assert_eq!(
+        QualifiedChunkId::from_bin(&chunk_id.to_bin()).unwrap(),
+        chunk_id
+    );
+}
+
+#[test]
+fn test_layout_capabilities() {
+    let chunk_id = QualifiedChunkId {
+        path: FileId {
+            path: PathBuf::from("file"),
+            hash: [0u8; 32],
+        },
+        offset: 0x01020304,
+        id: ChunkId([1u8; 32].to_vec()),
+    };
+    // Peers without `LARGE_OFFSETS` use 32 bit offsets
+    let raw = chunk_id.to_bin_for(Capabilities(0)).unwrap();
+    assert_eq!(raw.len(), chunk_id.to_bin().len() - 4);
+    assert_eq!(raw[40..44], [1u8, 2, 3, 4]);
+    assert_eq!(
+        QualifiedChunkId::from_bin_for(&raw, Capabilities(0)).unwrap(),
+        chunk_id
+    );
+    assert_eq!(
+        QualifiedChunkId::from_bin_for(&chunk_id.to_bin(), Capabilities::LARGE_OFFSETS).unwrap(),
+        chunk_id
+    );
+
+    let file = FileMetadata {
+        file_id: FileId {
+            path: PathBuf::from("file"),
+            hash: [3u8; 32],
+        },
+        file_name: "file".into(),
+        permissions: 0o100644,
+        modified: 1,
+        created: 2,
+        size: 3,
+        chunks: vec![ChunkId([1u8; 32].to_vec())],
+        kind: EntryKind::File,
+        extended: ExtendedMetadata {
+            ownership: None,
+            xattrs: vec![Xattr {
+                name: "user.tag".into(),
+                value: vec![1],
+            }],
+            acl: vec![],
+            default_acl: vec![],
+        },
+    };
+    // Without any of the layout capabilities, the size, kind and extended metadata are left out
+    let raw = file.to_bin_for(Capabilities(0));
+    // Path, permissions, times, hash and the chunk id
+    assert_eq!(raw.len(), 8 + 4 + 4 + 16 + 16 + 32 + 32);
+    assert_eq!(
+        FileMetadata::from_bin_for(&raw, Capabilities(0)).unwrap(),
+        FileMetadata {
+            size: 0,
+            extended: ExtendedMetadata::default(),
+            ..file.clone()
+        }
+    );
+    let capabilities = Capabilities(
+        Capabilities::LARGE_OFFSETS.0 | Capabilities::ENTRY_KINDS.0 | Capabilities::XATTRS.0,
+    );
+    assert_eq!(
+        FileMetadata::from_bin_for(&file.to_bin_for(capabilities), capabilities).unwrap(),
+        file
+    );
+
+    // Directories and links only reach peers that can synchronize them
+    assert!(Capabilities(0).carries(&EntryKind::File));
+    assert!(!Capabilities(0).carries(&EntryKind::Directory));
+    assert!(Capabilities::ENTRY_KINDS.carries(&EntryKind::Directory));
+    let link = EntryKind::Symlink(PathBuf::from("target"));
+    assert!(!Capabilities::ENTRY_KINDS.carries(&link));
+    assert!(Capabilities(Capabilities::ENTRY_KINDS.0 | Capabilities::SYMLINKS.0).carries(&link));
+
+    // Files past 4 GiB only reach peers with 64 bit offsets
+    assert!(Capabilities(0).fits(&file));
+    let large = FileMetadata {
+        size: 1 << 32,
+        ..file.clone()
+    };
+    assert!(!Capabilities(0).fits(&large));
+    assert!(Capabilities::LARGE_OFFSETS.fits(&large));
+    // Without the size, the chunks are counted
+    let counted = |chunks: usize| FileMetadata {
+        size: 0,
+        chunks: vec![ChunkId([1u8; 32].to_vec()); chunks],
+        ..file.clone()
+    };
+    assert!(Capabilities(0).fits(&counted(4096)));
+    assert!(!Capabilities(0).fits(&counted(4097)));
+}
+
+#[test]
+fn test_large_offset_without_capability() {
+    let chunk_id = QualifiedChunkId {
+        path: FileId {
+            path: PathBuf::from("file"),
+            hash: [0u8; 32],
+        },
+        offset: 1 << 32,
+        id: ChunkId([1u8; 32].to_vec()),
+    };
+    // Never truncated to another offset
+    let e = chunk_id.to_bin_for(Capabilities(0)).unwrap_err();
+    assert!(matches!(e, MessageError::OffsetTooLarge(offset) if offset == 1 << 32));
+    assert_eq!(ProtocolError::from(e).code, ErrorCode::TOO_LARGE);
+    let chunks = ChunkList(vec![chunk_id.clone()]);
+    assert!(chunks.to_bin_for(Capabilities(0)).is_err());
+    assert!(chunks.to_bin_for(Capabilities::LARGE_OFFSETS).is_ok());
+}
+
+#[test]
+fn test_argument_batches() {
+    let file_id = FileId {
+        path: PathBuf::from("dir/file"),
+        hash: [3u8; 32],
+    };
+    let chunks = ChunkList(vec![
+        QualifiedChunkId {
+            path: file_id.clone(),
+            offset: 0,
+            id: ChunkId([1u8; 32].to_vec()),
+        },
+        QualifiedChunkId {
+            path: file_id.clone(),
+            offset: 1 << 20,
+            id: ChunkId([2u8; 32].to_vec()),
+        },
+    ]);
+    assert_eq!(ChunkList::from_bin(&chunks.to_bin()).unwrap(), chunks);
+
+    let file = FileMetadata {
+        file_id,
+        file_name: "file".into(),
+        permissions: 0o644,
+        modified: 1,
+        created: 2,
+        size: 3,
+        chunks: vec![ChunkId([1u8; 32].to_vec())],
+        kind: EntryKind::File,
+        extended: ExtendedMetadata::default(),
+    };
+    let dir = FileMetadata {
+        file_id: FileId {
+            path: PathBuf::from("dir"),
+            hash: [0u8; 32],
+        },
+        file_name: "dir".into(),
+        size: 0,
+        chunks: vec![],
+        kind: EntryKind::Directory,
+        ..file.clone()
+    };
+    let files = MetadataList(vec![dir, file]);
+    let raw = files.to_bin();
+    assert_eq!(MetadataList::from_bin(&raw).unwrap(), files);
+
+    // Cut off in the middle of the last file
+    assert!(matches!(
+        MetadataList::from_bin(&raw[..raw.len() - 1]),
+        Err(MessageError::Truncated { .. })
+    ));
+    // Batches can't be empty
+    assert!(matches!(
+        ChunkList::from_bin(&[]),
+        Err(MessageError::EmptyBatch)
+    ));
+    assert!(matches!(
+        MetadataList::from_bin(&[]),
+        Err(MessageError::EmptyBatch)
+    ));
+    // Nor longer than `MAX_BATCH_LEN`
+    let max = crate::messaging::MAX_BATCH_LEN;
+    let chunk = chunks.0[0].clone();
+    let full = ChunkList(vec![chunk.clone(); max]);
+    assert_eq!(ChunkList::from_bin(&full.to_bin()).unwrap(), full);
+    let long = ChunkList(vec![chunk; max + 1]);
+    assert!(matches!(
+        ChunkList::from_bin(&long.to_bin()),
+        Err(MessageError::BatchTooLong { .. })
+    ));
+    let long = MetadataList(vec![files.0[1].clone(); max + 1]);
+    assert!(matches!(
+        MetadataList::from_bin(&long.to_bin()),
+        Err(MessageError::BatchTooLong { .. })
+    ));
 }
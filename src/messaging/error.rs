@@ -5,17 +5,98 @@ use std::{fmt::Display, error::Error, io, string::FromUtf8Error};
 #[derive(Debug)]
 /// Error used when encoding and decoding messages.
 pub enum MessageError {
-    /// Invalid message conversion from binary
-    InvalidBin,
+    /// The binary form ended in the middle of a field that needs `needed` bytes, of which only
+    /// `available` are left
+    Truncated {
+        needed: usize,
+        available: usize,
+    },
+    /// A fixed size argument doesn't have the expected length
+    BadLength {
+        expected: usize,
+        actual: usize,
+    },
+    /// The message's directive doesn't exist
+    UnknownDirective(u16),
+    /// The entry kind of a `FileMetadata` doesn't exist
+    UnknownEntryKind(u8),
+    /// The tag of an ACL entry doesn't exist
+    UnknownAclTag(u8),
     EmptyPath,
+    /// A batch argument doesn't hold anything
+    EmptyBatch,
+    /// A batch argument holds more than `max` items
+    BatchTooLong {
+        max: usize,
+    },
     UtfError,
+    /// A chunk offset needs more than the 32 bits a peer without `LARGE_OFFSETS` supports
+    OffsetTooLarge(u64),
+    /// The peer's first message wasn't its version announcement
+    MissingVersion,
+    /// The peer's protocol version is older than the oldest one accepted
+    VersionTooOld {
+        version: u8,
+        min_version: u8,
+    },
+    /// The peer doesn't accept our protocol version anymore
+    VersionRejected {
+        version: u8,
+        min_version: u8,
+    },
     /// Generic IO error
     IO(String),
 }
 
 impl Display for MessageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}", self))
+        match self {
+            MessageError::Truncated { needed, available } => write!(
+                f,
+                "Truncated message: field needs {} bytes, but only {} are left",
+                needed, available
+            ),
+            MessageError::BadLength { expected, actual } => write!(
+                f,
+                "Bad argument length: expected {} bytes, got {}",
+                expected, actual
+            ),
+            MessageError::UnknownDirective(verb) => write!(f, "Unknown directive {}", verb),
+            MessageError::UnknownEntryKind(kind) => write!(f, "Unknown entry kind {}", kind),
+            MessageError::UnknownAclTag(tag) => write!(f, "Unknown ACL tag {}", tag),
+            MessageError::EmptyPath => write!(f, "Empty path"),
+            MessageError::EmptyBatch => write!(f, "Empty batch"),
+            MessageError::BatchTooLong { max } => {
+                write!(f, "Batch holds more than {} items", max)
+            }
+            MessageError::UtfError => write!(f, "Invalid UTF-8"),
+            MessageError::OffsetTooLarge(offset) => {
+                write!(f, "Chunk offset {} needs more than 32 bits", offset)
+            }
+            MessageError::MissingVersion => {
+                write!(
+                    f,
+                    "Peer didn't announce its protocol version, it's likely too old"
+                )
+            }
+            MessageError::VersionTooOld {
+                version,
+                min_version,
+            } => write!(
+                f,
+                "Peer speaks protocol version {}, but at least version {} is required",
+                version, min_version
+            ),
+            MessageError::VersionRejected {
+                version,
+                min_version,
+            } => write!(
+                f,
+                "Peer requires at least protocol version {}, but this side speaks version {}",
+                min_version, version
+            ),
+            MessageError::IO(e) => write!(f, "IO error: {}", e),
+        }
     }
 }
 
@@ -1,8 +1,9 @@
 //! Messaging module to handle conversion to and from protocol message structure.
 //!
 //! This module defines the basic structure for messages between the client and server.
-//! It also implements the `From` traits between `Vec<u8>`/`&[u8]` and
-//! [`Message`](struct.Message.html).
+//! Every message is a variant of [`Message`](enum.Message.html), holding its typed argument.
+//! [`MessageBuilder`](struct.MessageBuilder.html) encodes them to binary and decodes them back
+//! into an [`Envelope`](struct.Envelope.html), which adds the message's header fields.
 //!
 //! ## Design
 //!
@@ -11,42 +12,105 @@
 //! Each message sent over the network should be encoded in binary and structured as follows:
 //!
 //! ```
-//! <msg-num:u16> <verb:u16> [<argument>]
+//! <msg-num:u16> <stream:u16> <verb:u16> [<argument>]
 //! ```
 //!
 //! - `msg-num` is a 16-bit unsigned integer that represents each network packet with a unique
-//! number.
+//!   number. Number `0` is reserved for `Credit` messages, see [Streams](#streams).
+//! - `stream` is the logical stream the message belongs to. See [Streams](#streams).
 //! - `verb` is a 16-bit unsigned integer that represents an action to be taken on the responders
-//! part. This can be thought of as a command/directive/verb.
+//!   part. This can be thought of as a command/directive/verb.
 //! - `argument` completely depends on the `verb`. Each `verb` will have its own argument type, and
-//! each argument can define its own structure. As such, arguments can be fixed or dynamic in size.
+//!   each argument can define its own structure. As such, arguments can be fixed or dynamic in size.
 //!
 //! A list of arguments can be found in the [`arguments`](arguments/index.html) sub-module.
 //!
-//! Verbs/directives are defined in the [`Directive`](enum.Directive.html) enum.
+//! Verbs/directives are defined in the [`Directive`](enum.Directive.html) enum. Adding one means
+//! adding a variant to both `Directive` and `Message`. The matches on `Message` are exhaustive, so
+//! the compiler points at every encoder and handler that has to deal with it.
+//!
+//! ## Streams
+//!
+//! Messages are multiplexed over a single connection using logical streams. Stream `0` is the
+//! control stream, which carries everything but chunk transfers and always has priority. Each file
+//! transfer gets a stream of its own: streams opened by the client have odd ids, streams opened by
+//! the server have even ids.
+//!
+//! The side that opened a stream sends chunk requests on it and the other side answers on the same
+//! stream. Requests are flow controlled with credits: a side can't have more than
+//! [`INITIAL_CREDIT`](mux/constant.INITIAL_CREDIT.html) requests outstanding on a stream. Once an
+//! answer has been written, the answering side hands the credit back with a `Credit` message on
+//! the same stream. This bounds the amount of chunk data queued for every stream, and a stream
+//! whose peer is slow can't hold up the others. A peer that sends more requests than it has credit
+//! for is dropped. See the [`mux`](mux/index.html) sub-module.
+//!
+//! The control stream has no credit. Instead, a peer that leaves more than
+//! [`CONTROL_BACKLOG`](mux/constant.CONTROL_BACKLOG.html) of the messages answering it on the
+//! control stream unread is dropped once it sends the next one.
+//!
+//! Credits are written by the multiplexer rather than along with the other messages of a
+//! connection, so they all carry the reserved `msg-num` [`CREDIT_ID`](constant.CREDIT_ID.html)
+//! instead of a number of their own.
+//!
+//! With the `BATCH` capability, up to [`MAX_BATCH_LEN`](constant.MAX_BATCH_LEN.html) chunks can be
+//! requested at once with `RequestChunks`. A batch counts as a single request: it's answered with
+//! one message per chunk, in order, and its credit is handed back after the last one. Longer
+//! batches, or batches sent without the capability, aren't decoded at all.
+//!
+//! ## Versions
+//!
+//! Right after the Noise handshake, both sides send an `AnnounceVersion` message on the control
+//! stream, before anything else. It holds the protocol version spoken by the sender, the oldest
+//! version it accepts from its peer and the optional features (capabilities) it supports. The
+//! first message received from the peer has to be its announcement.
+//!
+//! When either side is too old for the other one, both of them report the mismatch and the
+//! connection is dropped. Otherwise only the capabilities supported by both sides are used on the
+//! connection. Peers that only announce a version byte support no capabilities.
+//!
+//! Changes to the layout of arguments are gated on capabilities as well, so peers back to the
+//! first version with an announcement keep working. A side always decodes with the same
+//! capabilities its peer encodes with.
+//!
+//! ## Requests and Responses
+//!
+//! Every request the client sends on the control stream (`ListFiles`, `RequestFile`, `SendFile`,
+//! `SendFileBatch`, `DeleteFile` and `RenameFile`) is answered with exactly one `Response` or
+//! `Error` carrying the request's `msg-num`. Anything the request produces, like the file list, is
+//! sent before the response. The response to a `SendFile` is only sent once the upload is
+//! committed, after the last missing chunk was stored. A `SendFileBatch` uploads several files
+//! with a single request, which is answered once all of them are committed.
+//!
+//! Chunk requests are answered with the chunk itself, on the stream they were sent on. Control
+//! messages (`AnnounceVersion`, `Ping`, `Pong` and `Credit`) and the changes the server pushes to
+//! clients aren't answered.
+//!
+//! ## Errors
+//!
+//! A message that can't be handled is answered with an `Error` carrying the message's `msg-num`
+//! and a [`ProtocolError`](arguments/struct.ProtocolError.html), which holds a typed
+//! [`ErrorCode`](arguments/struct.ErrorCode.html) and a human readable description. Errors about
+//! chunk requests are sent on the request's stream in place of the chunk, everything else is
+//! answered on the control stream. The connection stays usable after an error.
 //!
 //! ## Examples
 //!
-//! Conversion between the two could look like this:
+//! Encoding a message could look like this:
 //! ```rust
-//! let mut msg: Message = Message {
-//!     id: 0,
-//!     verb: Directive::SendFile,
-//!     data: Some(Data::new(&vec![1, 2, 3])),
-//! };
-//! assert_eq!(Vec::from(msg), vec!(0, 0, 0, 3, 0, 0, 0, 3, 1, 2, 3),);
+//! let mut builder = MessageBuilder::new(PROTOCOL_VERSION);
+//! let msg = builder
+//!     .encode_message(Message::DeleteFile(FilePath("file".into())))
+//!     .unwrap();
+//! assert_eq!(msg, vec![0, 1, 0, 0, 0, 8, b'f', b'i', b'l', b'e']);
 //! ```
 //!
 //! And back:
 //! ```
-//! let mut msg: &[u8] = &[0u8, 0u8, 0u8, 3u8, 0u8, 0u8, 0u8, 3u8, 1u8, 2u8, 3u8][..];
+//! let envelope = builder.decode_message(&msg).unwrap();
+//! assert_eq!(envelope.id, 1);
 //! assert_eq!(
-//!     Message::from(msg),
-//!     Message {
-//!         id: 0,
-//!         verb: Directive::SendFile,
-//!         data: Some(Data::new(&vec![1, 2, 3])),
-//!     }
+//!     envelope.message,
+//!     Message::DeleteFile(FilePath("file".into()))
 //! );
 //! ```
 
@@ -55,14 +119,32 @@
 
 pub mod arguments;
 pub mod error;
+pub mod mux;
 
-use arguments::{Argument, Version};
+use arguments::{
+    Argument, Capabilities, Chunk, ChunkList, Credit, FileId, FileList, FileMetadata, FilePath,
+    MetadataList, ProtocolError, QualifiedChunk, QualifiedChunkId, Rename, ResponseCode, Version,
+};
 
 use self::error::MessageError;
 
-//struct Version(u8);
-
-static NOISE_MAX_LENGTH: usize = 65535;
+/// Protocol version spoken by this side
+pub const PROTOCOL_VERSION: u8 = 3;
+/// Oldest protocol version accepted from peers. Newer layouts of the arguments are gated on
+/// capabilities, so older peers can still be talked to.
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+/// Optional features supported by this side
+pub const CAPABILITIES: Capabilities = Capabilities(
+    Capabilities::LARGE_OFFSETS.0
+        | Capabilities::RENAME.0
+        | Capabilities::ENTRY_KINDS.0
+        | Capabilities::SYMLINKS.0
+        | Capabilities::EXTENDED.0
+        | Capabilities::BATCH.0
+        | Capabilities::COMPRESSION.0,
+);
+/// Most chunks requested by a `RequestChunks` or files sent by a `SendFileBatch`
+pub const MAX_BATCH_LEN: usize = 128;
 
 /// Defines the different available protocol verbs/directives.
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -77,13 +159,26 @@ pub enum Directive {
     SendQualifiedChunk,
     DeleteFile,
     Response,
+    /// Liveness check, answered with a `Pong`
+    Ping,
+    Pong,
+    /// Flow control credit for the message's stream
+    Credit,
+    /// A message couldn't be handled
+    Error,
+    /// Move a file or directory, keeping its chunks. Only used with the `RENAME` capability.
+    RenameFile,
+    /// Request several chunks at once. Only used with the `BATCH` capability.
+    RequestChunks,
+    /// Send the metadata of several files at once. Only used with the `BATCH` capability.
+    SendFileBatch,
 }
 
 /// Covert from u16 to Directive.
 /// This should proably be handled better in the future
 impl TryFrom<u16> for Directive {
     type Error = &'static str;
-    fn try_from(num: u16) -> Result<Self, Self::Error> {
+    fn try_from(num: u16) -> Result<Self, &'static str> {
         // TODO: This logic seems verbose
         match num {
             0 => Ok(Directive::AnnounceVersion),
@@ -96,22 +191,143 @@ impl TryFrom<u16> for Directive {
             7 => Ok(Directive::SendQualifiedChunk),
             8 => Ok(Directive::DeleteFile),
             9 => Ok(Directive::Response),
+            10 => Ok(Directive::Ping),
+            11 => Ok(Directive::Pong),
+            12 => Ok(Directive::Credit),
+            13 => Ok(Directive::Error),
+            14 => Ok(Directive::RenameFile),
+            15 => Ok(Directive::RequestChunks),
+            16 => Ok(Directive::SendFileBatch),
             _ => Err("Failed to convert Directive"),
         }
     }
 }
 
 /// This is the main structure for every message sent over the network.
-#[derive(Debug)]
-pub struct Message {
+///
+/// Every directive is a variant holding the directive's argument.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Message {
+    AnnounceVersion(Version),
+    ListFiles,
+    SendFiles(FileList),
+    RequestFile(FileId),
+    RequestChunk(QualifiedChunkId),
+    SendFile(FileMetadata),
+    SendChunk(Chunk),
+    SendQualifiedChunk(QualifiedChunk),
+    DeleteFile(FilePath),
+    Response(ResponseCode),
+    Ping,
+    Pong,
+    Credit(Credit),
+    Error(ProtocolError),
+    RenameFile(Rename),
+    RequestChunks(ChunkList),
+    SendFileBatch(MetadataList),
+}
+
+impl Message {
+    /// Directive the message is sent with
+    pub fn directive(&self) -> Directive {
+        match self {
+            Message::AnnounceVersion(_) => Directive::AnnounceVersion,
+            Message::ListFiles => Directive::ListFiles,
+            Message::SendFiles(_) => Directive::SendFiles,
+            Message::RequestFile(_) => Directive::RequestFile,
+            Message::RequestChunk(_) => Directive::RequestChunk,
+            Message::SendFile(_) => Directive::SendFile,
+            Message::SendChunk(_) => Directive::SendChunk,
+            Message::SendQualifiedChunk(_) => Directive::SendQualifiedChunk,
+            Message::DeleteFile(_) => Directive::DeleteFile,
+            Message::Response(_) => Directive::Response,
+            Message::Ping => Directive::Ping,
+            Message::Pong => Directive::Pong,
+            Message::Credit(_) => Directive::Credit,
+            Message::Error(_) => Directive::Error,
+            Message::RenameFile(_) => Directive::RenameFile,
+            Message::RequestChunks(_) => Directive::RequestChunks,
+            Message::SendFileBatch(_) => Directive::SendFileBatch,
+        }
+    }
+
+    /// Binary form of the message's argument for a peer supporting `capabilities`, if it has one
+    fn argument(&self, capabilities: Capabilities) -> Result<Option<Vec<u8>>, MessageError> {
+        Ok(match self {
+            Message::AnnounceVersion(x) => Some(x.to_bin()),
+            Message::SendFiles(x) => Some(x.to_bin()),
+            Message::RequestFile(x) => Some(x.to_bin()),
+            Message::RequestChunk(x) => Some(x.to_bin_for(capabilities)?),
+            Message::SendFile(x) => Some(x.to_bin_for(capabilities)),
+            Message::SendChunk(x) => Some(x.to_bin()),
+            Message::SendQualifiedChunk(x) => Some(x.to_bin_for(capabilities)?),
+            Message::DeleteFile(x) => Some(x.to_bin()),
+            Message::Response(x) => Some(x.to_bin()),
+            Message::Credit(x) => Some(x.to_bin()),
+            Message::Error(x) => Some(x.to_bin()),
+            Message::RenameFile(x) => Some(x.to_bin()),
+            Message::RequestChunks(x) => Some(x.to_bin_for(capabilities)?),
+            Message::SendFileBatch(x) => Some(x.to_bin_for(capabilities)),
+            Message::ListFiles | Message::Ping | Message::Pong => None,
+        })
+    }
+
+    /// Decode the argument of a `verb` message sent by a peer supporting `capabilities`
+    fn decode(
+        verb: Directive,
+        data: &[u8],
+        capabilities: Capabilities,
+    ) -> Result<Message, MessageError> {
+        Ok(match verb {
+            Directive::AnnounceVersion => Message::AnnounceVersion(Version::from_bin(data)?),
+            Directive::ListFiles => Message::ListFiles,
+            Directive::SendFiles => Message::SendFiles(FileList::from_bin(data)?),
+            Directive::RequestFile => Message::RequestFile(FileId::from_bin(data)?),
+            Directive::RequestChunk => {
+                Message::RequestChunk(QualifiedChunkId::from_bin_for(data, capabilities)?)
+            }
+            Directive::SendFile => {
+                Message::SendFile(FileMetadata::from_bin_for(data, capabilities)?)
+            }
+            Directive::SendChunk => Message::SendChunk(Chunk::from_bin(data)?),
+            Directive::SendQualifiedChunk => {
+                Message::SendQualifiedChunk(QualifiedChunk::from_bin_for(data, capabilities)?)
+            }
+            Directive::DeleteFile => Message::DeleteFile(FilePath::from_bin(data)?),
+            Directive::Response => Message::Response(ResponseCode::from_bin(data)?),
+            Directive::Ping => Message::Ping,
+            Directive::Pong => Message::Pong,
+            Directive::Credit => Message::Credit(Credit::from_bin(data)?),
+            Directive::Error => Message::Error(ProtocolError::from_bin(data)?),
+            Directive::RenameFile => Message::RenameFile(Rename::from_bin(data)?),
+            // Batches aren't part of the protocol unless both sides support them
+            Directive::RequestChunks | Directive::SendFileBatch
+                if !capabilities.contains(Capabilities::BATCH) =>
+            {
+                return Err(MessageError::UnknownDirective(verb as u16))
+            }
+            Directive::RequestChunks => {
+                Message::RequestChunks(ChunkList::from_bin_for(data, capabilities)?)
+            }
+            Directive::SendFileBatch => {
+                Message::SendFileBatch(MetadataList::from_bin_for(data, capabilities)?)
+            }
+        })
+    }
+}
+
+/// A received message along with its header
+#[derive(Debug, PartialEq, Eq)]
+pub struct Envelope {
     pub id: u16,
-    pub verb: Directive,
-    pub argument: Option<Box<dyn Argument>>,
+    pub stream: u16,
+    pub message: Message,
 }
 
 #[derive(PartialEq, Debug)]
 struct RawMessage {
     id: u16,
+    stream: u16,
     verb: Directive,
     data: Option<Vec<u8>>,
 }
@@ -123,6 +339,9 @@ impl From<RawMessage> for Vec<u8> {
         // Add the message id
         buffer.extend(msg.id.to_be_bytes());
 
+        // Add the stream id
+        buffer.extend(msg.stream.to_be_bytes());
+
         // Add the directive
         buffer.extend((msg.verb as u16).to_be_bytes());
 
@@ -135,8 +354,16 @@ impl From<RawMessage> for Vec<u8> {
     }
 }
 
-impl From<&[u8]> for RawMessage {
-    fn from(msg: &[u8]) -> RawMessage {
+impl TryFrom<&[u8]> for RawMessage {
+    type Error = MessageError;
+    fn try_from(msg: &[u8]) -> Result<RawMessage, MessageError> {
+        if msg.len() < 6 {
+            return Err(MessageError::Truncated {
+                needed: 6,
+                available: msg.len(),
+            });
+        }
+
         // Two byte buffer will be used to create be arrays
         let mut buf: [u8; 2] = [0u8; 2];
 
@@ -144,103 +371,204 @@ impl From<&[u8]> for RawMessage {
         buf.copy_from_slice(&msg[0..2]);
         let id: u16 = u16::from_be_bytes(buf);
 
-        // Deserialize the derective
+        // Deserialize the stream id
         buf.copy_from_slice(&msg[2..4]);
-        let verb: Directive = match u16::from_be_bytes(buf).try_into() {
-            Ok(x) => x,
-            Err(x) => panic!("{}", x),
-        };
+        let stream: u16 = u16::from_be_bytes(buf);
+
+        // Deserialize the derective
+        buf.copy_from_slice(&msg[4..6]);
+        let verb = u16::from_be_bytes(buf);
+        let verb: Directive = verb
+            .try_into()
+            .map_err(|_| MessageError::UnknownDirective(verb))?;
 
         // Add the data
         let data: Option<Vec<u8>> = match msg.len() {
-            0..=4 => None,
-            _ => Some(msg[4..].to_vec()),
+            0..=6 => None,
+            _ => Some(msg[6..].to_vec()),
         };
 
-        RawMessage { id, verb, data }
+        Ok(RawMessage {
+            id,
+            stream,
+            verb,
+            data,
+        })
     }
 }
 
+/// Message id carried by every `Credit` message. Other messages never use it.
+pub const CREDIT_ID: u16 = 0;
+
 /// Build messages according to the <insert_protocol_name_here> protocol.
 ///
 /// A MessageBuilder will be created for each connection. It's main goal is to keep track of the
 /// current MessageId and encode/decode message packets
 pub struct MessageBuilder {
-    protocol_version: Version,
+    protocol_version: u8,
+    /// Optional features announced to the peer. Once negotiated, the ones supported by both sides,
+    /// which decide the binary layout of the arguments.
+    capabilities: Capabilities,
     current_request: u16,
 }
 
 impl MessageBuilder {
     pub fn new(ver: u8) -> MessageBuilder {
         MessageBuilder {
-            protocol_version: Version(ver),
-            current_request: 0,
+            protocol_version: ver,
+            capabilities: CAPABILITIES,
+            current_request: CREDIT_ID + 1,
         }
     }
 
-    /// Encode a message from language constructs to a binary packet format
-    pub fn encode_message<T>(&mut self, verb: Directive, argument: Option<T>) -> Vec<u8>
-    where
-        T: Argument,
-    {
-        // Encode message arguments
-        let encoded_data = argument.map(|x| x.to_bin());
+    /// Announce only `capabilities`, e.g. when this side can't make use of some of them
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
 
+    /// Encode the announcement of our protocol version and capabilities, which has to be the
+    /// first message sent on a connection
+    pub fn announce_version(&mut self) -> Vec<u8> {
+        let version = Version {
+            version: self.protocol_version,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: self.capabilities,
+        };
         let msg = RawMessage {
             id: self.current_request,
-            verb,
-            data: encoded_data,
+            stream: mux::CONTROL_STREAM,
+            verb: Directive::AnnounceVersion,
+            data: Some(version.to_bin()),
+        };
+        self.increment_counter();
+        msg.into()
+    }
+
+    /// Check the first message received from the peer, which has to be its version
+    /// announcement.
+    ///
+    /// Returns the capabilities supported by both sides, which are used for every message encoded
+    /// and decoded from then on.
+    pub fn negotiate(&mut self, message: &[u8]) -> Result<Capabilities, MessageError> {
+        // Older peers might not even share the message header, so check it before decoding
+        let verb = (Directive::AnnounceVersion as u16).to_be_bytes();
+        if message.len() <= 6 || message[4..6] != verb {
+            return Err(MessageError::MissingVersion);
+        }
+        let peer = match self.decode_message(message)?.message {
+            Message::AnnounceVersion(peer) => peer,
+            _ => return Err(MessageError::MissingVersion),
+        };
+
+        if peer.version < MIN_PROTOCOL_VERSION {
+            return Err(MessageError::VersionTooOld {
+                version: peer.version,
+                min_version: MIN_PROTOCOL_VERSION,
+            });
+        }
+        if self.protocol_version < peer.min_version {
+            return Err(MessageError::VersionRejected {
+                version: self.protocol_version,
+                min_version: peer.min_version,
+            });
+        }
+        self.capabilities = self.capabilities.intersection(peer.capabilities);
+        Ok(self.capabilities)
+    }
+
+    /// Encode a message from language constructs to a binary packet format.
+    ///
+    /// The message is sent on the control stream.
+    pub fn encode_message(&mut self, message: Message) -> Result<Vec<u8>, MessageError> {
+        self.encode_stream_message(mux::CONTROL_STREAM, message)
+    }
+
+    /// Encode a message belonging to a specific stream.
+    ///
+    /// Fails when the message can't be expressed with the negotiated capabilities, like a chunk
+    /// past 4 GiB without `LARGE_OFFSETS`. No id is used up then.
+    pub fn encode_stream_message(
+        &mut self,
+        stream: u16,
+        message: Message,
+    ) -> Result<Vec<u8>, MessageError> {
+        let msg = RawMessage {
+            id: self.current_request,
+            stream,
+            verb: message.directive(),
+            data: message.argument(self.capabilities)?,
         };
 
-        self.current_request += 1;
+        self.increment_counter();
 
-        let raw_bytes: Vec<u8> = msg.into();
+        // Messages larger than a single Noise frame are fragmented by the `net` layer
+        Ok(msg.into())
+    }
 
-        if raw_bytes.len() > NOISE_MAX_LENGTH {
-            error!("Noise message length error");
-            error!("Length: {} (allowed {NOISE_MAX_LENGTH})", raw_bytes.len());
-            error!("File {} times too big", raw_bytes.len() / NOISE_MAX_LENGTH);
-            panic!();
+    /// Encode the credit handed back for requests answered on `stream`
+    pub fn encode_credit(stream: u16, credit: u32) -> Vec<u8> {
+        RawMessage {
+            id: CREDIT_ID,
+            stream,
+            verb: Directive::Credit,
+            data: Some(Credit(credit).to_bin()),
         }
+        .into()
+    }
 
-        raw_bytes
+    /// Encode the response to the request `id` received from the peer.
+    ///
+    /// Responses carry the id of the request instead of a new one and always go on the control
+    /// stream.
+    pub fn encode_response(&self, id: u16, code: ResponseCode) -> Vec<u8> {
+        RawMessage {
+            id,
+            stream: mux::CONTROL_STREAM,
+            verb: Directive::Response,
+            data: Some(code.to_bin()),
+        }
+        .into()
     }
 
-    pub fn decode_message(message: &[u8]) -> Result<Box<Message>, MessageError> {
-        let msg = RawMessage::from(message);
-
-        let mut arg: Option<Box<dyn Argument>> = None;
-
-        if let Some(x) = msg.data {
-            // It's best to keep this match verbose. If directives are added in the future, the
-            // exhaustive match will force us to handle its argument type here.
-            arg = match msg.verb {
-                Directive::AnnounceVersion => Some(Box::new(arguments::Version::from_bin(&x)?)),
-                Directive::ListFiles => None,
-                Directive::SendFiles => Some(Box::new(arguments::FileList::from_bin(&x)?)),
-                Directive::RequestFile => Some(Box::new(arguments::FileId::from_bin(&x)?)),
-                Directive::RequestChunk => {
-                    Some(Box::new(arguments::QualifiedChunkId::from_bin(&x)?))
-                }
-                Directive::SendFile => Some(Box::new(arguments::FileMetadata::from_bin(&x)?)),
-                Directive::SendChunk => Some(Box::new(arguments::Chunk::from_bin(&x)?)),
-                Directive::SendQualifiedChunk => {
-                    Some(Box::new(arguments::QualifiedChunk::from_bin(&x)?))
-                }
-                Directive::DeleteFile => Some(Box::new(arguments::FilePath::from_bin(&x)?)),
-                Directive::Response => Some(Box::new(arguments::ResponseCode::from_bin(&x)?)),
-            };
+    /// Encode the error answering the message `id` received from the peer on `stream`.
+    ///
+    /// Like responses, errors carry the id of the message they answer.
+    pub fn encode_error(&self, id: u16, stream: u16, error: &ProtocolError) -> Vec<u8> {
+        RawMessage {
+            id,
+            stream,
+            verb: Directive::Error,
+            data: Some(error.to_bin()),
         }
+        .into()
+    }
 
-        Ok(Box::new(Message {
+    /// Id of the next message encoded by this builder
+    pub fn next_id(&self) -> u16 {
+        self.current_request
+    }
+
+    /// Decode a message received from the peer.
+    ///
+    /// Arguments are checked against the message's directive, so a message with a missing or
+    /// malformed argument is an error.
+    pub fn decode_message(&self, message: &[u8]) -> Result<Envelope, MessageError> {
+        let msg = RawMessage::try_from(message)?;
+        let data = msg.data.unwrap_or_default();
+
+        Ok(Envelope {
             id: msg.id,
-            verb: msg.verb,
-            argument: arg,
-        }))
+            stream: msg.stream,
+            message: Message::decode(msg.verb, &data, self.capabilities)?,
+        })
     }
 
     pub fn increment_counter(&mut self) {
-        self.current_request += 1;
+        self.current_request = self.current_request.wrapping_add(1);
+        // The credit id is skipped when the counter wraps around
+        if self.current_request == CREDIT_ID {
+            self.current_request += 1;
+        }
     }
 }
 
@@ -252,29 +580,344 @@ mod tests {
     fn test_msg_ser() {
         let mut msg: RawMessage = RawMessage {
             id: 0,
+            stream: 0,
             verb: Directive::SendFile,
             data: Some(vec![1, 2, 3]),
         };
-        assert_eq!(Vec::from(msg), vec!(0, 0, 0, 5, 1, 2, 3),);
+        assert_eq!(Vec::from(msg), vec!(0, 0, 0, 0, 0, 5, 1, 2, 3),);
         msg = RawMessage {
             id: 1,
+            stream: 3,
             verb: Directive::ListFiles,
             data: None,
         };
-        assert_eq!(Vec::from(msg), vec!(0, 1, 0, 1));
+        assert_eq!(Vec::from(msg), vec!(0, 1, 0, 3, 0, 1));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut builder = MessageBuilder::new(PROTOCOL_VERSION);
+        let announcement = builder.announce_version();
+        assert_eq!(builder.negotiate(&announcement).unwrap(), CAPABILITIES);
+
+        // Capabilities only the peer supports aren't used
+        let peer = Version {
+            version: PROTOCOL_VERSION + 1,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities(u32::MAX),
+        };
+        let msg = builder
+            .encode_message(Message::AnnounceVersion(peer))
+            .unwrap();
+        assert_eq!(builder.negotiate(&msg).unwrap(), CAPABILITIES);
+
+        // Neither are the ones this side leaves out
+        builder.set_capabilities(Capabilities::RENAME);
+        assert_eq!(builder.negotiate(&msg).unwrap(), Capabilities::RENAME);
+        builder.set_capabilities(CAPABILITIES);
+
+        // Peers from before the layout changes keep the old layout
+        let peer = builder
+            .encode_message(Message::AnnounceVersion(Version {
+                version: 2,
+                min_version: 2,
+                capabilities: Capabilities(0),
+            }))
+            .unwrap();
+        assert_eq!(builder.negotiate(&peer).unwrap(), Capabilities(0));
+
+        let old = builder
+            .encode_message(Message::AnnounceVersion(Version {
+                version: MIN_PROTOCOL_VERSION - 1,
+                min_version: MIN_PROTOCOL_VERSION - 1,
+                capabilities: Capabilities(0),
+            }))
+            .unwrap();
+        assert!(matches!(
+            builder.negotiate(&old),
+            Err(MessageError::VersionTooOld { .. })
+        ));
+
+        let new = builder
+            .encode_message(Message::AnnounceVersion(Version {
+                version: PROTOCOL_VERSION + 1,
+                min_version: PROTOCOL_VERSION + 1,
+                capabilities: Capabilities(0),
+            }))
+            .unwrap();
+        assert!(matches!(
+            builder.negotiate(&new),
+            Err(MessageError::VersionRejected { .. })
+        ));
+
+        // Peers that never announce their version, or use an older header
+        let list = builder.encode_message(Message::ListFiles).unwrap();
+        assert!(matches!(
+            builder.negotiate(&list),
+            Err(MessageError::MissingVersion)
+        ));
+        assert!(matches!(
+            builder.negotiate(&[0, 1, 0, 1]),
+            Err(MessageError::MissingVersion)
+        ));
+    }
+
+    #[test]
+    fn test_response() {
+        let mut client = MessageBuilder::new(PROTOCOL_VERSION);
+        let server = MessageBuilder::new(PROTOCOL_VERSION);
+        client.encode_message(Message::ListFiles).unwrap();
+
+        let id = client.next_id();
+        let request = client.encode_message(Message::ListFiles).unwrap();
+        assert_eq!(server.decode_message(&request).unwrap().id, id);
+
+        let response = server.encode_response(id, ResponseCode::OK);
+        let response = client.decode_message(&response).unwrap();
+        assert_eq!(response.id, id);
+        assert_eq!(response.message, Message::Response(ResponseCode::OK));
+
+        let error = ProtocolError::new(arguments::ErrorCode::NOT_FOUND, "no such file");
+        let response = server.encode_error(id, 3, &error);
+        let response = client.decode_message(&response).unwrap();
+        assert_eq!(response.id, id);
+        assert_eq!(response.stream, 3);
+        assert_eq!(response.message, Message::Error(error));
+    }
+
+    #[test]
+    fn test_encode_large_offset() {
+        let mut builder = MessageBuilder::new(PROTOCOL_VERSION);
+        builder.set_capabilities(Capabilities(0));
+        let id = builder.next_id();
+        let request = Message::RequestChunk(QualifiedChunkId {
+            path: FileId {
+                path: "file".into(),
+                hash: [1u8; 32],
+            },
+            offset: 1 << 32,
+            id: arguments::ChunkId([2u8; 32].to_vec()),
+        });
+        assert!(matches!(
+            builder.encode_stream_message(1, request),
+            Err(MessageError::OffsetTooLarge(_))
+        ));
+        assert_eq!(builder.next_id(), id);
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let mut builder = MessageBuilder::new(PROTOCOL_VERSION);
+        let messages = vec![
+            Message::ListFiles,
+            Message::SendFiles(FileList(vec![])),
+            Message::DeleteFile(FilePath("dir/file".into())),
+            Message::RenameFile(Rename {
+                from: "dir".into(),
+                to: "other/dir".into(),
+            }),
+            Message::Credit(Credit(3)),
+            Message::Ping,
+            Message::RequestChunks(ChunkList(vec![
+                QualifiedChunkId {
+                    path: FileId {
+                        path: "dir/file".into(),
+                        hash: [1u8; 32],
+                    },
+                    offset: 0,
+                    id: arguments::ChunkId([2u8; 32].to_vec()),
+                };
+                2
+            ])),
+        ];
+        for message in messages {
+            let id = builder.next_id();
+            let encoded = builder.encode_stream_message(5, message.clone()).unwrap();
+            assert_eq!(
+                builder.decode_message(&encoded).unwrap(),
+                Envelope {
+                    id,
+                    stream: 5,
+                    message
+                }
+            );
+        }
+
+        // Directives that need an argument can't go without one
+        let response = RawMessage {
+            id: 0,
+            stream: 0,
+            verb: Directive::Response,
+            data: None,
+        };
+        assert!(matches!(
+            builder.decode_message(&Vec::from(response)),
+            Err(MessageError::BadLength { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let builder = MessageBuilder::new(PROTOCOL_VERSION);
+        assert!(matches!(
+            builder.decode_message(&[0, 1, 0]),
+            Err(MessageError::Truncated {
+                needed: 6,
+                available: 3
+            })
+        ));
+        assert!(matches!(
+            builder.decode_message(&[0, 1, 0, 0, 0xff, 0xff]),
+            Err(MessageError::UnknownDirective(0xffff))
+        ));
+
+        // Batches can only be sent once both sides support them
+        let mut builder = MessageBuilder::new(PROTOCOL_VERSION);
+        let batch = builder
+            .encode_message(Message::RequestChunks(ChunkList(vec![QualifiedChunkId {
+                path: FileId {
+                    path: "dir/file".into(),
+                    hash: [1u8; 32],
+                },
+                offset: 0,
+                id: arguments::ChunkId([2u8; 32].to_vec()),
+            }])))
+            .unwrap();
+        assert!(builder.decode_message(&batch).is_ok());
+        builder.set_capabilities(Capabilities(CAPABILITIES.0 & !Capabilities::BATCH.0));
+        assert!(matches!(
+            builder.decode_message(&batch),
+            Err(MessageError::UnknownDirective(15))
+        ));
+    }
+
+    /// Decode random and corrupted messages of every directive. Decoding has to fail cleanly or
+    /// succeed, but never panic.
+    #[test]
+    fn test_decode_fuzz() {
+        use arguments::*;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::path::PathBuf;
+
+        // Seeded so failures can be reproduced
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let file_id = FileId {
+            path: PathBuf::from("dir/file"),
+            hash: [7u8; 32],
+        };
+        let chunk_id = QualifiedChunkId {
+            path: file_id.clone(),
+            offset: 42,
+            id: ChunkId([1u8; 32].to_vec()),
+        };
+        let metadata = FileMetadata {
+            file_id: file_id.clone(),
+            file_name: "file".into(),
+            permissions: 0o644,
+            modified: 1,
+            created: 2,
+            size: 3,
+            chunks: vec![ChunkId([1u8; 32].to_vec())],
+            kind: EntryKind::File,
+            extended: ExtendedMetadata::default(),
+        };
+        let mut builder = MessageBuilder::new(PROTOCOL_VERSION);
+        let valid = vec![
+            builder.announce_version(),
+            builder
+                .encode_message(Message::SendFiles(FileList(vec![file_id.clone()])))
+                .unwrap(),
+            builder
+                .encode_message(Message::RequestFile(file_id.clone()))
+                .unwrap(),
+            builder
+                .encode_message(Message::RequestChunk(chunk_id.clone()))
+                .unwrap(),
+            builder
+                .encode_message(Message::SendFile(metadata.clone()))
+                .unwrap(),
+            builder
+                .encode_message(Message::SendFileBatch(MetadataList(vec![
+                    metadata.clone(),
+                    metadata,
+                ])))
+                .unwrap(),
+            builder
+                .encode_message(Message::RequestChunks(ChunkList(vec![
+                    chunk_id.clone(),
+                    chunk_id.clone(),
+                ])))
+                .unwrap(),
+            builder
+                .encode_message(Message::SendChunk(Chunk {
+                    id: ChunkId([1u8; 32].to_vec()),
+                    data: vec![1, 2, 3],
+                }))
+                .unwrap(),
+            builder
+                .encode_message(Message::SendQualifiedChunk(QualifiedChunk {
+                    id: chunk_id,
+                    data: vec![1, 2, 3],
+                }))
+                .unwrap(),
+            builder
+                .encode_message(Message::DeleteFile(FilePath("file".into())))
+                .unwrap(),
+            builder
+                .encode_message(Message::RenameFile(Rename {
+                    from: "file".into(),
+                    to: "dir/file".into(),
+                }))
+                .unwrap(),
+            builder.encode_response(0, ResponseCode::OK),
+            builder.encode_message(Message::Credit(Credit(1))).unwrap(),
+            builder.encode_error(0, 0, &ProtocolError::new(ErrorCode::NOT_FOUND, "gone")),
+        ];
+
+        for msg in &valid {
+            assert!(builder.decode_message(msg).is_ok());
+            // Every truncation
+            for len in 0..msg.len() {
+                let _ = builder.decode_message(&msg[..len]);
+            }
+            // Random corruptions, which mostly hit the length prefixes and the verb
+            for _ in 0..500 {
+                let mut corrupted = msg.clone();
+                for _ in 0..rng.gen_range(1..4) {
+                    let i = rng.gen_range(0..corrupted.len());
+                    corrupted[i] = rng.gen();
+                }
+                corrupted.truncate(rng.gen_range(0..=corrupted.len()));
+                let _ = builder.decode_message(&corrupted);
+                let _ = MessageBuilder::new(PROTOCOL_VERSION).negotiate(&corrupted);
+            }
+        }
+
+        // Random arguments for every verb, including unknown ones
+        for _ in 0..5000 {
+            let mut msg = vec![0u8, 0, 0, 0, 0, rng.gen_range(0..18)];
+            let len = rng.gen_range(0..128);
+            msg.extend((0..len).map(|_| rng.gen::<u8>()));
+            let _ = builder.decode_message(&msg);
+        }
     }
 
     #[test]
     fn test_msg_de() {
-        let mut msg_raw: &[u8] = &[0u8, 0u8, 0u8, 0u8, 1u8][..];
+        let mut msg_raw: &[u8] = &[0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 1u8][..];
         let mut msg = RawMessage {
             id: 0,
+            stream: 0,
             verb: Directive::AnnounceVersion,
             data: Some(vec![1]),
         };
-        assert_eq!(RawMessage::from(msg_raw), msg,);
-        msg_raw = &[1u8, 0u8, 0u8, 0u8, 1u8];
+        assert_eq!(RawMessage::try_from(msg_raw).unwrap(), msg,);
+        msg_raw = &[1u8, 0u8, 0u8, 0u8, 0u8, 0u8, 1u8];
         msg.id += 256;
-        assert_eq!(RawMessage::from(msg_raw), msg,);
+        assert_eq!(RawMessage::try_from(msg_raw).unwrap(), msg,);
+        msg_raw = &[0u8, 0u8, 1u8, 1u8, 0u8, 0u8, 1u8];
+        msg.id = 0;
+        msg.stream = 257;
+        assert_eq!(RawMessage::try_from(msg_raw).unwrap(), msg,);
     }
 }
@@ -0,0 +1,472 @@
+//! Multiplexing of outgoing messages over logical streams.
+//!
+//! Every message is handed to a [`MuxSender`](struct.MuxSender.html) along with its stream, and a
+//! background task writes them to the connection in the following order:
+//!
+//! 1. Messages on the [control stream](constant.CONTROL_STREAM.html), including flow control
+//!    credits. These don't use credit.
+//! 2. Messages on the other streams, taking turns between streams. A request is held back until
+//!    the stream has credit left.
+//!
+//! Priorities only apply between whole messages, so a control message waits at most for the chunk
+//! currently being written.
+//!
+//! The receiving side holds the peer to the same credit: every request received on a stream opened
+//! by the peer has to be passed to [`accept_request`](struct.MuxSender.html#method.accept_request),
+//! which fails once the peer has more than [`INITIAL_CREDIT`](constant.INITIAL_CREDIT.html)
+//! unanswered requests on the stream. Credit handed back for requests that were never sent is
+//! ignored.
+//!
+//! Messages received on the control stream go through `accept_request` as well. Answers to them
+//! are queued with [`answer`](struct.MuxSender.html#method.answer) or
+//! [`answer_part`](struct.MuxSender.html#method.answer_part), and once
+//! [`CONTROL_BACKLOG`](constant.CONTROL_BACKLOG.html) of those wait to be written, the peer isn't
+//! reading what it asks for and its next control message is refused.
+//!
+//! Together these bound what a peer can pile up in the writer's queue. Requests and pushes this
+//! side queues on its own aren't bounded, it's up to the caller not to queue more than it means
+//! to.
+
+use super::MessageBuilder;
+use crate::net::{error::NetError, NoiseSender};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{Arc, Mutex},
+};
+use tokio::{io::AsyncWrite, sync::mpsc};
+
+/// Stream carrying everything but chunk transfers
+pub const CONTROL_STREAM: u16 = 0;
+/// Number of requests a side can have outstanding on a stream
+pub const INITIAL_CREDIT: u32 = 4;
+/// Number of answers on the control stream that can wait to be written before the peer's control
+/// messages are refused
+pub const CONTROL_BACKLOG: u32 = 1024;
+
+enum Command {
+    /// A request, or any message on the control stream
+    Request(u16, Vec<u8>),
+    /// The answer to a request received on a stream
+    Answer(u16, Vec<u8>),
+    /// Part of an answer that's followed by more
+    Part(u16, Vec<u8>),
+    /// Credit handed back by the peer
+    Credit(u16, u32),
+    /// Whether to compress the messages written from now on
+    Compression(bool),
+}
+
+enum Outgoing {
+    Request(Vec<u8>),
+    Answer(Vec<u8>),
+    Part(Vec<u8>),
+}
+
+/// Requests that are waiting for an answer, on both sides of the connection
+#[derive(Default)]
+struct Outstanding {
+    /// Requests received from the peer on each stream that haven't been answered yet
+    inbound: HashMap<u16, u32>,
+    /// Requests queued on each stream that the peer hasn't handed credit back for yet
+    outbound: HashMap<u16, u32>,
+    /// Answers queued on the control stream that aren't written yet
+    control: u32,
+}
+
+/// Handle used to queue messages for the connection's writer task.
+///
+/// Queueing never waits. Requests on a stream without credit stay queued until the peer hands
+/// credit back, which bounds the number of answers the peer has to buffer.
+#[derive(Clone)]
+pub struct MuxSender {
+    tx: mpsc::UnboundedSender<Command>,
+    outstanding: Arc<Mutex<Outstanding>>,
+}
+
+impl MuxSender {
+    /// Start writing messages to `sender` in the background.
+    ///
+    /// The task stops once every `MuxSender` is dropped or writing to the connection fails.
+    pub fn spawn<W: AsyncWrite + Unpin + Send + 'static>(sender: NoiseSender<W>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let outstanding: Arc<Mutex<Outstanding>> = Arc::default();
+        let writer = Writer {
+            sender,
+            outstanding: outstanding.clone(),
+            control: VecDeque::new(),
+            streams: HashMap::new(),
+            ready: VecDeque::new(),
+        };
+        tokio::spawn(writer.run(rx));
+        MuxSender { tx, outstanding }
+    }
+
+    /// Queue a message. Messages on streams other than the control stream are requests and use up
+    /// one credit of the stream.
+    pub fn send(&self, stream: u16, msg: Vec<u8>) -> Result<(), NetError> {
+        if stream != CONTROL_STREAM {
+            let mut outstanding = self.outstanding.lock().unwrap();
+            *outstanding.outbound.entry(stream).or_default() += 1;
+        }
+        self.command(Command::Request(stream, msg))
+    }
+
+    /// Account for a request received from the peer on `stream`, which has to be answered with
+    /// [`answer`](#method.answer).
+    ///
+    /// Fails when the peer already had [`INITIAL_CREDIT`](constant.INITIAL_CREDIT.html) requests
+    /// outstanding on the stream. Messages on the control stream fail once
+    /// [`CONTROL_BACKLOG`](constant.CONTROL_BACKLOG.html) answers wait to be written. The
+    /// connection should be dropped then.
+    pub fn accept_request(&self, stream: u16) -> Result<(), NetError> {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        if stream == CONTROL_STREAM {
+            if outstanding.control >= CONTROL_BACKLOG {
+                return Err(NetError::CreditExceeded(stream));
+            }
+            return Ok(());
+        }
+        let count = outstanding.inbound.entry(stream).or_default();
+        if *count >= INITIAL_CREDIT {
+            return Err(NetError::CreditExceeded(stream));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    /// Queue the answer to a request received on `stream`.
+    ///
+    /// The request's credit is handed back to the peer once the answer has been written.
+    pub fn answer(&self, stream: u16, msg: Vec<u8>) -> Result<(), NetError> {
+        self.release(stream);
+        self.command(Command::Answer(stream, msg))
+    }
+
+    /// Queue part of the answer to a batch request received on `stream`. The request's credit is
+    /// only handed back by the last part, queued with [`answer`](#method.answer).
+    pub fn answer_part(&self, stream: u16, msg: Vec<u8>) -> Result<(), NetError> {
+        if stream == CONTROL_STREAM {
+            self.outstanding.lock().unwrap().control += 1;
+        }
+        self.command(Command::Part(stream, msg))
+    }
+
+    /// Add the credit handed back by the peer with a `Credit` message.
+    ///
+    /// Only credit for requests that were actually sent on the stream is added.
+    pub fn add_credit(&self, stream: u16, credit: u32) -> Result<(), NetError> {
+        let credit = {
+            let mut outstanding = self.outstanding.lock().unwrap();
+            let count = match outstanding.outbound.get_mut(&stream) {
+                Some(count) => count,
+                None => return Ok(()),
+            };
+            let credit = credit.min(*count);
+            *count -= credit;
+            if *count == 0 {
+                outstanding.outbound.remove(&stream);
+            }
+            credit
+        };
+        self.command(Command::Credit(stream, credit))
+    }
+
+    /// Compress the messages written from now on, once the `COMPRESSION` capability was
+    /// negotiated.
+    ///
+    /// Messages queued earlier may be compressed as well. The peer accepts compressed messages
+    /// as soon as it announces the capability, so only the announcement itself has to go out
+    /// before this.
+    pub fn set_compression(&self, enabled: bool) -> Result<(), NetError> {
+        self.command(Command::Compression(enabled))
+    }
+
+    /// Forget a request received on `stream` once it's answered. Answers on the control stream
+    /// count towards the backlog until they're written instead.
+    fn release(&self, stream: u16) {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        if stream == CONTROL_STREAM {
+            outstanding.control += 1;
+            return;
+        }
+        if let Some(count) = outstanding.inbound.get_mut(&stream) {
+            *count -= 1;
+            if *count == 0 {
+                outstanding.inbound.remove(&stream);
+            }
+        }
+    }
+
+    fn command(&self, command: Command) -> Result<(), NetError> {
+        self.tx
+            .send(command)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+    }
+}
+
+/// Send side state of a stream
+struct StreamState {
+    credit: u32,
+    queue: VecDeque<Outgoing>,
+}
+
+impl Default for StreamState {
+    fn default() -> Self {
+        StreamState {
+            credit: INITIAL_CREDIT,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+struct Writer<W> {
+    sender: NoiseSender<W>,
+    outstanding: Arc<Mutex<Outstanding>>,
+    control: VecDeque<Outgoing>,
+    /// Streams that have messages queued or credit outstanding
+    streams: HashMap<u16, StreamState>,
+    /// Streams with queued messages, in the order they take turns
+    ready: VecDeque<u16>,
+}
+
+impl<W: AsyncWrite + Unpin + Send> Writer<W> {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Command>) {
+        loop {
+            // Take in everything queued so far, so the priorities account for all of it
+            while let Ok(command) = rx.try_recv() {
+                self.apply(command);
+            }
+
+            match self.next_message() {
+                Some((msg, answered)) => {
+                    if let Err(e) = self.sender.send(&msg).await {
+                        debug!("Stopped writing to the connection: {}", e);
+                        return;
+                    }
+                    if let Some(stream) = answered {
+                        self.queue_credit(stream);
+                    }
+                }
+                None => match rx.recv().await {
+                    Some(command) => self.apply(command),
+                    None => return,
+                },
+            }
+        }
+    }
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::Request(CONTROL_STREAM, msg) => self.control.push_back(Outgoing::Request(msg)),
+            Command::Answer(CONTROL_STREAM, msg) => self.control.push_back(Outgoing::Answer(msg)),
+            Command::Part(CONTROL_STREAM, msg) => self.control.push_back(Outgoing::Part(msg)),
+            Command::Request(stream, msg) => self.queue(stream, Outgoing::Request(msg)),
+            Command::Answer(stream, msg) => self.queue(stream, Outgoing::Answer(msg)),
+            Command::Part(stream, msg) => self.queue(stream, Outgoing::Part(msg)),
+            Command::Credit(stream, credit) => {
+                let state = self.streams.entry(stream).or_default();
+                state.credit = state.credit.saturating_add(credit);
+                self.forget_idle(stream);
+            }
+            Command::Compression(enabled) => self.sender.set_compression(enabled),
+        }
+    }
+
+    fn queue(&mut self, stream: u16, msg: Outgoing) {
+        let state = self.streams.entry(stream).or_default();
+        if state.queue.is_empty() {
+            self.ready.push_back(stream);
+        }
+        state.queue.push_back(msg);
+    }
+
+    fn queue_credit(&mut self, stream: u16) {
+        self.control
+            .push_back(Outgoing::Request(MessageBuilder::encode_credit(stream, 1)));
+    }
+
+    /// Pick the next message to write, along with the stream it answers a request on
+    fn next_message(&mut self) -> Option<(Vec<u8>, Option<u16>)> {
+        match self.control.pop_front() {
+            Some(Outgoing::Request(msg)) => return Some((msg, None)),
+            Some(Outgoing::Answer(msg) | Outgoing::Part(msg)) => {
+                let mut outstanding = self.outstanding.lock().unwrap();
+                outstanding.control = outstanding.control.saturating_sub(1);
+                return Some((msg, None));
+            }
+            None => {}
+        }
+
+        for _ in 0..self.ready.len() {
+            let stream = self.ready.pop_front()?;
+            let state = self.streams.get_mut(&stream)?;
+            let next = match state.queue.front() {
+                Some(Outgoing::Request(_)) if state.credit == 0 => None,
+                _ => state.queue.pop_front(),
+            };
+
+            if !state.queue.is_empty() {
+                self.ready.push_back(stream);
+            }
+            let next = match next {
+                Some(Outgoing::Request(msg)) => {
+                    state.credit -= 1;
+                    (msg, None)
+                }
+                Some(Outgoing::Answer(msg)) => (msg, Some(stream)),
+                Some(Outgoing::Part(msg)) => (msg, None),
+                // Out of credit, give the other streams a turn
+                None => continue,
+            };
+            self.forget_idle(stream);
+            return Some(next);
+        }
+        None
+    }
+
+    /// Drop the state of a stream that has nothing queued and all of its credit back
+    fn forget_idle(&mut self, stream: u16) {
+        if let Some(state) = self.streams.get(&stream) {
+            if state.queue.is_empty() && state.credit >= INITIAL_CREDIT {
+                self.streams.remove(&stream);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        messaging::{arguments::Credit, Message, CREDIT_ID, PROTOCOL_VERSION},
+        net::{generate_noise_keypair, NetClient, NetServer, NoiseConnection, NoiseReceiver},
+    };
+    use std::time::Duration;
+    use tokio::{
+        io::{duplex, DuplexStream, ReadHalf},
+        time::timeout,
+    };
+
+    /// A multiplexer on the client side of a connection, and the server's end of it
+    async fn connect() -> (MuxSender, NoiseReceiver<ReadHalf<DuplexStream>>) {
+        let server_keys = generate_noise_keypair();
+        let client_keys = generate_noise_keypair();
+        let (client_stream, server_stream) = duplex(1 << 16);
+
+        let client_pubkey = client_keys.public.clone();
+        let server = tokio::spawn(async move {
+            NetServer::new(server_stream, &server_keys.private, &[client_pubkey])
+                .await
+                .unwrap()
+        });
+        let client = NetClient::new(client_stream, &client_keys.private, &[server_keys.public])
+            .await
+            .unwrap();
+
+        let (sender, _receiver) = client.split();
+        let (_sender, server) = server.await.unwrap().split();
+        (MuxSender::spawn(sender), server)
+    }
+
+    #[tokio::test]
+    async fn test_mux_priority_and_credit() {
+        let (mux, mut server) = connect().await;
+        for i in 0..6u8 {
+            mux.send(1, vec![i]).unwrap();
+        }
+        mux.send(CONTROL_STREAM, b"control".to_vec()).unwrap();
+
+        // The control message isn't held back by the requests, which stop once the credit runs
+        // out
+        let mut received = vec![];
+        for _ in 0..=INITIAL_CREDIT {
+            received.push(server.recv().await.unwrap());
+        }
+        assert!(received.contains(&b"control".to_vec()));
+        for i in 0..INITIAL_CREDIT as u8 {
+            assert!(received.contains(&vec![i]));
+        }
+        let early = timeout(Duration::from_millis(100), server.recv()).await;
+        assert!(early.is_err());
+
+        mux.add_credit(1, 2).unwrap();
+        assert_eq!(server.recv().await.unwrap(), vec![4]);
+        assert_eq!(server.recv().await.unwrap(), vec![5]);
+
+        // Answers don't need credit, and hand back the credit of the request they answer. Parts of
+        // an answer don't.
+        mux.answer_part(2, b"part".to_vec()).unwrap();
+        mux.answer(2, b"answer".to_vec()).unwrap();
+        assert_eq!(server.recv().await.unwrap(), b"part");
+        assert_eq!(server.recv().await.unwrap(), b"answer");
+        let builder = MessageBuilder::new(PROTOCOL_VERSION);
+        let credit = builder
+            .decode_message(&server.recv().await.unwrap())
+            .unwrap();
+        assert_eq!(credit.message, Message::Credit(Credit(1)));
+        assert_eq!(credit.stream, 2);
+        assert_eq!(credit.id, CREDIT_ID);
+    }
+
+    #[tokio::test]
+    async fn test_inbound_credit() {
+        let (mux, mut server) = connect().await;
+
+        for _ in 0..INITIAL_CREDIT {
+            mux.accept_request(2).unwrap();
+        }
+        assert!(matches!(
+            mux.accept_request(2),
+            Err(NetError::CreditExceeded(2))
+        ));
+        // Other streams have credit of their own, and the control stream only has a backlog
+        mux.accept_request(4).unwrap();
+        mux.accept_request(CONTROL_STREAM).unwrap();
+
+        // Answering hands the credit back
+        mux.answer(2, b"answer".to_vec()).unwrap();
+        mux.accept_request(2).unwrap();
+
+        // Credit for requests that were never sent doesn't let more through
+        mux.add_credit(1, 10).unwrap();
+        for i in 0..=INITIAL_CREDIT as u8 {
+            mux.send(1, vec![i]).unwrap();
+        }
+        assert_eq!(server.recv().await.unwrap(), b"answer");
+        let builder = MessageBuilder::new(PROTOCOL_VERSION);
+        assert!(builder
+            .decode_message(&server.recv().await.unwrap())
+            .is_ok());
+        for i in 0..INITIAL_CREDIT as u8 {
+            assert_eq!(server.recv().await.unwrap(), vec![i]);
+        }
+        let early = timeout(Duration::from_millis(100), server.recv()).await;
+        assert!(early.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_control_backlog() {
+        let (mux, mut server) = connect().await;
+
+        // A peer that doesn't read its answers can only ask for so many. The answers are
+        // incompressible, so only a few of them fit in the connection's buffer.
+        let count = CONTROL_BACKLOG as usize + 100;
+        for _ in 0..count {
+            let answer: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+            mux.answer(CONTROL_STREAM, answer).unwrap();
+        }
+        assert!(matches!(
+            mux.accept_request(CONTROL_STREAM),
+            Err(NetError::CreditExceeded(CONTROL_STREAM))
+        ));
+        // Our own requests don't count
+        mux.send(CONTROL_STREAM, b"request".to_vec()).unwrap();
+
+        for _ in 0..count {
+            assert_eq!(server.recv().await.unwrap().len(), 1024);
+        }
+        assert_eq!(server.recv().await.unwrap(), b"request");
+        mux.accept_request(CONTROL_STREAM).unwrap();
+    }
+}
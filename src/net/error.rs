@@ -9,13 +9,34 @@ pub enum NetError {
     Noise(String),
     /// Network message too long to send using Noise
     MsgLength(usize),
+    /// A transport frame was missing its fragment header
+    MalformedFrame,
+    /// The remote static key isn't in the list of allowed keys (base64 encoded key)
+    UnknownKey(String),
+    /// Nothing was heard from the remote side within the idle timeout
+    Timeout,
+    /// A compressed message couldn't be decompressed
+    Compression(String),
+    /// The remote side sent more requests on a stream than it had credit for (stream id)
+    CreditExceeded(u16),
     /// Generic IO Error
     IO(String),
 }
 
 impl Display for NetError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}", self))
+        match self {
+            NetError::Noise(e) => write!(f, "Noise error: {}", e),
+            NetError::MsgLength(len) => write!(f, "Message too long: {} bytes", len),
+            NetError::MalformedFrame => write!(f, "Malformed transport frame"),
+            NetError::UnknownKey(key) => write!(f, "Unknown remote public key: {}", key),
+            NetError::Timeout => write!(f, "Connection timed out"),
+            NetError::Compression(e) => write!(f, "Couldn't decompress message: {}", e),
+            NetError::CreditExceeded(stream) => {
+                write!(f, "Remote side exceeded its credit on stream {}", stream)
+            }
+            NetError::IO(e) => write!(f, "IO error: {}", e),
+        }
     }
 }
 
@@ -0,0 +1,61 @@
+//! Liveness tracking for connections
+
+use super::error::NetError;
+use std::time::Duration;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+/// Keeps track of when the remote side was last heard from.
+///
+/// Neither side notices a half-open connection on its own, so both of them send a heartbeat every
+/// `interval` and give up on the connection once nothing was received for `timeout`.
+pub struct Heartbeat {
+    interval: Interval,
+    timeout: Duration,
+    last_seen: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        let now = Instant::now();
+        let mut interval = time::interval_at(now + interval, interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Heartbeat {
+            interval,
+            timeout,
+            last_seen: now,
+        }
+    }
+
+    /// Record that something was received from the remote side
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Wait until the next heartbeat should be sent.
+    ///
+    /// Returns `NetError::Timeout` instead when the remote side has been silent for longer than
+    /// the timeout. This is cancel safe, so it can be used in `select!`.
+    pub async fn tick(&mut self) -> Result<(), NetError> {
+        self.interval.tick().await;
+        if self.last_seen.elapsed() >= self.timeout {
+            return Err(NetError::Timeout);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_timeout() {
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(10), Duration::from_secs(25));
+        assert!(heartbeat.tick().await.is_ok());
+        assert!(heartbeat.tick().await.is_ok());
+        heartbeat.seen();
+        assert!(heartbeat.tick().await.is_ok());
+        assert!(heartbeat.tick().await.is_ok());
+        assert!(matches!(heartbeat.tick().await, Err(NetError::Timeout)));
+    }
+}
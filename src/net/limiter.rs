@@ -0,0 +1,92 @@
+//! Bandwidth limiting for connections
+
+use std::time::Duration;
+use tokio::{
+    sync::watch,
+    time::{self, Instant},
+};
+
+/// Token bucket limiting the bandwidth of one direction of a connection.
+///
+/// The limit (in bytes per second, `None` for unlimited) is read from a watch channel on every
+/// use, so it can be changed while connections are running. Up to a second worth of unused
+/// bandwidth can be saved up for bursts.
+pub struct RateLimiter {
+    limit: Option<watch::Receiver<Option<u64>>>,
+    /// Available bytes. This goes negative when more than what's available is used, and the
+    /// debt is waited out before continuing.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: watch::Receiver<Option<u64>>) -> Self {
+        RateLimiter {
+            limit: Some(limit),
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        RateLimiter {
+            limit: None,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Account for `bytes` being transferred, waiting until the limit allows it.
+    ///
+    /// The bytes are accounted for before waiting, so this is cancel safe.
+    pub async fn acquire(&mut self, bytes: usize) {
+        let rate = match self.limit.as_ref().and_then(|limit| *limit.borrow()) {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => return,
+        };
+
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            time::sleep(Duration::from_secs_f64(-self.tokens / rate)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limiter() {
+        let (limit_tx, limit_rx) = watch::channel(Some(1000));
+        let mut limiter = RateLimiter::new(limit_rx);
+
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire(500).await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        // A single transfer larger than the bucket is waited out afterwards
+        let start = Instant::now();
+        limiter.acquire(3000).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        // Changing the limit applies right away. The time waited so far filled up the bucket.
+        limit_tx.send(Some(4000)).unwrap();
+        let start = Instant::now();
+        limiter.acquire(4000).await;
+        limiter.acquire(2000).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+
+        limit_tx.send(None).unwrap();
+        let start = Instant::now();
+        limiter.acquire(1 << 30).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -7,6 +7,10 @@
 //! The two main structs are the [`client`](struct.Client.html) and [`server`](struct.Server.html),
 //! which both implement the [`NoiseConnection`](trait.NoiseConnection.html) trait.
 //!
+//! Both are generic over the underlying byte stream. Anything implementing
+//! [`Transport`](trait.Transport.html) (`AsyncRead + AsyncWrite`) can carry a connection, such as
+//! a `TcpStream`, a `UnixStream` or an in-memory `tokio::io::DuplexStream`.
+//!
 //! ## Server Example
 //!
 //! ```rust
@@ -14,14 +18,16 @@
 //! let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
 //! // Iterate through connections
 //! for stream in listener.incoming() {
-//!     let mut server = Server::new(
+//!     let server = Server::new(
 //!         stream.unwrap(),
 //!         &noise_private_key,
 //!         &valid_client_pubkeys,
-//!     ).unwrap()
-//!     
+//!     ).unwrap();
+//!     // Split the connection into its sending and receiving halves
+//!     let (sender, mut receiver) = server.split();
+//!
 //!     // Receive message
-//!     if let Ok(msg) = &server.recv() {
+//!     if let Ok(msg) = &receiver.recv() {
 //!         println!("{:?}", msg);
 //!     }
 //! }
@@ -32,63 +38,189 @@
 //!
 //! ```rust
 //! // Create client to wrap messages with the noise protocol
-//! let mut client = Client::new(
+//! let client = Client::new(
 //!     TcpStream::connect("127.0.0.1:8080").unwrap(),
 //!     &noise_private_key,
 //!     &[server_public_key],
 //! )
 //! .unwrap();
+//! let (mut sender, receiver) = client.split();
 //!
 //! // Create MessageBuilder to create messages to send
-//! let mut builder = messaging::MessageBuilder::new(1);
+//! let mut builder = messaging::MessageBuilder::new(messaging::PROTOCOL_VERSION);
 //! // Create a message
-//! let msg = builder.encode_message(Directive::AnnounceVersion, Some(arguments::Version(1)));
+//! let msg = builder.announce_version();
 //! // Send the message
-//! client.send(&msg).unwrap();
+//! sender.send(&msg).unwrap();
+//! ```
+//!
+//! ## Fragmentation
+//!
+//! A single Noise message can't be longer than 65535 bytes, but the messages handed to
+//! [`send`](struct.NoiseSender.html#method.send) can be much larger (file chunks for
+//! instance). Logical messages are therefore split over as many Noise transport frames as
+//! needed. The plaintext of each frame is laid out as follows:
+//!
+//! ```
+//! <flags:u8> <fragment>
 //! ```
+//!
+//! The `0x01` flag is set on every frame of a message except the last one.
+//! [`recv`](struct.NoiseReceiver.html#method.recv) keeps reading frames until it has
+//! reassembled a complete message.
+//!
+//! ## Compression
+//!
+//! Once [enabled](struct.NoiseSender.html#method.set_compression), messages are compressed with
+//! zstd before being fragmented, and the `0x02` flag is set on every frame of a compressed
+//! message. Short messages and messages that don't shrink (like chunks of files that are already
+//! compressed) are sent as is.
+//!
+//! Whether to compress is up to the layer above, which negotiates it with the `COMPRESSION`
+//! [capability](../messaging/arguments/struct.Capabilities.html). Compressed messages are always
+//! accepted, as long as they don't decompress to more than
+//! [`MAX_MESSAGE_LENGTH`](constant.MAX_MESSAGE_LENGTH.html).
+//!
+//! ## Splitting
+//!
+//! A connection can be [`split`](trait.NoiseConnection.html#tymethod.split) into a
+//! [`NoiseSender`](struct.NoiseSender.html) and a [`NoiseReceiver`](struct.NoiseReceiver.html).
+//! Each half keeps its own nonce, so both directions can be used at the same time. This way a
+//! large upload doesn't keep the other side's messages from being read.
 
 pub mod error;
+mod heartbeat;
+mod limiter;
 
 use async_trait::async_trait;
 use base64ct::{Base64, Encoding};
 use error::NetError;
-use snow::{Builder, Keypair, TransportState};
-use tokio::{io::AsyncReadExt, io::AsyncWriteExt, net::TcpStream};
+pub use heartbeat::Heartbeat;
+pub use limiter::RateLimiter;
+use snow::{Builder, Keypair, StatelessTransportState};
+use std::{
+    io::{self, Read},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf},
+    sync::mpsc,
+    task::JoinHandle,
+};
 
 static NOISE_PATTERN: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
 
+/// Maximum length of a single Noise message (ciphertext included)
+const NOISE_MAX_LENGTH: usize = 65535;
+/// Length of the authentication tag appended to every Noise transport message
+const NOISE_TAG_LENGTH: usize = 16;
+/// Maximum amount of message data that fits in a single transport frame.
+///
+/// One byte of every frame is reserved for the fragment flags.
+const FRAGMENT_PAYLOAD_LENGTH: usize = NOISE_MAX_LENGTH - NOISE_TAG_LENGTH - 1;
+/// Fragment flag signaling that more frames belong to the current message
+const FRAGMENT_MORE: u8 = 0x01;
+/// Fragment flag signaling that the message is compressed with zstd
+const FRAGMENT_COMPRESSED: u8 = 0x02;
+/// Messages shorter than this aren't worth compressing
+const COMPRESSION_THRESHOLD: usize = 256;
+const COMPRESSION_LEVEL: i32 = 3;
+/// Upper bound on the size of a reassembled message.
+///
+/// This keeps a misbehaving peer from making us buffer an endless stream of fragments.
+pub const MAX_MESSAGE_LENGTH: usize = 64 << 20;
+
+/// A duplex byte stream that a Noise connection can be layered on top of.
+///
+/// This is implemented for every `AsyncRead + AsyncWrite` type, so it never needs to be
+/// implemented by hand.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// A type erased [`Transport`](trait.Transport.html), used when the kind of stream is only known
+/// at runtime.
+pub type BoxedTransport = Box<dyn Transport>;
+
+/// Joins a separate reader and writer into a single [`Transport`](trait.Transport.html).
+///
+/// This allows the protocol to be spoken over a pair of pipes, such as the server's own
+/// stdin/stdout or the stdout/stdin of a child process.
+pub struct Duplex<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Duplex<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Duplex { reader, writer }
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Duplex<R, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().reader).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Duplex<R, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_shutdown(cx)
+    }
+}
+
 #[async_trait]
 /// A generic trait that allows noise connections to be created and send/recieve information
-pub trait NoiseConnection {
-    async fn new(
-        stream: TcpStream,
-        static_key: &[u8],
-        remote_keys: &[Vec<u8>],
-    ) -> Result<Self, NetError>
+pub trait NoiseConnection<S: Transport> {
+    async fn new(stream: S, static_key: &[u8], remote_keys: &[Vec<u8>]) -> Result<Self, NetError>
     where
         Self: Sized;
-    async fn send(&mut self, msg: &[u8]) -> Result<(), NetError>;
-    async fn recv(&mut self) -> Result<Vec<u8>, NetError>;
+    /// Limit the bandwidth used by the connection in each direction
+    fn set_limiters(&mut self, send: RateLimiter, recv: RateLimiter);
+    /// Split the connection into halves that can send and receive independently
+    fn split(self) -> (NoiseSender<WriteHalf<S>>, NoiseReceiver<ReadHalf<S>>);
 }
 
 /// The server side of the network connection
 ///
 /// `NetServer` will be the responder in the Noise handshake while the
 /// [`NetClient`](struct.NetClient.html) will be the initiator.
-pub struct NetServer {
-    stream: TcpStream,
+pub struct NetServer<S: Transport> {
+    stream: S,
     buf: Vec<u8>,
-    noise: TransportState,
+    noise: Arc<StatelessTransportState>,
+    send_nonce: u64,
+    recv_state: RecvState,
+    send_limiter: RateLimiter,
+    recv_limiter: RateLimiter,
 }
 
 #[async_trait]
-impl NoiseConnection for NetServer {
+impl<S: Transport> NoiseConnection<S> for NetServer<S> {
     async fn new(
-        mut stream: TcpStream,
+        mut stream: S,
         static_key: &[u8],
         remote_keys: &[Vec<u8>],
     ) -> Result<Self, NetError> {
-        let mut buf = vec![0u8; 65535];
+        let mut buf = vec![0u8; NOISE_MAX_LENGTH];
 
         // Setup builder to start handshake
         let builder = Builder::new(NOISE_PATTERN.parse().unwrap());
@@ -104,31 +236,44 @@ impl NoiseConnection for NetServer {
             Base64::encode_string(noise.get_remote_static().unwrap())
         );
 
+        // Refuse unknown keys before replying so the initiator never gets into transport mode
         let is = noise.get_remote_static().unwrap();
         if !remote_keys.contains(&is.to_vec()) {
-            error!("Remote public key isn't known");
+            let key = Base64::encode_string(is);
+            let _ = stream.shutdown().await;
+            return Err(NetError::UnknownKey(key));
         }
 
         // -> e, ee, se
-        let len = noise.write_message(&[0u8; 0], &mut buf)?;
+        let len = noise.write_message(&[], &mut buf)?;
         send(&mut stream, &buf[..len]).await?;
 
         // Finished handshake. Switch to transport mode
-        let noise = noise.into_transport_mode()?;
-        Ok(NetServer { stream, buf, noise })
+        let noise = Arc::new(noise.into_stateless_transport_mode()?);
+        Ok(NetServer {
+            stream,
+            buf,
+            noise,
+            send_nonce: 0,
+            recv_state: RecvState::default(),
+            send_limiter: RateLimiter::unlimited(),
+            recv_limiter: RateLimiter::unlimited(),
+        })
     }
 
-    async fn send(&mut self, msg: &[u8]) -> Result<(), NetError> {
-        let len = self.noise.write_message(msg, &mut self.buf)?;
-        send(&mut self.stream, &self.buf[..len]).await?;
-        Ok(())
+    fn set_limiters(&mut self, send: RateLimiter, recv: RateLimiter) {
+        self.send_limiter = send;
+        self.recv_limiter = recv;
     }
 
-    async fn recv(&mut self) -> Result<Vec<u8>, NetError> {
-        let len = self
-            .noise
-            .read_message(&recv(&mut self.stream).await?, &mut self.buf)?;
-        Ok(self.buf[..len].to_vec())
+    fn split(self) -> (NoiseSender<WriteHalf<S>>, NoiseReceiver<ReadHalf<S>>) {
+        split(
+            self.stream,
+            self.buf,
+            self.noise,
+            (self.send_nonce, self.send_limiter),
+            (self.recv_state, self.recv_limiter),
+        )
     }
 }
 
@@ -136,20 +281,24 @@ impl NoiseConnection for NetServer {
 ///
 /// `NetClient` will be the initiator in the Noise handshake while the
 /// [`NetServer`](struct.NetServer.html) will be the responder.
-pub struct NetClient {
-    stream: TcpStream,
+pub struct NetClient<S: Transport> {
+    stream: S,
     buf: Vec<u8>,
-    noise: TransportState,
+    noise: Arc<StatelessTransportState>,
+    send_nonce: u64,
+    recv_state: RecvState,
+    send_limiter: RateLimiter,
+    recv_limiter: RateLimiter,
 }
 
 #[async_trait]
-impl NoiseConnection for NetClient {
+impl<S: Transport> NoiseConnection<S> for NetClient<S> {
     async fn new(
-        mut stream: TcpStream,
+        mut stream: S,
         static_key: &[u8],
         remote_keys: &[Vec<u8>],
     ) -> Result<Self, NetError> {
-        let mut buf = vec![0u8; 65535];
+        let mut buf = vec![0u8; NOISE_MAX_LENGTH];
 
         // Setup builder to start handshake
         let builder = Builder::new(NOISE_PATTERN.parse()?);
@@ -165,31 +314,307 @@ impl NoiseConnection for NetClient {
         send(&mut stream, &buf[..len]).await?;
 
         // <- e, ee, se
-        noise
-            .read_message(&recv(&mut stream).await?, &mut buf)?;
+        noise.read_message(&recv(&mut stream).await?, &mut buf)?;
+
+        let noise = Arc::new(noise.into_stateless_transport_mode()?);
+        Ok(NetClient {
+            stream,
+            buf,
+            noise,
+            send_nonce: 0,
+            recv_state: RecvState::default(),
+            send_limiter: RateLimiter::unlimited(),
+            recv_limiter: RateLimiter::unlimited(),
+        })
+    }
+
+    fn set_limiters(&mut self, send: RateLimiter, recv: RateLimiter) {
+        self.send_limiter = send;
+        self.recv_limiter = recv;
+    }
+
+    fn split(self) -> (NoiseSender<WriteHalf<S>>, NoiseReceiver<ReadHalf<S>>) {
+        split(
+            self.stream,
+            self.buf,
+            self.noise,
+            (self.send_nonce, self.send_limiter),
+            (self.recv_state, self.recv_limiter),
+        )
+    }
+}
+
+/// Sending half of a split connection
+pub struct NoiseSender<W> {
+    stream: W,
+    buf: Vec<u8>,
+    noise: Arc<StatelessTransportState>,
+    compression: bool,
+    nonce: u64,
+    limiter: RateLimiter,
+}
+
+impl<W: AsyncWrite + Unpin + Send> NoiseSender<W> {
+    /// Compress the messages sent from now on, once the peer is known to accept them
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    pub async fn send(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        send_fragmented(
+            &mut self.stream,
+            &self.noise,
+            self.compression,
+            &mut self.nonce,
+            &mut self.limiter,
+            &mut self.buf,
+            msg,
+        )
+        .await
+    }
+}
+
+/// Receiving half of a split connection
+pub struct NoiseReceiver<R> {
+    stream: R,
+    buf: Vec<u8>,
+    noise: Arc<StatelessTransportState>,
+    state: RecvState,
+    limiter: RateLimiter,
+}
 
-        let noise = noise.into_transport_mode()?;
-        Ok(NetClient { stream, buf, noise })
+impl<R: AsyncRead + Unpin + Send + 'static> NoiseReceiver<R> {
+    pub async fn recv(&mut self) -> Result<Vec<u8>, NetError> {
+        recv_fragmented(
+            &mut self.stream,
+            &self.noise,
+            &mut self.limiter,
+            &mut self.buf,
+            &mut self.state,
+        )
+        .await
     }
 
-    async fn send(&mut self, msg: &[u8]) -> Result<(), NetError> {
-        if msg.len() > 65535 {
-            return Err(NetError::MsgLength(msg.len()));
+    /// Keep receiving messages in a background task.
+    ///
+    /// The connection is read as soon as data arrives, even while the owner of the returned
+    /// [`Incoming`](struct.Incoming.html) is busy sending. At most `capacity` messages are
+    /// buffered before the task stops reading.
+    pub fn spawn(mut self, capacity: usize) -> Incoming {
+        let (tx, rx) = mpsc::channel(capacity);
+        let task = tokio::spawn(async move {
+            loop {
+                let msg = self.recv().await;
+                let failed = msg.is_err();
+                if tx.send(msg).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Incoming { rx, task }
+    }
+}
+
+/// Messages read by a [`NoiseReceiver`](struct.NoiseReceiver.html) running in the background.
+///
+/// The background task is stopped when this is dropped.
+pub struct Incoming {
+    rx: mpsc::Receiver<Result<Vec<u8>, NetError>>,
+    task: JoinHandle<()>,
+}
+
+impl Incoming {
+    /// Get the next message. This is cancel safe.
+    pub async fn recv(&mut self) -> Result<Vec<u8>, NetError> {
+        match self.rx.recv().await {
+            Some(msg) => msg,
+            // The task only stops after handing over the error that ended it
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
         }
-        let len = self.noise.write_message(msg, &mut self.buf)?;
-        send(&mut self.stream, &self.buf[..len]).await?;
-        Ok(())
     }
+}
 
-    async fn recv(&mut self) -> Result<Vec<u8>, NetError> {
-        let len = self
-            .noise
-            .read_message(&recv(&mut self.stream).await?, &mut self.buf)?;
-        Ok(self.buf[..len].to_vec())
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        self.task.abort();
     }
 }
 
-pub async fn recv(stream: &mut TcpStream) -> Result<Vec<u8>, NetError> {
+/// Split a connection in transport mode into its two halves
+fn split<S: Transport>(
+    stream: S,
+    buf: Vec<u8>,
+    noise: Arc<StatelessTransportState>,
+    (send_nonce, send_limiter): (u64, RateLimiter),
+    (recv_state, recv_limiter): (RecvState, RateLimiter),
+) -> (NoiseSender<WriteHalf<S>>, NoiseReceiver<ReadHalf<S>>) {
+    let (reader, writer) = tokio::io::split(stream);
+    let sender = NoiseSender {
+        stream: writer,
+        buf,
+        noise: noise.clone(),
+        compression: false,
+        nonce: send_nonce,
+        limiter: send_limiter,
+    };
+    let receiver = NoiseReceiver {
+        stream: reader,
+        buf: vec![0u8; NOISE_MAX_LENGTH],
+        noise,
+        state: recv_state,
+        limiter: recv_limiter,
+    };
+    (sender, receiver)
+}
+
+/// Encrypt and send a logical message, splitting it over as many Noise frames as needed.
+///
+/// Every frame's plaintext starts with a flag byte. [`FRAGMENT_MORE`] is set on every frame
+/// except the last one of a message. When `compression` is enabled and compressing the message
+/// pays off, [`FRAGMENT_COMPRESSED`] is set on all of its frames.
+async fn send_fragmented<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    noise: &StatelessTransportState,
+    compression: bool,
+    nonce: &mut u64,
+    limiter: &mut RateLimiter,
+    buf: &mut [u8],
+    msg: &[u8],
+) -> Result<(), NetError> {
+    if msg.len() > MAX_MESSAGE_LENGTH {
+        return Err(NetError::MsgLength(msg.len()));
+    }
+
+    let compressed = if compression { compress(msg) } else { None };
+    let (msg, compressed_flag) = match &compressed {
+        Some(compressed) => (&compressed[..], FRAGMENT_COMPRESSED),
+        None => (msg, 0),
+    };
+
+    let mut fragments = msg.chunks(FRAGMENT_PAYLOAD_LENGTH).peekable();
+    let mut frame = Vec::with_capacity(FRAGMENT_PAYLOAD_LENGTH + 1);
+    loop {
+        let fragment = fragments.next().unwrap_or_default();
+        let flags = if fragments.peek().is_some() {
+            FRAGMENT_MORE | compressed_flag
+        } else {
+            compressed_flag
+        };
+
+        frame.clear();
+        frame.push(flags);
+        frame.extend_from_slice(fragment);
+
+        let len = noise.write_message(*nonce, &frame, buf)?;
+        *nonce += 1;
+        limiter.acquire(len + 2).await;
+        send(stream, &buf[..len]).await?;
+
+        if flags & FRAGMENT_MORE == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Receive side state of a connection in transport mode.
+///
+/// Everything read from the stream is kept here rather than in the `recv` future, which makes
+/// receiving cancel safe: dropping the future (like `select!` does) never loses part of a frame
+/// or of a fragmented message.
+#[derive(Default)]
+struct RecvState {
+    /// Raw bytes read from the stream that don't make up a complete frame yet
+    pending: Vec<u8>,
+    /// Fragments of the message currently being reassembled
+    message: Vec<u8>,
+    /// Nonce of the next frame
+    nonce: u64,
+}
+
+impl RecvState {
+    /// Take the next complete length prefixed frame out of the pending bytes
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.pending.len() < 2 {
+            return None;
+        }
+        let len = u16::from_be_bytes([self.pending[0], self.pending[1]]) as usize;
+        if self.pending.len() < 2 + len {
+            return None;
+        }
+        let frame = self.pending[2..2 + len].to_vec();
+        self.pending.drain(..2 + len);
+        Some(frame)
+    }
+}
+
+/// Compress a message, unless it's too short or doesn't shrink
+fn compress(msg: &[u8]) -> Option<Vec<u8>> {
+    if msg.len() < COMPRESSION_THRESHOLD {
+        return None;
+    }
+    zstd::bulk::compress(msg, COMPRESSION_LEVEL)
+        .ok()
+        .filter(|compressed| compressed.len() < msg.len())
+}
+
+/// Decompress a message, refusing to inflate it past [`MAX_MESSAGE_LENGTH`]
+fn decompress(msg: &[u8]) -> Result<Vec<u8>, NetError> {
+    let decoder = zstd::stream::read::Decoder::with_buffer(msg)
+        .map_err(|e| NetError::Compression(e.to_string()))?;
+    let mut decompressed = vec![];
+    // Reading one byte past the limit tells a message that's too long from one that fits exactly
+    decoder
+        .take(MAX_MESSAGE_LENGTH as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| NetError::Compression(e.to_string()))?;
+    if decompressed.len() > MAX_MESSAGE_LENGTH {
+        return Err(NetError::MsgLength(decompressed.len()));
+    }
+    Ok(decompressed)
+}
+
+/// Receive and decrypt Noise frames until a full logical message has been reassembled
+async fn recv_fragmented<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    noise: &StatelessTransportState,
+    limiter: &mut RateLimiter,
+    buf: &mut [u8],
+    state: &mut RecvState,
+) -> Result<Vec<u8>, NetError> {
+    loop {
+        while let Some(frame) = state.next_frame() {
+            let len = noise.read_message(state.nonce, &frame, buf)?;
+            state.nonce += 1;
+            if len == 0 {
+                return Err(NetError::MalformedFrame);
+            }
+
+            state.message.extend_from_slice(&buf[1..len]);
+            if state.message.len() > MAX_MESSAGE_LENGTH {
+                return Err(NetError::MsgLength(state.message.len()));
+            }
+
+            if buf[0] & FRAGMENT_MORE == 0 {
+                let message = std::mem::take(&mut state.message);
+                if buf[0] & FRAGMENT_COMPRESSED == 0 {
+                    return Ok(message);
+                }
+                return decompress(&message);
+            }
+        }
+
+        // A single read is cancel safe, unlike `read_exact`
+        state.pending.reserve(NOISE_MAX_LENGTH);
+        let len = stream.read_buf(&mut state.pending).await?;
+        if len == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        // Holding off the next read keeps the peer from sending faster than the limit
+        limiter.acquire(len).await;
+    }
+}
+
+pub async fn recv<S: Transport>(stream: &mut S) -> Result<Vec<u8>, NetError> {
     let mut msg_len_buf = [0u8; 2];
     stream.read_exact(&mut msg_len_buf).await?;
     let msg_len = u16::from_be_bytes(msg_len_buf) as usize;
@@ -198,11 +623,13 @@ pub async fn recv(stream: &mut TcpStream) -> Result<Vec<u8>, NetError> {
     Ok(msg)
 }
 
-async fn send(stream: &mut TcpStream, msg: &[u8]) -> Result<(), NetError> {
+async fn send<W: AsyncWrite + Unpin>(stream: &mut W, msg: &[u8]) -> Result<(), NetError> {
     let msg_len = (msg.len() as u16).to_be_bytes();
     // Time out might be needed here...?
     stream.write_all(&msg_len).await?;
     stream.write_all(msg).await?;
+    // Buffered streams (like stdout) won't hand the frame to the peer until they're flushed
+    stream.flush().await?;
     Ok(())
 }
 
@@ -210,3 +637,269 @@ pub fn generate_noise_keypair() -> Keypair {
     let builder = Builder::new(NOISE_PATTERN.parse().unwrap());
     builder.generate_keypair().unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// Incompressible data, so messages span as many frames as their length suggests
+    fn random_bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|_| rand::random()).collect()
+    }
+
+    fn text(len: usize) -> Vec<u8> {
+        b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. "
+            .iter()
+            .copied()
+            .cycle()
+            .take(len)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_messages() {
+        let server_keys = generate_noise_keypair();
+        let client_keys = generate_noise_keypair();
+        let (client_stream, server_stream) = duplex(NOISE_MAX_LENGTH);
+
+        let client_pubkey = client_keys.public.clone();
+        let server = tokio::spawn(async move {
+            let server = NetServer::new(server_stream, &server_keys.private, &[client_pubkey])
+                .await
+                .unwrap();
+            let (mut sender, mut receiver) = server.split();
+            let msg = receiver.recv().await.unwrap();
+            sender.send(&msg).await.unwrap();
+            assert!(receiver.recv().await.unwrap().is_empty());
+            msg
+        });
+
+        let client = NetClient::new(client_stream, &client_keys.private, &[server_keys.public])
+            .await
+            .unwrap();
+        let (mut sender, mut receiver) = client.split();
+
+        // Larger than a full client chunk, and not a multiple of the fragment size
+        let msg = random_bytes((1 << 20) + 1234);
+        sender.send(&msg).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), msg);
+        sender.send(&[]).await.unwrap();
+        assert_eq!(server.await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key_refused() {
+        let server_keys = generate_noise_keypair();
+        let client_keys = generate_noise_keypair();
+        let known_keys = vec![generate_noise_keypair().public];
+        let (client_stream, server_stream) = duplex(NOISE_MAX_LENGTH);
+
+        let server = tokio::spawn(async move {
+            NetServer::new(server_stream, &server_keys.private, &known_keys).await
+        });
+
+        let client =
+            NetClient::new(client_stream, &client_keys.private, &[server_keys.public]).await;
+
+        assert!(client.is_err());
+        match server.await.unwrap() {
+            Err(NetError::UnknownKey(key)) => {
+                assert_eq!(key, Base64::encode_string(&client_keys.public))
+            }
+            _ => panic!("Unknown key wasn't refused"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_recv() {
+        let server_keys = generate_noise_keypair();
+        let client_keys = generate_noise_keypair();
+        let (client_stream, server_stream) = duplex(1024);
+
+        let client_pubkey = client_keys.public.clone();
+        let client = tokio::spawn(async move {
+            let client = NetClient::new(client_stream, &client_keys.private, &[server_keys.public])
+                .await
+                .unwrap();
+            let (mut sender, _receiver) = client.split();
+            let msg = random_bytes(200_000);
+            sender.send(&msg).await.unwrap();
+            msg
+        });
+
+        let server = NetServer::new(server_stream, &server_keys.private, &[client_pubkey])
+            .await
+            .unwrap();
+        let (_sender, mut receiver) = server.split();
+
+        // Keep dropping the recv future part way through the message
+        let msg = loop {
+            let recv = receiver.recv();
+            if let Ok(msg) = tokio::time::timeout(std::time::Duration::from_micros(10), recv).await
+            {
+                break msg.unwrap();
+            }
+        };
+        assert_eq!(msg, client.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_duplex_transport() {
+        let server_keys = generate_noise_keypair();
+        let client_keys = generate_noise_keypair();
+
+        // Two one-way pipes, like a child process' stdin and stdout
+        let (to_server, from_client) = duplex(NOISE_MAX_LENGTH);
+        let (to_client, from_server) = duplex(NOISE_MAX_LENGTH);
+        let (server_reader, _) = tokio::io::split(from_client);
+        let (_, server_writer) = tokio::io::split(to_client);
+        let (client_reader, _) = tokio::io::split(from_server);
+        let (_, client_writer) = tokio::io::split(to_server);
+
+        let client_pubkey = client_keys.public.clone();
+        let server = tokio::spawn(async move {
+            let stream = Duplex::new(server_reader, server_writer);
+            let server = NetServer::new(stream, &server_keys.private, &[client_pubkey])
+                .await
+                .unwrap();
+            let (mut sender, mut receiver) = server.split();
+            let msg = receiver.recv().await.unwrap();
+            sender.send(&msg).await.unwrap();
+        });
+
+        let stream = Duplex::new(client_reader, client_writer);
+        let client = NetClient::new(stream, &client_keys.private, &[server_keys.public])
+            .await
+            .unwrap();
+        let (mut sender, mut receiver) = client.split();
+        sender.send(b"Hello world").await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), b"Hello world");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_split() {
+        let server_keys = generate_noise_keypair();
+        let client_keys = generate_noise_keypair();
+        // Far smaller than the messages, so neither side can finish sending before the other one
+        // reads
+        let (client_stream, server_stream) = duplex(1024);
+        let msg = random_bytes(1 << 20);
+
+        let client_pubkey = client_keys.public.clone();
+        let server_msg = msg.clone();
+        let server = tokio::spawn(async move {
+            let server = NetServer::new(server_stream, &server_keys.private, &[client_pubkey])
+                .await
+                .unwrap();
+            let (mut sender, receiver) = server.split();
+            let mut incoming = receiver.spawn(1);
+            sender.send(&server_msg).await.unwrap();
+            incoming.recv().await.unwrap()
+        });
+
+        let client = NetClient::new(client_stream, &client_keys.private, &[server_keys.public])
+            .await
+            .unwrap();
+        let (mut sender, mut receiver) = client.split();
+        let (sent, received) = tokio::join!(sender.send(&msg), receiver.recv());
+        sent.unwrap();
+        assert_eq!(received.unwrap(), msg);
+        assert_eq!(server.await.unwrap(), msg);
+    }
+
+    #[test]
+    fn test_compress() {
+        assert!(compress(&text(1 << 20)).unwrap().len() < 1 << 16);
+        // Already compressed data and short messages aren't worth it
+        assert!(compress(&random_bytes(1 << 20)).is_none());
+        assert!(compress(&text(COMPRESSION_THRESHOLD - 1)).is_none());
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let msg = text(MAX_MESSAGE_LENGTH);
+        assert_eq!(decompress(&compress(&msg).unwrap()).unwrap(), msg);
+        let msg = text(MAX_MESSAGE_LENGTH + 1);
+        assert!(matches!(
+            decompress(&compress(&msg).unwrap()),
+            Err(NetError::MsgLength(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_messages() {
+        let server_keys = generate_noise_keypair();
+        let client_keys = generate_noise_keypair();
+        let (client_stream, server_stream) = duplex(NOISE_MAX_LENGTH);
+
+        let client_pubkey = client_keys.public.clone();
+        let server = tokio::spawn(async move {
+            let server = NetServer::new(server_stream, &server_keys.private, &[client_pubkey])
+                .await
+                .unwrap();
+            let (mut sender, mut receiver) = server.split();
+            sender.set_compression(true);
+            for _ in 0..2 {
+                let msg = receiver.recv().await.unwrap();
+                sender.send(&msg).await.unwrap();
+            }
+        });
+
+        let client = NetClient::new(client_stream, &client_keys.private, &[server_keys.public])
+            .await
+            .unwrap();
+        let (mut sender, mut receiver) = client.split();
+        sender.set_compression(true);
+        for msg in [text((1 << 20) + 1234), random_bytes(100_000)] {
+            sender.send(&msg).await.unwrap();
+            assert_eq!(receiver.recv().await.unwrap(), msg);
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_uncompressed_by_default() {
+        let server_keys = generate_noise_keypair();
+        let client_keys = generate_noise_keypair();
+        let (mut client_stream, server_stream) = duplex(NOISE_MAX_LENGTH);
+        let msg = text(100_000);
+
+        let client_pubkey = client_keys.public.clone();
+        let server_msg = msg.clone();
+        let server = tokio::spawn(async move {
+            let server = NetServer::new(server_stream, &server_keys.private, &[client_pubkey])
+                .await
+                .unwrap();
+            let (mut sender, _receiver) = server.split();
+            sender.send(&server_msg).await.unwrap();
+        });
+
+        // A bare client, so the flags of every frame can be checked
+        let mut buf = vec![0u8; NOISE_MAX_LENGTH];
+        let mut noise = Builder::new(NOISE_PATTERN.parse().unwrap())
+            .local_private_key(&client_keys.private)
+            .remote_public_key(&server_keys.public)
+            .build_initiator()
+            .unwrap();
+        let len = noise.write_message(&[], &mut buf).unwrap();
+        send(&mut client_stream, &buf[..len]).await.unwrap();
+        let frame = recv(&mut client_stream).await.unwrap();
+        noise.read_message(&frame, &mut buf).unwrap();
+        let noise = noise.into_stateless_transport_mode().unwrap();
+
+        let mut received = vec![];
+        for nonce in 0.. {
+            let frame = recv(&mut client_stream).await.unwrap();
+            let len = noise.read_message(nonce, &frame, &mut buf).unwrap();
+            assert_eq!(buf[0] & FRAGMENT_COMPRESSED, 0);
+            received.extend_from_slice(&buf[1..len]);
+            if buf[0] & FRAGMENT_MORE == 0 {
+                break;
+            }
+        }
+        assert_eq!(received, msg);
+        server.await.unwrap();
+    }
+}
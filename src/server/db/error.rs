@@ -2,7 +2,6 @@
 
 use std::{fmt::Display, error::Error};
 
-
 #[derive(Debug)]
 /// Error type used by the `Db` module.
 pub enum DbError {
@@ -10,11 +9,17 @@ pub enum DbError {
     EngineError(sled::Error),
     /// Error indicating duplicate file was added to database
     DuplicateFile,
+    /// Error indicating files that are still being uploaded would be changed
+    UploadPending,
 }
 
 impl Display for DbError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}", self))
+        match self {
+            DbError::EngineError(e) => write!(f, "Database engine error: {}", e),
+            DbError::DuplicateFile => write!(f, "Duplicate file"),
+            DbError::UploadPending => write!(f, "Upload pending"),
+        }
     }
 }
 
@@ -4,14 +4,29 @@
 
 pub mod error;
 
+use crate::client::CHUNK_SIZE;
+use crate::messaging::arguments::{
+    os_bytes, Capabilities, Chunk, ChunkId, EntryKind, ExtendedMetadata, FileId, FileList,
+    FileMetadata, FilePath,
+};
 use base64ct::{Base64, Encoding};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
 use sled::{
-    transaction::{ConflictableTransactionResult, TransactionalTree, ConflictableTransactionError, TransactionError},
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree, UnabortableTransactionError,
+    },
     IVec, Transactional, Tree,
 };
-use std::{collections::HashSet, fmt::Write, path::Path, vec};
-use crate::messaging::arguments::{Chunk, ChunkId, FileId, FileList, FileMetadata, FilePath};
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fmt::Write,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    vec,
+};
 
 use self::error::DbError;
 
@@ -25,6 +40,13 @@ static CHUNK_TABLE: &str = "chunk_table";
 static CHUNK_COUNT: &str = "chunk_count";
 /// Static name of the missing_chunks table
 static MISSING_CHUNKS: &str = "missing_chunks";
+/// Static name of the meta table
+static META: &str = "meta";
+/// Key of the schema version in the meta table
+static SCHEMA_KEY: &str = "schema_version";
+
+/// Version of the layout file metadata is stored with, see [`Db::migrate`]
+const SCHEMA_VERSION: u32 = 3;
 
 #[derive(Debug)]
 /// The main database stucture to store back-end data.
@@ -42,6 +64,8 @@ pub struct Db {
     pending_table: Tree,
     /// Table to store chunks that the database doesn't have yet
     missing_chunks: Tree,
+    /// Table to store facts about the database itself, like its schema version
+    meta: Tree,
 }
 
 impl Db {
@@ -58,23 +82,138 @@ impl Db {
         let chunk_count = db.open_tree(CHUNK_COUNT)?;
         let pending_table = db.open_tree(PENDING_TABLE)?;
         let missing_chunks = db.open_tree(MISSING_CHUNKS)?;
-        Ok(Db {
+        let meta = db.open_tree(META)?;
+        let db = Db {
             file_table,
             chunk_table,
             chunk_count,
             pending_table,
             missing_chunks,
-        })
+            meta,
+        };
+        db.migrate()?;
+        Ok(db)
     }
 
     pub fn new_temporary() -> sled::Result<Db> {
         let db = sled::Config::new().temporary(true).open()?;
-        Ok(Db {
+        let db = Db {
             file_table: db.open_tree(FILE_TABLE)?,
             chunk_table: db.open_tree(CHUNK_TABLE)?,
             chunk_count: db.open_tree(CHUNK_COUNT)?,
             pending_table: db.open_tree(PENDING_TABLE)?,
             missing_chunks: db.open_tree(MISSING_CHUNKS)?,
+            meta: db.open_tree(META)?,
+        };
+        db.migrate()?;
+        Ok(db)
+    }
+
+    /// Bring the metadata stored by older versions up to date.
+    ///
+    /// The layout of the stored metadata is recorded as the schema version:
+    /// 0. The original layout, held by databases without a version
+    /// 1. Adds the file size. Every chunk but the last one is
+    ///    [`CHUNK_SIZE`](../../client/constant.CHUNK_SIZE.html) long, so the size follows from the
+    ///    length of the last chunk. Uploads that are still missing it are taken to end on a chunk
+    ///    boundary, the client sends their metadata again on reconnect.
+    /// 2. Adds the entry kind, everything stored before is a file
+    /// 3. Adds the extended metadata, which older records have none of
+    ///
+    /// The records and the new version are written at once, so an interrupted migration starts
+    /// over from the same layout.
+    fn migrate(&self) -> sled::Result<()> {
+        let version = match self.meta.get(SCHEMA_KEY)? {
+            Some(x) => std::io::Cursor::new(x).read_u32::<LittleEndian>()?,
+            None => 0,
+        };
+        if version > SCHEMA_VERSION {
+            return Err(sled::Error::Unsupported(format!(
+                "Database schema version {} is newer than {}",
+                version, SCHEMA_VERSION
+            )));
+        }
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        // Bincode writes fields in order, so the fields added at the end are appended
+        let extended = bincode::serialize(&ExtendedMetadata::default()).unwrap();
+        let missing = match version {
+            1 => [bincode::serialize(&EntryKind::File).unwrap(), extended].concat(),
+            2 => extended,
+            _ => vec![],
+        };
+        let mut migrated = [vec![], vec![]];
+        for (table, records) in [&self.file_table, &self.pending_table]
+            .into_iter()
+            .zip(migrated.iter_mut())
+        {
+            for entry in table.iter() {
+                let (key, value) = entry?;
+                let decoded = match version {
+                    0 => bincode::deserialize::<LegacyFileMetadata>(&value)
+                        .map(|legacy| self.add_size(legacy)),
+                    _ => bincode::deserialize::<FileMetadata>(&[&value[..], &missing].concat())
+                        .map(Ok),
+                };
+                let file = match decoded {
+                    Ok(file) => file?,
+                    Err(e) => {
+                        error!("Can't read the metadata of {:?}: {}", display_key(&key), e);
+                        continue;
+                    }
+                };
+                records.push((key, bincode::serialize(&file).unwrap()));
+            }
+        }
+
+        let [files, pending] = &migrated;
+        (&self.file_table, &self.pending_table, &self.meta)
+            .transaction(
+                |(ft, pt, meta)| -> ConflictableTransactionResult<(), sled::Error> {
+                    for (key, value) in files {
+                        ft.insert(key, value.as_slice())?;
+                    }
+                    for (key, value) in pending {
+                        pt.insert(key, value.as_slice())?;
+                    }
+                    meta.insert(SCHEMA_KEY, &SCHEMA_VERSION.to_le_bytes())?;
+                    Ok(())
+                },
+            )
+            .map_err(flatten_error)?;
+        if files.len() + pending.len() > 0 {
+            info!(
+                "Updated the metadata of {} stored files",
+                files.len() + pending.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Work out the size of a file stored before sizes were recorded
+    fn add_size(&self, legacy: LegacyFileMetadata) -> sled::Result<FileMetadata> {
+        let size = match legacy.chunks.split_last() {
+            Some((last, rest)) => {
+                let last_len = match self.chunk_table.get(&last.0)? {
+                    Some(data) => data.len(),
+                    None => CHUNK_SIZE,
+                };
+                (rest.len() * CHUNK_SIZE + last_len) as u64
+            }
+            None => 0,
+        };
+        Ok(FileMetadata {
+            file_id: legacy.file_id,
+            file_name: legacy.file_name,
+            permissions: legacy.permissions,
+            modified: legacy.modified,
+            created: legacy.created,
+            size,
+            chunks: legacy.chunks,
+            kind: EntryKind::File,
+            extended: ExtendedMetadata::default(),
         })
     }
 
@@ -116,7 +255,7 @@ impl Db {
                     let mut new_chunks = vec![];
 
                     // Prevent duplicate entries with the same data
-                    if let Some(x) = ft.get(&file.file_id.path.to_str().unwrap().as_bytes())? {
+                    if let Some(x) = ft.get(path_key(&file.file_id.path))? {
                         let old_file = bincode::deserialize::<FileMetadata>(&x).unwrap();
                         if old_file == *file {
                             // The file is the same as the old
@@ -166,22 +305,20 @@ impl Db {
                         };
                         if (ct.get(&chunk.0)?).is_none() {
                             new_chunks.push(chunk.clone());
-                            let mut ref_files: Vec<String> = match mc.get(&*chunk.0)? {
-                                Some(x) => bincode::deserialize::<Vec<String>>(&x).unwrap(),
+                            let mut ref_files: Vec<Vec<u8>> = match mc.get(&*chunk.0)? {
+                                Some(x) => bincode::deserialize::<Vec<Vec<u8>>>(&x).unwrap(),
                                 None => vec![],
                             };
-                            ref_files.push(file.file_id.path.display().to_string());
+                            ref_files.push(path_key(&file.file_id.path).to_vec());
                             mc.insert(&*chunk.0, bincode::serialize(&ref_files).unwrap())?;
                         }
                     }
 
                     // Add the file metadata to the file table
                     if new_chunks.is_empty() {
-                        ft.insert(file.file_id.path.to_str().unwrap().as_bytes(), &*value)
-                            .unwrap();
+                        ft.insert(path_key(&file.file_id.path), &*value)?;
                     } else {
-                        pt.insert(file.file_id.path.to_str().unwrap().as_bytes(), &*value)
-                            .unwrap();
+                        pt.insert(path_key(&file.file_id.path), &*value)?;
                     }
                     Ok(new_chunks)
                 },
@@ -190,15 +327,14 @@ impl Db {
                 Err(TransactionError::Abort(e)) => {
                     return Err(e);
                 }
-                // TODO: Fix this error handling
-                _ => panic!("Database operation failed"),
+                Err(TransactionError::Storage(e)) => return Err(DbError::EngineError(e)),
             };
         Ok(chunks)
     }
 
-    /// Returns a [File](struct.File.html) from the database when given a file_hash.
-    pub fn get_file(&self, file: &str) -> sled::Result<Option<FileMetadata>> {
-        match self.file_table.get(file) {
+    /// Returns a [File](struct.File.html) from the database when given its path.
+    pub fn get_file(&self, file: &Path) -> sled::Result<Option<FileMetadata>> {
+        match self.file_table.get(path_key(file)) {
             Ok(x) => match x {
                 Some(value) => Ok(
                     Some(bincode::deserialize::<FileMetadata>(&value).expect("Failed to deserialize"))
@@ -239,7 +375,7 @@ impl Db {
                         ct.insert(chunk.id.0.to_vec(), chunk.data.to_owned())?;
                         mc.remove(chunk.id.0.to_vec())?;
                         // TODO: Cleanup Partially transferred files
-                        let files = bincode::deserialize::<Vec<String>>(&x).unwrap();
+                        let files = bincode::deserialize::<Vec<Vec<u8>>>(&x).unwrap();
                         for file in files {
                             if let Some(raw_file) = pt.get(&file)? {
                                 let file_md: FileMetadata =
@@ -252,8 +388,8 @@ impl Db {
                                     }
                                 }
                                 if file_complete {
-                                    debug!("File completed transfer: {:?}", file);
-                                    ft.insert(file.as_bytes(), &pt.remove(&*file)?.unwrap())?;
+                                    debug!("File completed transfer: {:?}", display_key(&file));
+                                    ft.insert(&*file, pt.remove(&*file)?.unwrap())?;
                                     return Ok(Some(file_md.file_id));
                                 }
                             }
@@ -262,69 +398,133 @@ impl Db {
                     Ok(None)
                 },
             )
-            .unwrap();
+            .map_err(flatten_error)?;
         Ok(ret)
     }
 
     /// Gets a chunk out of the database given it's ID (hash).
-    pub fn get_chunk(&self, chunk_hash: [u8; 32]) -> sled::Result<Chunk> {
-        // TODO: Improve error handling
-        match self.chunk_table.get(&chunk_hash) {
-            Ok(x) => match x {
-                Some(value) => Ok(Chunk {
-                    id: ChunkId(chunk_hash.to_vec()),
-                    data: value.to_vec(),
-                }),
-                None => panic!("Chunk not found"),
-            },
-            Err(e) => Err(e),
-        }
+    ///
+    /// Returns `None` when the chunk isn't stored (yet).
+    pub fn get_chunk(&self, chunk_hash: [u8; 32]) -> sled::Result<Option<Chunk>> {
+        Ok(self.chunk_table.get(chunk_hash)?.map(|value| Chunk {
+            id: ChunkId(chunk_hash.to_vec()),
+            data: value.to_vec(),
+        }))
     }
 
-    pub fn rm_file(&self, file_path: &FilePath) {
-        (&self.file_table, &self.chunk_table, &self.chunk_count)
+    /// Remove the entry at `file_path`, along with everything below it when it's a directory.
+    ///
+    /// Uploads below it are dropped as well, so chunks arriving later can't complete them.
+    /// Returns whether anything was stored at `file_path`, not counting those uploads.
+    pub fn rm_file(&self, file_path: &FilePath) -> sled::Result<bool> {
+        let keys = keys_below(&self.file_table, &file_path.0)?;
+        let pending = keys_below(&self.pending_table, &file_path.0)?;
+        (
+            &self.file_table,
+            &self.pending_table,
+            &self.chunk_table,
+            &self.chunk_count,
+            &self.missing_chunks,
+        )
             .transaction(
-                |(ft, ct, cc)| -> ConflictableTransactionResult<(), sled::Error> {
+                |(ft, pt, ct, cc, mc)| -> ConflictableTransactionResult<bool, sled::Error> {
+                    let mut removed = false;
+                    for key in &pending {
+                        if let Some(bin_file) = pt.remove(key.as_slice())? {
+                            let file = bincode::deserialize::<FileMetadata>(&bin_file).unwrap();
+                            drop_upload(ft, ct, cc, mc, key, &file)?;
+                        }
+                    }
                     // 1. Get the file and desearialize it
                     // 2. Iterate through the chunks and decrement the refcounter
                     // 3.   if 0 refs, delete the chunk from the chunk table
-                    if let Ok(Some(bin_file)) = ft.get(&file_path.0.as_bytes()) {
-                        // Deserialize bin into the File struct
-                        if let Ok(file) = bincode::deserialize::<FileMetadata>(&bin_file) {
-                            for chunk in file.chunks {
-                                if let Ok(Some(x)) = cc.get(&chunk.0) {
-                                    let mut rdr = std::io::Cursor::new(x);
-                                    match rdr.read_u32::<LittleEndian>() {
-                                        // If there are no more references to the given chunk,
-                                        // remove it from the chunk table and the chunk count table
-                                        Ok(0) | Ok(1) => {
-                                            ct.remove(&*chunk.0)?;
-                                            cc.remove(&*chunk.0)?;
-                                        }
-                                        Ok(x) => {
-                                            let mut wtr = vec![];
-                                            wtr.write_u32::<LittleEndian>(x - 1).unwrap();
-                                            cc.insert(chunk.0, wtr)?;
-                                        }
-                                        _ => {}
-                                    }
-                                }
+                    for key in &keys {
+                        if let Ok(Some(bin_file)) = ft.get(key) {
+                            // Deserialize bin into the File struct
+                            if let Ok(file) = bincode::deserialize::<FileMetadata>(&bin_file) {
+                                release_chunks(ct, cc, &file.chunks)?;
+                                ft.remove(key.as_slice())?;
+                                removed = true;
                             }
-                            ft.remove(file_path.0.as_bytes()).unwrap();
                         }
                     }
-                    Ok(())
+                    Ok(removed)
                 },
             )
-            .unwrap();
+            .map_err(flatten_error)
     }
 
-    pub fn get_files(&self) -> Result<FileList, sled::Error> {
+    /// Move the file at `from`, or every file below it when it's a directory, to `to`.
+    ///
+    /// The files keep their chunks, and files already stored at the new paths are replaced.
+    /// Returns the number of files moved.
+    ///
+    /// Uploads are tracked by path until they're committed, so nothing is moved while files below
+    /// `from` are still being uploaded, which is an [`UploadPending`](error/enum.DbError.html)
+    /// error.
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<usize, DbError> {
+        let keys = keys_below(&self.file_table, from)?;
+        let mut pending = keys_below(&self.pending_table, from)?;
+        pending.extend_from_slice(&keys);
+
+        let res = (
+            &self.file_table,
+            &self.pending_table,
+            &self.chunk_table,
+            &self.chunk_count,
+        )
+            .transaction(
+                |(ft, pt, ct, cc)| -> ConflictableTransactionResult<usize, DbError> {
+                    for key in &pending {
+                        if pt.get(key)?.is_some() {
+                            return Err(ConflictableTransactionError::Abort(
+                                DbError::UploadPending,
+                            ));
+                        }
+                    }
+                    // Everything is taken out before putting it back, so files moving onto paths
+                    // that are moved themselves aren't lost
+                    let mut files = vec![];
+                    for key in &keys {
+                        if let Some(value) = ft.remove(key.as_slice())? {
+                            files.push(bincode::deserialize::<FileMetadata>(&value).unwrap());
+                        }
+                    }
+                    for file in &mut files {
+                        let rest = file.file_id.path.strip_prefix(from).unwrap();
+                        let path = if rest.as_os_str().is_empty() {
+                            to.to_owned()
+                        } else {
+                            to.join(rest)
+                        };
+                        file.file_name = path.file_name().unwrap_or_default().to_owned();
+                        file.file_id.path = path;
+
+                        let value = bincode::serialize(&file).unwrap();
+                        if let Some(old) = ft.insert(path_key(&file.file_id.path), value)? {
+                            let old: FileMetadata = bincode::deserialize(&old).unwrap();
+                            release_chunks(ct, cc, &old.chunks)?;
+                        }
+                    }
+                    Ok(files.len())
+                },
+            );
+        match res {
+            Ok(x) => Ok(x),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(DbError::EngineError(e)),
+        }
+    }
+
+    /// List the entries a peer supporting `capabilities` can synchronize
+    pub fn get_files(&self, capabilities: Capabilities) -> Result<FileList, sled::Error> {
         let mut files: Vec<FileId> = vec![];
         for file in self.file_table.iter() {
             let file_struct = bincode::deserialize::<FileMetadata>(&file?.1)
                 .expect("Failed to create FileMetadata struct from the database.");
-            files.push(file_struct.file_id);
+            if capabilities.carries(&file_struct.kind) && capabilities.fits(&file_struct) {
+                files.push(file_struct.file_id);
+            }
         }
         Ok(FileList(files))
     }
@@ -336,7 +536,7 @@ impl Db {
         while let Some(Ok((key, value))) = table.next() {
             println!(
                 "Key: {:?}\n{}",
-                String::from_utf8(key.to_vec()).unwrap(),
+                display_key(&key),
                 bincode::deserialize::<FileMetadata>(&value).unwrap()
             );
         }
@@ -346,7 +546,11 @@ impl Db {
             println!(
                 "ChunkId: {}\n - File: {:?}",
                 Base64::encode_string(&key),
-                bincode::deserialize::<Vec<String>>(&value).unwrap()
+                bincode::deserialize::<Vec<Vec<u8>>>(&value)
+                    .unwrap()
+                    .iter()
+                    .map(|x| display_key(x))
+                    .collect::<Vec<_>>()
             );
         }
         let mut table = self.file_table.iter();
@@ -354,7 +558,7 @@ impl Db {
         while let Some(Ok((key, value))) = table.next() {
             println!(
                 "Key: {:?}\n{}",
-                String::from_utf8(key.to_vec()).unwrap(),
+                display_key(&key),
                 bincode::deserialize::<FileMetadata>(&value).unwrap()
             );
         }
@@ -385,6 +589,116 @@ impl Db {
     }
 }
 
+/// File metadata as stored before the size of files was recorded
+#[derive(Serialize, Deserialize)]
+struct LegacyFileMetadata {
+    file_id: FileId,
+    #[serde(with = "os_bytes")]
+    file_name: OsString,
+    permissions: u32,
+    modified: u128,
+    created: u128,
+    chunks: Vec<ChunkId>,
+}
+
+/// Key of a path in the file tables. Paths are stored as raw bytes, which don't have to be valid
+/// UTF-8.
+fn path_key(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+/// Lossy form of a path key, for showing it to humans
+fn display_key(key: &[u8]) -> std::borrow::Cow<'_, str> {
+    OsStr::from_bytes(key).to_string_lossy()
+}
+
+/// Keys of the entry at `path` and of every entry below it in `tree`.
+///
+/// Transactions can't iterate, so the entries a transaction works on are looked up beforehand.
+fn keys_below(tree: &Tree, path: &Path) -> sled::Result<Vec<Vec<u8>>> {
+    let mut keys = vec![path_key(path).to_vec()];
+    let mut prefix = path_key(path).to_vec();
+    prefix.push(b'/');
+    for entry in tree.scan_prefix(&prefix) {
+        keys.push(entry?.0.to_vec());
+    }
+    Ok(keys)
+}
+
+/// Errors of transactions that only abort with storage errors
+fn flatten_error(e: TransactionError<sled::Error>) -> sled::Error {
+    match e {
+        TransactionError::Abort(e) | TransactionError::Storage(e) => e,
+    }
+}
+
+/// Drop the references of an upload that's not committed yet, which was stored at `key`.
+///
+/// Only the chunks the upload added to the stored version of the file hold a reference, see
+/// [`Db::add_file`](struct.Db.html#method.add_file). The upload is also taken off the chunks that
+/// are still missing.
+fn drop_upload(
+    ft: &TransactionalTree,
+    ct: &TransactionalTree,
+    cc: &TransactionalTree,
+    mc: &TransactionalTree,
+    key: &[u8],
+    file: &FileMetadata,
+) -> Result<(), UnabortableTransactionError> {
+    match ft.get(key)? {
+        Some(stored) => {
+            let stored = bincode::deserialize::<FileMetadata>(&stored).unwrap();
+            let added: HashSet<&ChunkId> = file
+                .chunks
+                .iter()
+                .filter(|chunk| !stored.chunks.contains(chunk))
+                .collect();
+            release_chunks(ct, cc, added)?;
+        }
+        None => release_chunks(ct, cc, &file.chunks)?,
+    }
+    for chunk in &file.chunks {
+        if let Some(x) = mc.get(&*chunk.0)? {
+            let mut files = bincode::deserialize::<Vec<Vec<u8>>>(&x).unwrap();
+            files.retain(|file| file != key);
+            if files.is_empty() {
+                mc.remove(&*chunk.0)?;
+            } else {
+                mc.insert(&*chunk.0, bincode::serialize(&files).unwrap())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drop a file's references to `chunks`, removing the chunks nothing references anymore
+fn release_chunks<'a>(
+    ct: &TransactionalTree,
+    cc: &TransactionalTree,
+    chunks: impl IntoIterator<Item = &'a ChunkId>,
+) -> Result<(), UnabortableTransactionError> {
+    for chunk in chunks {
+        if let Ok(Some(x)) = cc.get(&chunk.0) {
+            let mut rdr = std::io::Cursor::new(x);
+            match rdr.read_u32::<LittleEndian>() {
+                // If there are no more references to the given chunk,
+                // remove it from the chunk table and the chunk count table
+                Ok(0) | Ok(1) => {
+                    ct.remove(&*chunk.0)?;
+                    cc.remove(&*chunk.0)?;
+                }
+                Ok(x) => {
+                    let mut wtr = vec![];
+                    wtr.write_u32::<LittleEndian>(x - 1).unwrap();
+                    cc.insert(&*chunk.0, wtr)?;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
 /// This is a poor mans merge operator for TransactionalTrees because they don't support proper
 /// merge operations.
 fn rc_merge(old_value: Option<IVec>, increment: i32) -> Option<Vec<u8>> {
@@ -413,12 +727,15 @@ mod tests {
 
     use super::*;
 
+    /// Modification time of the test file, which has to match when it's read back
+    const TEST_MODIFIED: u128 = 1_600_000_000_000;
+
     // The tests need to be able to use their own temperary database rather than using the global
     // static
 
-    fn run_test<T>(test: T) -> ()
+    fn run_test<T>(test: T)
     where
-        T: FnOnce(Arc<Mutex<Db>>) -> () + panic::UnwindSafe,
+        T: FnOnce(Arc<Mutex<Db>>) + panic::UnwindSafe,
     {
         let db = Arc::new(Mutex::new(Db::new_temporary().unwrap()));
         create_test_data(db.clone());
@@ -434,17 +751,17 @@ mod tests {
                 path: PathBuf::from("TestFile"),
                 hash: [0u8; 32],
             },
-            file_name: "TestFile".to_owned(),
+            file_name: "TestFile".into(),
             permissions: 0b110110000,
-            modified: time::SystemTime::now()
-                .duration_since(time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis(),
+            modified: TEST_MODIFIED,
             created: time::SystemTime::now()
                 .duration_since(time::UNIX_EPOCH)
                 .unwrap()
                 .as_millis(),
+            size: 0,
             chunks: vec![],
+            kind: EntryKind::File,
+            extended: ExtendedMetadata::default(),
         };
         db.add_file(&file);
     }
@@ -458,19 +775,19 @@ mod tests {
                     path: PathBuf::from("TestFile"),
                     hash: [0u8; 32],
                 },
-                file_name: "TestFile".to_owned(),
+                file_name: "TestFile".into(),
                 permissions: 0b110110000,
-                modified: time::SystemTime::now()
-                    .duration_since(time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis(),
+                modified: TEST_MODIFIED,
                 created: time::SystemTime::now()
                     .duration_since(time::UNIX_EPOCH)
                     .unwrap()
                     .as_millis(),
+                size: 0,
                 chunks: vec![],
+                kind: EntryKind::File,
+                extended: ExtendedMetadata::default(),
             };
-            assert_eq!(Some(file), db.get_file("TestFile").unwrap())
+            assert_eq!(Some(file), db.get_file(Path::new("TestFile")).unwrap())
         })
     }
 
@@ -478,8 +795,261 @@ mod tests {
     fn test_file_rm() {
         run_test(|db| {
             let db = db.lock().unwrap();
-            db.rm_file(&FilePath("TestFile".to_owned()));
-            assert_eq!(None, db.get_file("TestFile").unwrap())
+            db.rm_file(&FilePath("TestFile".into()));
+            assert_eq!(None, db.get_file(Path::new("TestFile")).unwrap())
+        })
+    }
+
+    #[test]
+    fn test_rm_dir() {
+        let db = Db::new_temporary().unwrap();
+        let entry = |path: &str, kind| FileMetadata {
+            file_id: FileId {
+                path: PathBuf::from(path),
+                hash: [0u8; 32],
+            },
+            file_name: Path::new(path).file_name().unwrap().to_owned(),
+            permissions: 0o755,
+            modified: 0,
+            created: 0,
+            size: 0,
+            chunks: vec![],
+            kind,
+            extended: ExtendedMetadata::default(),
+        };
+        db.add_file(&entry("dir", EntryKind::Directory)).unwrap();
+        db.add_file(&entry("dir/empty", EntryKind::Directory))
+            .unwrap();
+        db.add_file(&entry("dir/sub/file", EntryKind::File))
+            .unwrap();
+        db.add_file(&entry("dirty", EntryKind::File)).unwrap();
+        // Links to directories are entries of their own, which stay when their target is removed
+        let link = entry("link", EntryKind::Symlink("dir".into()));
+        db.add_file(&link).unwrap();
+
+        assert!(db.rm_file(&FilePath("dir".into())).unwrap());
+        assert!(!db.rm_file(&FilePath("dir".into())).unwrap());
+        let mut files = db.get_files(crate::messaging::CAPABILITIES).unwrap().0;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, PathBuf::from("dirty"));
+        assert_eq!(db.get_file(Path::new("link")).unwrap(), Some(link));
+    }
+
+    #[test]
+    fn test_rm_pending() {
+        let db = Db::new_temporary().unwrap();
+        let chunk = Chunk {
+            id: ChunkId([5u8; 32].to_vec()),
+            data: vec![1, 2, 3],
+        };
+        let file = FileMetadata {
+            file_id: FileId {
+                path: PathBuf::from("dir/file"),
+                hash: [0u8; 32],
+            },
+            file_name: "file".into(),
+            permissions: 0o644,
+            modified: 0,
+            created: 0,
+            size: 3,
+            chunks: vec![chunk.id.clone()],
+            kind: EntryKind::File,
+            extended: ExtendedMetadata::default(),
+        };
+        assert_eq!(db.add_file(&file).unwrap(), vec![chunk.id.clone()]);
+
+        // Nothing was committed yet
+        assert!(!db.rm_file(&FilePath("dir".into())).unwrap());
+        // The chunk of the dropped upload isn't wanted anymore
+        assert_eq!(db.add_chunk(&chunk).unwrap(), None);
+        assert_eq!(db.get_file(Path::new("dir/file")).unwrap(), None);
+        assert_eq!(db.get_chunk([5u8; 32]).unwrap(), None);
+        assert!(db.pending_table.is_empty() && db.missing_chunks.is_empty());
+        assert!(db.chunk_count.is_empty());
+    }
+
+    #[test]
+    fn test_rename() {
+        let db = Db::new_temporary().unwrap();
+        let file = |path: &str, chunks: Vec<ChunkId>| FileMetadata {
+            file_id: FileId {
+                path: PathBuf::from(path),
+                hash: [0u8; 32],
+            },
+            file_name: Path::new(path).file_name().unwrap().to_owned(),
+            permissions: 0o644,
+            modified: 0,
+            created: 0,
+            size: 0,
+            chunks,
+            kind: EntryKind::File,
+            extended: ExtendedMetadata::default(),
+        };
+        for path in ["dir/a", "dir/sub/b", "dirty"] {
+            db.add_file(&file(path, vec![])).unwrap();
+        }
+        let chunk = Chunk {
+            id: ChunkId([5u8; 32].to_vec()),
+            data: vec![1, 2, 3],
+        };
+        db.add_file(&file("moved/a", vec![chunk.id.clone()]))
+            .unwrap();
+        db.add_chunk(&chunk).unwrap();
+
+        assert_eq!(db.rename(Path::new("dir"), Path::new("moved")).unwrap(), 2);
+        assert_eq!(db.get_file(Path::new("dir/a")).unwrap(), None);
+        let moved = db.get_file(Path::new("moved/sub/b")).unwrap().unwrap();
+        assert_eq!(moved.file_id.path, PathBuf::from("moved/sub/b"));
+        assert_eq!(moved.file_name, "b");
+        // Only whole path components match
+        assert!(db.get_file(Path::new("dirty")).unwrap().is_some());
+        // The replaced file let go of its chunk
+        assert_eq!(
+            db.get_file(Path::new("moved/a")).unwrap(),
+            Some(file("moved/a", vec![]))
+        );
+        assert_eq!(db.get_chunk([5u8; 32]).unwrap(), None);
+
+        assert_eq!(
+            db.rename(Path::new("dirty"), Path::new("clean")).unwrap(),
+            1
+        );
+        assert_eq!(
+            db.rename(Path::new("dirty"), Path::new("clean")).unwrap(),
+            0
+        );
+        // Uploads are tracked by path, so their directories stay until they're committed
+        let chunk = ChunkId([6u8; 32].to_vec());
+        db.add_file(&file("moved/sub/c", vec![chunk.clone()]))
+            .unwrap();
+        assert!(matches!(
+            db.rename(Path::new("moved"), Path::new("dir")),
+            Err(DbError::UploadPending)
+        ));
+        assert!(db.get_file(Path::new("moved/a")).unwrap().is_some());
+        db.add_chunk(&Chunk {
+            id: chunk,
+            data: vec![4],
+        })
+        .unwrap();
+        assert_eq!(db.rename(Path::new("moved"), Path::new("dir")).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let at_version = |version: u32| {
+            let db = Db::new_temporary().unwrap();
+            db.meta.insert(SCHEMA_KEY, &version.to_le_bytes()).unwrap();
+            db
+        };
+
+        // Databases without a version hold the original layout
+        let db = Db::new_temporary().unwrap();
+        db.meta.remove(SCHEMA_KEY).unwrap();
+        let legacy = LegacyFileMetadata {
+            file_id: FileId {
+                path: PathBuf::from("OldFile"),
+                hash: [2u8; 32],
+            },
+            file_name: "OldFile".into(),
+            permissions: 0o644,
+            modified: 1,
+            created: 2,
+            chunks: vec![ChunkId([3u8; 32].to_vec()), ChunkId([4u8; 32].to_vec())],
+        };
+        db.file_table
+            .insert("OldFile", bincode::serialize(&legacy).unwrap())
+            .unwrap();
+        db.chunk_table.insert([4u8; 32], vec![0u8; 10]).unwrap();
+        db.migrate().unwrap();
+        let file = db.get_file(Path::new("OldFile")).unwrap().unwrap();
+        assert_eq!(file.size, CHUNK_SIZE as u64 + 10);
+        assert_eq!(file.chunks, legacy.chunks);
+        assert_eq!(file.kind, EntryKind::File);
+        assert_eq!(
+            db.meta.get(SCHEMA_KEY).unwrap().unwrap(),
+            SCHEMA_VERSION.to_le_bytes()
+        );
+        // Records that are already migrated are left alone
+        db.migrate().unwrap();
+        assert_eq!(db.get_file(Path::new("OldFile")).unwrap(), Some(file));
+
+        // A record with a size, but from before entry kinds
+        let db = at_version(1);
+        let sized = FileMetadata {
+            file_id: FileId {
+                path: PathBuf::from("SizedFile"),
+                hash: [5u8; 32],
+            },
+            file_name: "SizedFile".into(),
+            permissions: 0o644,
+            modified: 1,
+            created: 2,
+            size: 42,
+            chunks: vec![ChunkId([6u8; 32].to_vec())],
+            kind: EntryKind::File,
+            extended: ExtendedMetadata::default(),
+        };
+        let extended_len = bincode::serialize(&ExtendedMetadata::default())
+            .unwrap()
+            .len();
+        let mut value = bincode::serialize(&sized).unwrap();
+        value.truncate(
+            value.len() - bincode::serialize(&EntryKind::File).unwrap().len() - extended_len,
+        );
+        db.pending_table.insert("SizedFile", value).unwrap();
+        db.migrate().unwrap();
+        let stored = db.pending_table.get("SizedFile").unwrap().unwrap();
+        assert_eq!(
+            bincode::deserialize::<FileMetadata>(&stored).unwrap(),
+            sized
+        );
+
+        // A record from before extended metadata
+        let db = at_version(2);
+        let mut kinded = sized.clone();
+        kinded.file_id.path = PathBuf::from("KindedFile");
+        kinded.file_name = "KindedFile".into();
+        kinded.kind = EntryKind::Directory;
+        let mut value = bincode::serialize(&kinded).unwrap();
+        value.truncate(value.len() - extended_len);
+        db.file_table.insert("KindedFile", value).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_file(Path::new("KindedFile")).unwrap(), Some(kinded));
+
+        // Databases written by newer versions can't be read
+        let db = at_version(SCHEMA_VERSION + 1);
+        assert!(matches!(db.migrate(), Err(sled::Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_non_utf8_file() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        run_test(|db| {
+            let db = db.lock().unwrap();
+            // Latin-1 "café"
+            let name = OsStr::from_bytes(b"caf\xe9");
+            let path = Path::new("dir").join(name);
+            let file = FileMetadata {
+                file_id: FileId {
+                    path: path.clone(),
+                    hash: [1u8; 32],
+                },
+                file_name: name.to_owned(),
+                permissions: 0b110110000,
+                modified: 0,
+                created: 0,
+                size: 0,
+                chunks: vec![],
+                kind: EntryKind::File,
+                extended: ExtendedMetadata::default(),
+            };
+            db.add_file(&file).unwrap();
+            assert_eq!(Some(file), db.get_file(&path).unwrap());
+            db.rm_file(&FilePath(path.clone())).unwrap();
+            assert_eq!(None, db.get_file(&path).unwrap());
         })
     }
 }
@@ -0,0 +1,152 @@
+//! Listening sockets for the server's [`Endpoint`](../../config/enum.Endpoint.html)s
+
+use crate::{config::Endpoint, net::BoxedTransport};
+use socket2::{Domain, Socket, Type};
+use std::{
+    fs, io,
+    net::SocketAddr,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::Path,
+};
+use tokio::net::{lookup_host, TcpListener, UnixListener};
+
+/// Backlog used for the TCP listening sockets
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// A bound listening socket
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, String),
+}
+
+impl Listener {
+    /// Bind every socket needed to listen on an endpoint.
+    ///
+    /// A TCP endpoint can resolve to multiple addresses (`localhost` for instance), in which case
+    /// a listener is bound for each one of them.
+    pub async fn bind(endpoint: &Endpoint) -> io::Result<Vec<Listener>> {
+        match endpoint {
+            Endpoint::Tcp(addr) => {
+                let mut listeners = vec![];
+                for addr in lookup_host(addr).await? {
+                    listeners.push(Listener::Tcp(bind_tcp(addr)?));
+                }
+                Ok(listeners)
+            }
+            Endpoint::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(vec![Listener::Unix(
+                    UnixListener::bind(path)?,
+                    endpoint.to_string(),
+                )])
+            }
+        }
+    }
+
+    /// Accept a new connection, returning the stream along with a printable peer address
+    pub async fn accept(&self) -> io::Result<(BoxedTransport, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), addr.to_string()))
+            }
+            Listener::Unix(listener, name) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), name.clone()))
+            }
+        }
+    }
+
+    /// The local address the listener is bound to
+    pub fn local_addr(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "unknown".to_owned(),
+            },
+            Listener::Unix(_, name) => name.clone(),
+        }
+    }
+}
+
+/// Bind a TCP listener.
+///
+/// IPv6 sockets are made IPv6 only, so `0.0.0.0:port` and `[::]:port` can be listened on at the
+/// same time.
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Remove a socket file left behind by a previous run.
+///
+/// Anything that isn't a socket is left alone so a typo in the config can't delete a file. A
+/// socket is only stale when nothing listens on it anymore, the socket of a server that's still
+/// running is an error.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(md) if md.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is used by a running server", path.display()),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+            Err(e) => Err(e),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UnixStream},
+    };
+
+    #[tokio::test]
+    async fn test_listeners() {
+        let socket_path = std::env::temp_dir().join(format!("phoenix-{}.sock", std::process::id()));
+        let endpoints = vec![
+            Endpoint::Tcp("127.0.0.1:0".to_owned()),
+            Endpoint::Unix(socket_path.clone()),
+        ];
+
+        let mut listeners = vec![];
+        for endpoint in &endpoints {
+            listeners.append(&mut Listener::bind(endpoint).await.unwrap());
+        }
+        assert_eq!(listeners.len(), 2);
+
+        let mut tcp = TcpStream::connect(listeners[0].local_addr()).await.unwrap();
+        let mut unix = UnixStream::connect(&socket_path).await.unwrap();
+        tcp.write_all(b"tcp").await.unwrap();
+        unix.write_all(b"unix").await.unwrap();
+
+        let (mut stream, _) = listeners[0].accept().await.unwrap();
+        let mut buf = [0u8; 3];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"tcp");
+
+        let (mut stream, peer) = listeners[1].accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"unix");
+        assert_eq!(peer, endpoints[1].to_string());
+
+        // The socket of a running server is left alone, a stale one is replaced
+        let e = Listener::bind(&endpoints[1]).await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        drop(listeners);
+        Listener::bind(&endpoints[1]).await.unwrap();
+        fs::remove_file(socket_path).unwrap();
+    }
+}
@@ -1,130 +1,605 @@
 mod db;
+mod listener;
 
 use super::{
-    config::{Config, ServerConfig},
+    config::{self, Config, ServerConfig},
     messaging::MessageBuilder,
-    net::{NetServer, NoiseConnection},
+    net::{error::NetError, Duplex, Heartbeat, NetServer, NoiseConnection, RateLimiter, Transport},
 };
 use crate::{
-    client::CHUNK_SIZE,
+    client::{backoff::Backoff, ChunkBatches},
     messaging::{
-        arguments::{Chunk, FileId, FileMetadata, FilePath, QualifiedChunk, QualifiedChunkId},
-        Directive,
+        arguments::{
+            is_safe_path, Capabilities, ChunkId, ErrorCode, FileId, FileMetadata, MetadataList,
+            ProtocolError, QualifiedChunk, QualifiedChunkId, ResponseCode,
+        },
+        mux::{MuxSender, CONTROL_STREAM},
+        Message, PROTOCOL_VERSION,
     },
 };
 use base64ct::{Base64, Encoding};
 use db::error::DbError;
 use db::Db;
-use std::{path::Path, sync::Arc, time::Duration};
+use listener::Listener;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{
-    net::TcpListener,
     select,
-    sync::mpsc::{self, Receiver, Sender},
+    signal::unix::{signal, SignalKind},
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+        watch,
+    },
+    time::timeout,
 };
 
-type TxRxHandles = (Sender<Sender<Vec<u8>>>, Receiver<Sender<Vec<u8>>>);
+type TxRxHandles = (Sender<Sender<Message>>, Receiver<Sender<Message>>);
+
+/// Number of received messages buffered for each connection
+const INCOMING_CAPACITY: usize = 16;
+/// Delay before accepting again after the first failure
+const ACCEPT_MIN_DELAY: Duration = Duration::from_millis(10);
+/// Upper bound on the delay before accepting again
+const ACCEPT_MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Number of handshakes refused because the client's key wasn't known
+static REFUSED_HANDSHAKES: AtomicUsize = AtomicUsize::new(0);
+
+/// State shared between every connection handled by the server
+#[derive(Clone)]
+struct ServerState {
+    config: Arc<ServerConfig>,
+    db: Arc<Db>,
+    /// Channel used to register a new connection with the broadcast system
+    threads_tx: Sender<Sender<Message>>,
+    /// Channel used to broadcast a message to every connection
+    broadcast: Sender<Message>,
+    /// Current bandwidth limit of each connection in bytes/s
+    client_limit: watch::Receiver<Option<u64>>,
+}
+
+/// State of a single client connection
+struct Session {
+    svc: MuxSender,
+    msg_builder: MessageBuilder,
+    /// Optional features agreed on with the client
+    capabilities: Capabilities,
+    /// Id of the next stream opened by the server. These ids are even.
+    next_stream: u16,
+    /// Channel used to broadcast a message to every connection
+    broadcast: Sender<Message>,
+    /// Id and hash of the upload request of every file that isn't committed yet, by path
+    uploads: HashMap<PathBuf, (u16, [u8; 32])>,
+    /// `SendFileBatch` requests that aren't answered yet, by id
+    batches: HashMap<u16, Batch>,
+}
+
+/// A `SendFileBatch` request waiting for its files to be committed
+struct Batch {
+    /// Number of files that aren't committed yet
+    waiting: usize,
+    /// Files committed so far. They're broadcast together once the request is answered.
+    committed: Vec<FileMetadata>,
+}
+
+impl Session {
+    /// Answer the client's request `id`
+    fn respond(&self, id: u16, code: ResponseCode) {
+        let _ = self
+            .svc
+            .answer(CONTROL_STREAM, self.msg_builder.encode_response(id, code));
+    }
+
+    /// Answer the message `raw_msg` received from the client with `error`.
+    ///
+    /// Errors about requests on a stream opened by the client take the place of the answer, so
+    /// the stream's credit is handed back.
+    fn send_error(&self, raw_msg: &[u8], error: &ProtocolError) {
+        let (id, stream) = message_header(raw_msg);
+        let _ = if stream % 2 == 1 {
+            self.svc
+                .answer(stream, self.msg_builder.encode_error(id, stream, error))
+        } else {
+            self.svc.answer(
+                CONTROL_STREAM,
+                self.msg_builder.encode_error(id, CONTROL_STREAM, error),
+            )
+        };
+    }
+
+    /// Send a message the client didn't ask for, like a change or a ping, on the control stream.
+    ///
+    /// Messages that can't be encoded for the client are dropped.
+    fn push(&mut self, message: Message) -> Result<(), NetError> {
+        match self.msg_builder.encode_message(message) {
+            Ok(msg) => self.svc.send(CONTROL_STREAM, msg),
+            Err(e) => {
+                error!("Couldn't encode a message for the client: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Account for the credit used by `raw_msg` when it's a request on a stream opened by the
+    /// client, or a message on the control stream. Fails when the client exceeded its credit.
+    fn accept(&self, raw_msg: &[u8]) -> Result<(), NetError> {
+        match message_header(raw_msg) {
+            (_, stream) if stream % 2 == 1 || stream == CONTROL_STREAM => {
+                self.svc.accept_request(stream)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Open a new stream for a file transfer
+    fn open_stream(&mut self) -> u16 {
+        let stream = self.next_stream;
+        self.next_stream = match self.next_stream.wrapping_add(2) {
+            CONTROL_STREAM => 2,
+            x => x,
+        };
+        stream
+    }
+
+    /// Request `chunks` from the client on a stream of their own, so the upload can't hold up the
+    /// others. Nothing is requested when any of the chunks can't be addressed.
+    fn request_chunks(&mut self, chunks: ChunkBatches) -> Result<(), ProtocolError> {
+        let stream = self.open_stream();
+        let messages = chunks
+            .into_messages(self.capabilities.contains(Capabilities::BATCH))
+            .into_iter()
+            .map(|message| self.msg_builder.encode_stream_message(stream, message))
+            .collect::<Result<Vec<_>, _>>()?;
+        for msg in messages {
+            let _ = self.svc.send(stream, msg);
+        }
+        Ok(())
+    }
+
+    /// Wait for the upload of `file` to be committed before answering the request `id`
+    async fn add_upload(&mut self, id: u16, file: &FileId) {
+        if let Some(batch) = self.batches.get_mut(&id) {
+            batch.waiting += 1;
+        }
+        if let Some((old_id, _)) = self.uploads.insert(file.path.clone(), (id, file.hash)) {
+            // The client moved on to a newer version of the file
+            self.finish(old_id).await;
+        }
+    }
+
+    /// Broadcast `file`, which the client's chunks just committed, and answer the request that
+    /// uploaded it
+    async fn commit(&mut self, file: FileMetadata) {
+        match self.take_upload(&file.file_id) {
+            // Files of a batch are broadcast along with the rest of it
+            Some(id) if self.batches.contains_key(&id) => {
+                if let Some(batch) = self.batches.get_mut(&id) {
+                    batch.committed.push(file);
+                }
+                self.finish(id).await;
+            }
+            id => {
+                if let Some(id) = id {
+                    self.respond(id, ResponseCode::OK);
+                }
+                self.broadcast_files(vec![file]).await;
+            }
+        }
+    }
+
+    /// Answer the request that uploaded `file`, which another client committed and broadcast
+    async fn upload_committed(&mut self, file: &FileId) {
+        if let Some(id) = self.take_upload(file) {
+            self.finish(id).await;
+        }
+    }
+
+    /// Id of the request uploading `file`, which isn't waited on anymore
+    fn take_upload(&mut self, file: &FileId) -> Option<u16> {
+        match self.uploads.get(&file.path) {
+            Some(&(id, hash)) if hash == file.hash => {
+                self.uploads.remove(&file.path);
+                Some(id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Count one file of the request `id` as committed, answering the request once every file is
+    async fn finish(&mut self, id: u16) {
+        let committed = match self.batches.get_mut(&id) {
+            Some(batch) if batch.waiting > 1 => {
+                batch.waiting -= 1;
+                return;
+            }
+            Some(_) => self.batches.remove(&id).unwrap().committed,
+            None => vec![],
+        };
+        self.respond(id, ResponseCode::OK);
+        self.broadcast_files(committed).await;
+    }
+
+    /// Give up on the batch `id`, which is answered with an error. The files it already committed
+    /// still reach the other clients.
+    async fn abort_batch(&mut self, id: u16) {
+        self.uploads.retain(|_, (upload, _)| *upload != id);
+        if let Some(batch) = self.batches.remove(&id) {
+            self.broadcast_files(batch.committed).await;
+        }
+    }
 
-pub async fn start_server(config_file: &Path) {
+    /// Broadcast committed files to every connection, in a single message when there's more than
+    /// one
+    async fn broadcast_files(&mut self, mut files: Vec<FileMetadata>) {
+        let msg = match files.len() {
+            0 => return,
+            1 => Message::SendFile(files.pop().unwrap()),
+            _ => Message::SendFileBatch(MetadataList(files)),
+        };
+        self.broadcast.send(msg).await.unwrap();
+    }
+
+    /// Forward a message broadcast by any connection, including this one, to the client
+    async fn forward(&mut self, msg: Message) -> Result<(), NetError> {
+        let msg = match msg {
+            Message::SendFile(mut metadata) => {
+                // Uploads can be completed by the chunks of another client
+                self.upload_committed(&metadata.file_id).await;
+                // The client only gets the entries and extended metadata it supports
+                if !self.capabilities.carries(&metadata.kind) || !self.capabilities.fits(&metadata)
+                {
+                    return Ok(());
+                }
+                metadata.extended.restrict(self.capabilities);
+                Message::SendFile(metadata)
+            }
+            Message::SendFileBatch(mut files) => {
+                for file in &files.0 {
+                    self.upload_committed(&file.file_id).await;
+                }
+                let capabilities = self.capabilities;
+                files
+                    .0
+                    .retain(|file| capabilities.carries(&file.kind) && capabilities.fits(file));
+                for file in &mut files.0 {
+                    file.extended.restrict(capabilities);
+                }
+                // Clients that can't take batches get the files one by one
+                if files.0.len() < 2 || !capabilities.contains(Capabilities::BATCH) {
+                    for file in files.0 {
+                        self.push(Message::SendFile(file))?;
+                    }
+                    return Ok(());
+                }
+                Message::SendFileBatch(files)
+            }
+            // Clients that can't move files pick up the new paths when they reconnect
+            Message::RenameFile(_) if !self.capabilities.contains(Capabilities::RENAME) => {
+                return Ok(())
+            }
+            msg => msg,
+        };
+        self.push(msg)
+    }
+}
+
+/// Start the server.
+///
+/// When `stdio` is set, a single connection is served over stdin/stdout instead of listening on
+/// the configured address. This is meant to be spawned over ssh by the client's
+/// `server_command` option.
+pub async fn start_server(config_file: &Path, stdio: bool) {
     let config = Arc::new(ServerConfig::read_config(config_file).expect("Bad config"));
     let db = Arc::new(Db::new(&config.storage_path).expect("Failed to open database"));
 
-    // Construct TcpListener
-    let listener = TcpListener::bind(&config.bind_address).await.unwrap();
-
     // Store channel senders for each client connection thread
-    let (threads_tx, mut threads_rx): TxRxHandles = mpsc::channel(100);
-    let (broadcast_tx, mut broadcast_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel(100);
-
-    // Broadcast thread
-    tokio::spawn(async move {
-        let mut threads: Vec<Sender<Vec<u8>>> = vec![];
-        let mut remove_queue: Vec<usize> = vec![];
-        loop {
-            select! {
-                t = (&mut threads_rx).recv() => {
-                    match t {
-                        None => error!("threads_rx channel dropped"),
-                        Some(x) => {
-                            threads.push(x);
-                            debug!("Added a client thread to the broadcast system.");
-                        }
-                    }
-                },
-                raw_msg = (&mut broadcast_rx).recv() => {
-                    if let Some(msg) = raw_msg {
-                        for (i, thread) in threads.iter().enumerate() {
-                            if thread.send(msg.clone()).await.is_err() {
-                                // Assume the recieving thread died
-                                remove_queue.push(i);
-                            }
-                        };
-                        debug!("Broadcasted a message through the system.");
+    let (threads_tx, threads_rx): TxRxHandles = mpsc::channel(100);
+    let (broadcast_tx, broadcast_rx): (Sender<Message>, Receiver<Message>) = mpsc::channel(100);
+    tokio::spawn(broadcast_system(threads_rx, broadcast_rx));
+
+    let (limit_tx, client_limit) = watch::channel(config::limit_bytes(config.client_limit));
+    tokio::spawn(reload_limits(config_file.to_owned(), limit_tx));
+
+    let state = ServerState {
+        config: config.clone(),
+        db,
+        threads_tx,
+        broadcast: broadcast_tx,
+        client_limit,
+    };
+
+    if stdio {
+        // stdout carries the protocol, so everything else has to go through the logger (stderr)
+        info!("Serving a single connection over stdio");
+        let stream = Duplex::new(tokio::io::stdin(), tokio::io::stdout());
+        handle_connection(state, stream, "stdio".to_owned()).await;
+        return;
+    }
+
+    // Bind every configured endpoint before accepting anything
+    let mut listeners: Vec<Listener> = vec![];
+    for endpoint in &config.bind_address {
+        match Listener::bind(endpoint).await {
+            Ok(mut x) => listeners.append(&mut x),
+            Err(e) => {
+                error!("Failed to listen on {}: {}", endpoint, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Iterate through the streams of every listener
+    let mut accept_loops = vec![];
+    for listener in listeners {
+        info!("Listening for connections on {}...", listener.local_addr());
+        let state = state.clone();
+        accept_loops.push(tokio::spawn(async move {
+            // Errors like running out of file descriptors last until connections are closed, so
+            // retrying right away would only spin
+            let mut backoff = Backoff::new(ACCEPT_MIN_DELAY, ACCEPT_MAX_DELAY);
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!("Failed to accept a connection: {}", e);
+                        tokio::time::sleep(backoff.next_delay()).await;
+                        continue;
                     }
-                },
-            };
-            while let Some(i) = remove_queue.pop() {
-                debug!("Removed an old broadcast channel handel");
-                threads.remove(i);
+                };
+                backoff.reset();
+                info!("Spawning connection...");
+
+                // Spawn thread to handle each stream
+                tokio::spawn(handle_connection(state.clone(), stream, addr));
             }
-            tokio::time::sleep(Duration::from_millis(1000)).await;
+        }));
+    }
+    for accept_loop in accept_loops {
+        accept_loop.await.unwrap();
+    }
+}
+
+/// Re-read the bandwidth limit from the config file on every SIGHUP.
+///
+/// Connections pick up the new limit right away. Every other setting needs a restart.
+async fn reload_limits(config_file: PathBuf, client_limit: watch::Sender<Option<u64>>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Can't listen for SIGHUP, limits won't be reloaded: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match ServerConfig::read_config(&config_file) {
+            Ok(config) => {
+                client_limit.send_replace(config::limit_bytes(config.client_limit));
+                info!("Reloaded bandwidth limit: {:?} KiB/s", config.client_limit);
+            }
+            Err(e) => error!("Failed to reload {}: {}", config_file.display(), e),
         }
-    });
+    }
+}
 
-    // Iterate through streams
-    println!("Listening for connections on {}...", config.bind_address);
+/// Forward every broadcasted message to all of the registered connections.
+///
+/// Connections that fall behind by a full channel are dropped rather than holding up the others.
+/// Their clients catch up with the file list when they reconnect.
+async fn broadcast_system(
+    mut threads_rx: Receiver<Sender<Message>>,
+    mut broadcast_rx: Receiver<Message>,
+) {
+    let mut threads: Vec<Sender<Message>> = vec![];
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
-        println!("Spawning connection...");
-
-        // Create channel to to recieve push events
-        let (msg_tx, mut msg_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel(100);
-        debug!("threads_tx still alive: {:?}", threads_tx);
-        threads_tx.send(msg_tx).await.unwrap();
-
-        // Spawn thread to handle each stream
-        let config = config.clone();
-        let db = db.clone();
-        let broadcast = broadcast_tx.clone();
-        tokio::spawn(async move {
-            // Create new Server for use with noise layer
-            let mut svc = NetServer::new(
-                stream,
-                &Base64::decode_vec(&config.privkey).expect("Couldn't decode private key"),
-                &config
-                    .clients
-                    .iter()
-                    .map(|x| Base64::decode_vec(x).unwrap())
-                    .collect::<Vec<Vec<u8>>>(),
-            )
-            .await
-            .unwrap();
-            info!("Connection established!");
+        select! {
+            // Connections are registered before the messages broadcast after they were
+            biased;
+            t = threads_rx.recv() => {
+                match t {
+                    None => {
+                        error!("threads_rx channel dropped");
+                        return;
+                    }
+                    Some(x) => {
+                        threads.push(x);
+                        debug!("Added a client thread to the broadcast system.");
+                    }
+                }
+            },
+            msg = broadcast_rx.recv() => {
+                let msg = match msg {
+                    Some(x) => x,
+                    None => {
+                        error!("broadcast_rx channel dropped");
+                        return;
+                    }
+                };
+                threads.retain(|thread| match thread.try_send(msg.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Dropping a connection that fell behind on broadcasts");
+                        false
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                });
+                debug!("Broadcasted a message through the system.");
+            },
+        };
+        // Drop the channels of sessions that ended
+        let count = threads.len();
+        threads.retain(|thread| !thread.is_closed());
+        if threads.len() != count {
+            debug!(
+                "Removed {} old broadcast channel handles",
+                count - threads.len()
+            );
+        }
+    }
+}
 
-            //while let Ok(raw_msg) = &svc.recv().await {}
-            let mut msg_builder = MessageBuilder::new(1);
-            loop {
-                select! {
-                    // Messages from the client
-                    raw_msg = svc.recv() => {
-                        match raw_msg {
-                            Ok(msg) => {
-                                handle_client_msg(&mut svc,
-                                    &db,
-                                    &mut msg_builder,
-                                    &broadcast,
-                                    &msg).await;
-                            },
-                            Err(_) => break,
+/// Run the Noise handshake and serve a single client connection until it disconnects
+async fn handle_connection<S: Transport + 'static>(state: ServerState, stream: S, peer: String) {
+    let config = &state.config;
+
+    // Create new Server for use with noise layer
+    let mut svc = match NetServer::new(
+        stream,
+        &Base64::decode_vec(&config.privkey).expect("Couldn't decode private key"),
+        &config
+            .clients
+            .iter()
+            .map(|x| Base64::decode_vec(x).unwrap())
+            .collect::<Vec<Vec<u8>>>(),
+    )
+    .await
+    {
+        Ok(svc) => svc,
+        Err(NetError::UnknownKey(key)) => {
+            let refused = REFUSED_HANDSHAKES.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "Refused connection from {} with unknown key {} ({} refused so far)",
+                peer, key, refused
+            );
+            return;
+        }
+        Err(e) => {
+            error!("Handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    info!("Connection established!");
+    svc.set_limiters(
+        RateLimiter::new(state.client_limit.clone()),
+        RateLimiter::new(state.client_limit.clone()),
+    );
+
+    // Read the client's messages in the background so sending large responses or broadcasts never
+    // keeps the client's messages from being read
+    let (svc, incoming) = svc.split();
+    let mut incoming = incoming.spawn(INCOMING_CAPACITY);
+    let mut session = Session {
+        svc: MuxSender::spawn(svc),
+        msg_builder: MessageBuilder::new(PROTOCOL_VERSION),
+        capabilities: Capabilities::default(),
+        next_stream: 2,
+        broadcast: state.broadcast.clone(),
+        uploads: HashMap::new(),
+        batches: HashMap::new(),
+    };
+
+    // Both sides announce their version before anything else. The client gets our announcement
+    // even when its version is refused, so it can tell why.
+    let announcement = session.msg_builder.announce_version();
+    if let Err(e) = session.svc.send(CONTROL_STREAM, announcement) {
+        debug!("Connection to {} closed: {}", peer, e);
+        return;
+    }
+    let announcement =
+        match timeout(Duration::from_secs(config.idle_timeout), incoming.recv()).await {
+            Ok(Ok(x)) => x,
+            Ok(Err(e)) => {
+                debug!("Connection to {} closed: {}", peer, e);
+                return;
+            }
+            Err(_) => {
+                warn!("{} didn't announce its protocol version in time", peer);
+                return;
+            }
+        };
+    match session.msg_builder.negotiate(&announcement) {
+        Ok(capabilities) => {
+            debug!("Agreed on capabilities {:?} with {}", capabilities, peer);
+            session.capabilities = capabilities;
+            let compression = capabilities.contains(Capabilities::COMPRESSION);
+            if let Err(e) = session.svc.set_compression(compression) {
+                debug!("Connection to {} closed: {}", peer, e);
+                return;
+            }
+        }
+        Err(e) => {
+            error!("Refused connection from {}: {}", peer, e);
+            return;
+        }
+    }
+
+    // Create channel to to recieve push events
+    let (msg_tx, mut msg_rx): (Sender<Message>, Receiver<Message>) = mpsc::channel(100);
+    debug!("threads_tx still alive: {:?}", state.threads_tx);
+    state.threads_tx.send(msg_tx).await.unwrap();
+
+    //while let Ok(raw_msg) = &svc.recv().await {}
+    let mut heartbeat = Heartbeat::new(
+        Duration::from_secs(config.heartbeat_interval),
+        Duration::from_secs(config.idle_timeout),
+    );
+    loop {
+        select! {
+            // Messages from the client
+            raw_msg = incoming.recv() => {
+                match raw_msg {
+                    Ok(msg) => {
+                        heartbeat.seen();
+                        if let Err(e) = session.accept(&msg) {
+                            warn!("Dropping connection to {}: {}", peer, e);
+                            break;
                         }
+                        if let Err(e) = handle_client_msg(&mut session, &state.db, &msg).await {
+                            debug!("Couldn't handle a message from {}: {}", peer, e);
+                            session.send_error(&msg, &e);
+                        }
+                    },
+                    Err(e) => {
+                        debug!("Connection to {} closed: {}", peer, e);
+                        break;
                     }
-                    // Messages from the broadcast system
-                    msg = msg_rx.recv() => {
-                        svc.send(&msg.unwrap()).await.unwrap();
+                }
+            }
+            // Messages from the broadcast system
+            msg = msg_rx.recv() => {
+                let msg = match msg {
+                    Some(x) => x,
+                    None => {
+                        warn!("Dropping connection to {}, it fell behind on broadcasts", peer);
+                        break;
                     }
+                };
+                if let Err(e) = session.forward(msg).await {
+                    debug!("Connection to {} closed: {}", peer, e);
+                    break;
                 }
             }
-            info!("Client disconnected");
-        });
+            beat = heartbeat.tick() => {
+                if beat.is_err() {
+                    warn!("Connection to {} timed out", peer);
+                    break;
+                }
+                if let Err(e) = session.push(Message::Ping) {
+                    debug!("Connection to {} closed: {}", peer, e);
+                    break;
+                }
+            }
+        }
+    }
+    // Files committed by batches that weren't answered still have to reach the other clients
+    for (_, batch) in std::mem::take(&mut session.batches) {
+        session.broadcast_files(batch.committed).await;
+    }
+    info!("Client disconnected");
+}
+
+/// Id and stream of a message received from the client. The message might not even be decodable.
+fn message_header(raw_msg: &[u8]) -> (u16, u16) {
+    match raw_msg {
+        [a, b, c, d, ..] => (u16::from_be_bytes([*a, *b]), u16::from_be_bytes([*c, *d])),
+        _ => (0, CONTROL_STREAM),
     }
 }
 
@@ -134,106 +609,422 @@ pub fn dump_data(config_file: &Path) {
     db.dump_tree();
 }
 
+/// Handle a message received from the client.
+///
+/// Messages that can't be handled are answered with an error by the caller, the session goes on.
 async fn handle_client_msg(
-    svc: &mut NetServer,
+    session: &mut Session,
     db: &Db,
-    msg_builder: &mut MessageBuilder,
-    broadcast: &Sender<Vec<u8>>,
     raw_msg: &[u8],
-) {
-    let msg = MessageBuilder::decode_message(raw_msg).unwrap();
-    msg_builder.increment_counter();
-    match msg.verb {
-        Directive::SendFile => {
-            let argument = msg.argument.unwrap();
-            let metadata = argument.as_any().downcast_ref::<FileMetadata>().unwrap();
-            //let file_id = metadata.file_id.clone();
-
-            let chunks = match db.add_file(metadata) {
-                Ok(x) => {
-                    if x.is_empty() {
-                        // File is already completed
-                        let rmsg =
-                            msg_builder.encode_message(Directive::SendFile, Some(metadata.clone()));
-                        broadcast.send(rmsg).await.unwrap();
-                    }
-                    x
+) -> Result<(), ProtocolError> {
+    let envelope = session.msg_builder.decode_message(raw_msg)?;
+    session.msg_builder.increment_counter();
+    let id = envelope.id;
+    match envelope.message {
+        Message::SendFile(mut metadata) => {
+            let chunks = match store_file(session, db, &mut metadata)? {
+                Some(x) => x,
+                None => {
+                    session.respond(id, ResponseCode::OK);
+                    return Ok(());
                 }
-                Err(DbError::DuplicateFile) => vec![],
-                Err(_) => panic!("Failed to add file to database"),
             };
-
-            for (i, chunk) in chunks.iter().enumerate() {
-                let qualified_chunk = QualifiedChunkId {
-                    path: metadata.file_id.clone(),
-                    offset: (i * CHUNK_SIZE) as u32,
-                    id: chunk.clone(),
+            if chunks.is_empty() {
+                // File is already completed
+                session.broadcast_files(vec![metadata]).await;
+                session.respond(id, ResponseCode::OK);
+                return Ok(());
+            }
+            let mut batches = ChunkBatches::default();
+            batches.add_missing(&metadata, &chunks);
+            session.request_chunks(batches)?;
+            // Answered once the last chunk is stored
+            session.add_upload(id, &metadata.file_id).await;
+        }
+        Message::SendFileBatch(files) => {
+            // Counts as a file until every file was stored, so the batch can't be answered early
+            session.batches.insert(
+                id,
+                Batch {
+                    waiting: 1,
+                    committed: vec![],
+                },
+            );
+            // The chunks of every file are requested on a single stream
+            let mut batches = ChunkBatches::default();
+            for mut metadata in files.0 {
+                let chunks = match store_file(session, db, &mut metadata) {
+                    Ok(Some(x)) => x,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        session.abort_batch(id).await;
+                        return Err(e);
+                    }
                 };
-                let msg =
-                    msg_builder.encode_message(Directive::RequestChunk, Some(qualified_chunk));
-                let _ = &svc.send(&msg).await;
-            }
-        }
-        Directive::SendChunk => {
-            let complete = db
-                .add_chunk(
-                    msg.argument
-                        .unwrap()
-                        .as_any()
-                        .downcast_ref::<Chunk>()
-                        .unwrap(),
-                )
-                .expect("Failed to add chunk to database");
+                if chunks.is_empty() {
+                    if let Some(batch) = session.batches.get_mut(&id) {
+                        batch.committed.push(metadata);
+                    }
+                    continue;
+                }
+                session.add_upload(id, &metadata.file_id).await;
+                batches.add_missing(&metadata, &chunks);
+            }
+            if !batches.is_empty() {
+                if let Err(e) = session.request_chunks(batches) {
+                    session.abort_batch(id).await;
+                    return Err(e);
+                }
+            }
+            session.finish(id).await;
+        }
+        Message::SendChunk(chunk) => {
+            let complete = db.add_chunk(&chunk).map_err(|e| {
+                error!("Failed to add a chunk to the database: {}", e);
+                storage_failure(e)
+            })?;
 
             // If the file is complete, broadcast a fake `SendFile` message for every
             // thread to forward to the client
             if let Some(id) = complete {
-                let file_md = db.get_file(id.path.to_str().unwrap()).unwrap().unwrap();
-                let rmsg = msg_builder.encode_message(Directive::SendFile, Some(file_md));
-                broadcast.send(rmsg).await.unwrap();
+                match db.get_file(&id.path) {
+                    Ok(Some(file_md)) => session.commit(file_md).await,
+                    res => {
+                        session.upload_committed(&id).await;
+                        res.map_err(storage_failure)?;
+                        return Err(ProtocolError::new(
+                            ErrorCode::NOT_FOUND,
+                            format!("{} was removed during the upload", id.path.display()),
+                        ));
+                    }
+                }
             }
         }
-        Directive::ListFiles => {
-            let files = db.get_files().unwrap();
+        Message::ListFiles => {
+            let files = db
+                .get_files(session.capabilities)
+                .map_err(storage_failure)?;
             debug!("Sending file list to client");
-            let msg = msg_builder.encode_message(Directive::SendFiles, Some(files));
-            let _ = &svc.send(&msg).await;
-        }
-        Directive::RequestFile => {
-            let argument = msg.argument.unwrap();
-            let file_id = argument.as_any().downcast_ref::<FileId>().unwrap();
-            let file = db
-                .get_file(file_id.path.to_str().unwrap())
-                .unwrap()
-                .unwrap();
-            let msg = msg_builder.encode_message(Directive::SendFile, Some(file));
-            let _ = &svc.send(&msg).await;
-        }
-        Directive::RequestChunk => {
-            let argument = msg.argument.unwrap();
-            let chunk_id = argument
-                .as_any()
-                .downcast_ref::<QualifiedChunkId>()
+            let rmsg = session
+                .msg_builder
+                .encode_message(Message::SendFiles(files))?;
+            let _ = session.svc.answer_part(CONTROL_STREAM, rmsg);
+            session.respond(id, ResponseCode::OK);
+        }
+        Message::RequestFile(file_id) => match db.get_file(&file_id.path) {
+            Ok(Some(file))
+                if session.capabilities.carries(&file.kind)
+                    && !session.capabilities.fits(&file) =>
+            {
+                return Err(too_large(&file))
+            }
+            Ok(Some(mut file)) if session.capabilities.carries(&file.kind) => {
+                file.extended.restrict(session.capabilities);
+                let rmsg = session
+                    .msg_builder
+                    .encode_message(Message::SendFile(file))?;
+                let _ = session.svc.answer_part(CONTROL_STREAM, rmsg);
+                session.respond(id, ResponseCode::OK);
+            }
+            // Entries the client can't synchronize don't exist as far as it's concerned
+            Ok(_) => {
+                return Err(ProtocolError::new(
+                    ErrorCode::NOT_FOUND,
+                    format!("{} doesn't exist", file_id.path.display()),
+                ))
+            }
+            Err(e) => {
+                error!("Failed to read {:?} from the database: {}", file_id.path, e);
+                return Err(storage_failure(e));
+            }
+        },
+        Message::RequestChunk(chunk_id) => {
+            let q_chunk = read_chunk(db, chunk_id)?;
+            let rmsg = session
+                .msg_builder
+                .encode_stream_message(envelope.stream, Message::SendQualifiedChunk(q_chunk))?;
+            let _ = session.svc.answer(envelope.stream, rmsg);
+        }
+        Message::RequestChunks(chunks) => {
+            let last = chunks.0.len() - 1;
+            for (i, chunk_id) in chunks.0.into_iter().enumerate() {
+                // Chunks that can't be read are answered with an error in their place
+                let rmsg = read_chunk(db, chunk_id).and_then(|q_chunk| {
+                    let msg = Message::SendQualifiedChunk(q_chunk);
+                    Ok(session
+                        .msg_builder
+                        .encode_stream_message(envelope.stream, msg)?)
+                });
+                let rmsg = match rmsg {
+                    Ok(rmsg) => rmsg,
+                    Err(e) => session.msg_builder.encode_error(id, envelope.stream, &e),
+                };
+                let _ = if i == last {
+                    session.svc.answer(envelope.stream, rmsg)
+                } else {
+                    session.svc.answer_part(envelope.stream, rmsg)
+                };
+            }
+        }
+        Message::DeleteFile(file_path) => {
+            if !is_safe_path(&file_path.0) {
+                return Err(ProtocolError::new(
+                    ErrorCode::INVALID_ARGUMENT,
+                    format!("Invalid path {}", file_path.0.display()),
+                ));
+            }
+            let removed = db.rm_file(&file_path).map_err(|e| {
+                error!("Failed to remove {:?} from the database: {}", file_path, e);
+                storage_failure(e)
+            })?;
+            session.respond(id, ResponseCode::OK);
+            // The other clients never got entries that weren't stored
+            if removed {
+                debug!("Removed {:?} from the database", file_path);
+                session
+                    .broadcast
+                    .send(Message::DeleteFile(file_path))
+                    .await
+                    .unwrap();
+            }
+        }
+        Message::RenameFile(rename) => {
+            if !is_safe_path(&rename.from) || !is_safe_path(&rename.to) {
+                return Err(ProtocolError::new(
+                    ErrorCode::INVALID_ARGUMENT,
+                    format!(
+                        "Invalid path in the move of {} to {}",
+                        rename.from.display(),
+                        rename.to.display()
+                    ),
+                ));
+            }
+            if rename.to.starts_with(&rename.from) {
+                return Err(ProtocolError::new(
+                    ErrorCode::INVALID_ARGUMENT,
+                    format!("Can't move {} into itself", rename.from.display()),
+                ));
+            }
+            let moved = db.rename(&rename.from, &rename.to).map_err(|e| match e {
+                DbError::UploadPending => ProtocolError::new(
+                    ErrorCode::UPLOAD_PENDING,
+                    format!("{} is still being uploaded", rename.from.display()),
+                ),
+                e => {
+                    error!("Failed to move {:?} in the database: {}", rename.from, e);
+                    storage_failure(e)
+                }
+            })?;
+            if moved == 0 {
+                return Err(ProtocolError::new(
+                    ErrorCode::NOT_FOUND,
+                    format!("{} doesn't exist", rename.from.display()),
+                ));
+            }
+            debug!(
+                "Moved {} files from {:?} to {:?}",
+                moved, rename.from, rename.to
+            );
+            session.respond(id, ResponseCode::OK);
+            session
+                .broadcast
+                .send(Message::RenameFile(rename))
+                .await
                 .unwrap();
-            let mut buf = [0u8; 32];
-            buf.copy_from_slice(&chunk_id.id.0);
-            let chunk = db.get_chunk(buf).unwrap();
-            let q_chunk = QualifiedChunk {
-                id: chunk_id.clone(),
-                data: chunk.data,
+        }
+        Message::Ping => {
+            let rmsg = session.msg_builder.encode_message(Message::Pong)?;
+            let _ = session.svc.answer(CONTROL_STREAM, rmsg);
+        }
+        Message::Pong => {}
+        Message::Credit(credit) => {
+            let _ = session.svc.add_credit(envelope.stream, credit.0);
+        }
+        Message::Error(error) => {
+            warn!("Client couldn't handle message {}: {}", id, error);
+        }
+        // Only sent by the server, or already handled while connecting
+        message @ (Message::AnnounceVersion(_)
+        | Message::SendFiles(_)
+        | Message::SendQualifiedChunk(_)
+        | Message::Response(_)) => {
+            return Err(ProtocolError::new(
+                ErrorCode::UNSUPPORTED_DIRECTIVE,
+                format!("The server doesn't handle {:?}", message.directive()),
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Store the metadata of a file uploaded by the client. Returns the chunks that are still missing,
+/// or `None` when the file didn't change.
+fn store_file(
+    session: &Session,
+    db: &Db,
+    metadata: &mut FileMetadata,
+) -> Result<Option<Vec<ChunkId>>, ProtocolError> {
+    if !is_safe_path(&metadata.file_id.path) {
+        return Err(ProtocolError::new(
+            ErrorCode::INVALID_ARGUMENT,
+            format!("Invalid path {}", metadata.file_id.path.display()),
+        ));
+    }
+    // The client couldn't answer the requests for chunks past 4 GiB
+    if !session.capabilities.fits(metadata) {
+        return Err(too_large(metadata));
+    }
+    // Extended metadata the client can't carry stays as it is
+    if !session.capabilities.contains(Capabilities::EXTENDED) {
+        if let Ok(Some(stored)) = db.get_file(&metadata.file_id.path) {
+            metadata
+                .extended
+                .keep_unsupported(&stored.extended, session.capabilities);
+        }
+    }
+    match db.add_file(metadata) {
+        Ok(x) => Ok(Some(x)),
+        Err(DbError::DuplicateFile) => Ok(None),
+        Err(e) => {
+            error!(
+                "Failed to add {:?} to the database: {}",
+                metadata.file_id.path, e
+            );
+            Err(storage_failure(e))
+        }
+    }
+}
+
+/// Read the chunk requested by the client
+fn read_chunk(db: &Db, chunk_id: QualifiedChunkId) -> Result<QualifiedChunk, ProtocolError> {
+    let hash: [u8; 32] = chunk_id.id.0.as_slice().try_into().map_err(|_| {
+        ProtocolError::new(ErrorCode::INVALID_ARGUMENT, "Chunk ids are 32 bytes long")
+    })?;
+    let chunk = db
+        .get_chunk(hash)
+        .map_err(storage_failure)?
+        .ok_or_else(|| {
+            ProtocolError::new(
+                ErrorCode::NOT_FOUND,
+                format!(
+                    "Chunk at offset {} of {} doesn't exist",
+                    chunk_id.offset,
+                    chunk_id.path.path.display()
+                ),
+            )
+        })?;
+    Ok(QualifiedChunk {
+        id: chunk_id,
+        data: chunk.data,
+    })
+}
+
+fn storage_failure(e: impl Display) -> ProtocolError {
+    ProtocolError::new(ErrorCode::STORAGE_FAILURE, e.to_string())
+}
+
+fn too_large(file: &FileMetadata) -> ProtocolError {
+    ProtocolError::new(
+        ErrorCode::TOO_LARGE,
+        format!(
+            "{} is too large for 32 bit chunk offsets",
+            file.file_id.path.display()
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        messaging::arguments::{EntryKind, ExtendedMetadata},
+        net::{generate_noise_keypair, NetClient},
+    };
+    use tokio::io::{duplex, DuplexStream};
+
+    /// A session, and the client's end of its connection
+    async fn session() -> (Session, NetClient<DuplexStream>) {
+        let server_keys = generate_noise_keypair();
+        let client_keys = generate_noise_keypair();
+        let (client_stream, server_stream) = duplex(1 << 16);
+
+        let client_pubkey = client_keys.public.clone();
+        let server = tokio::spawn(async move {
+            NetServer::new(server_stream, &server_keys.private, &[client_pubkey])
+                .await
+                .unwrap()
+        });
+        let client = NetClient::new(client_stream, &client_keys.private, &[server_keys.public])
+            .await
+            .unwrap();
+
+        let (svc, _incoming) = server.await.unwrap().split();
+        let session = Session {
+            svc: MuxSender::spawn(svc),
+            msg_builder: MessageBuilder::new(PROTOCOL_VERSION),
+            capabilities: crate::messaging::CAPABILITIES,
+            next_stream: 2,
+            broadcast: mpsc::channel(100).0,
+            uploads: HashMap::new(),
+            batches: HashMap::new(),
+        };
+        (session, client)
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_slow_connection() {
+        let (threads_tx, threads_rx) = mpsc::channel(1);
+        let (broadcast_tx, broadcast_rx) = mpsc::channel(1);
+        tokio::spawn(broadcast_system(threads_rx, broadcast_rx));
+
+        let (fast_tx, mut fast) = mpsc::channel(2);
+        let (slow_tx, mut slow) = mpsc::channel(1);
+        threads_tx.send(fast_tx).await.unwrap();
+        threads_tx.send(slow_tx).await.unwrap();
+        for _ in 0..2 {
+            broadcast_tx.send(Message::Ping).await.unwrap();
+        }
+
+        // The connection that doesn't keep up is dropped without holding up the other one
+        for _ in 0..2 {
+            let msg = timeout(Duration::from_millis(100), fast.recv()).await;
+            assert_eq!(msg.unwrap(), Some(Message::Ping));
+        }
+        assert_eq!(slow.recv().await, Some(Message::Ping));
+        assert_eq!(slow.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_unsafe_upload_refused() {
+        let (mut session, _client) = session().await;
+        let db = Db::new_temporary().unwrap();
+        for path in ["../x", "/etc/x"] {
+            let file = FileMetadata {
+                file_id: FileId {
+                    path: PathBuf::from(path),
+                    hash: [0u8; 32],
+                },
+                file_name: "x".into(),
+                permissions: 0o644,
+                modified: 0,
+                created: 0,
+                size: 0,
+                chunks: vec![],
+                kind: EntryKind::File,
+                extended: ExtendedMetadata::default(),
             };
-            let msg = msg_builder
-                .encode_message::<QualifiedChunk>(Directive::SendQualifiedChunk, Some(q_chunk));
-            let _ = &svc.send(&msg).await;
-        }
-        Directive::DeleteFile => {
-            let argument = msg.argument.unwrap();
-            let file_path = argument.as_any().downcast_ref::<FilePath>().unwrap();
-            db.rm_file(file_path);
-            debug!("Removed {:?} from the database", file_path);
-            let rmsg = msg_builder.encode_message(Directive::DeleteFile, Some(file_path.clone()));
-            broadcast.send(rmsg).await.unwrap();
-        }
-        _ => todo!(),
+            for message in [
+                Message::SendFile(file.clone()),
+                Message::SendFileBatch(MetadataList(vec![file.clone()])),
+            ] {
+                let raw = session.msg_builder.encode_message(message).unwrap();
+                let e = handle_client_msg(&mut session, &db, &raw)
+                    .await
+                    .unwrap_err();
+                assert_eq!(e.code, ErrorCode::INVALID_ARGUMENT);
+            }
+            assert_eq!(db.get_file(Path::new(path)).unwrap(), None);
+        }
+        assert!(db.get_files(session.capabilities).unwrap().0.is_empty());
     }
 }